@@ -8,28 +8,34 @@ use std::{
     },
     collections::{
         HashMap,
+        HashSet,
     },
 };
 use chrono::{
     Utc,
     Duration,
 };
+use futures::{
+    channel::oneshot,
+    future::try_join_all,
+    select,
+    FutureExt,
+};
 use gloo::{
     utils::{
         window,
     },
     events::EventListener,
+    timers::future::TimeoutFuture,
 };
 use indexed_db_futures::IdbQuerySource;
-use js_sys::{
-    Object,
-    Uint8Array,
-};
+use js_sys::Uint8Array;
 use lunk::{
     link,
     Prim,
     ProcessingContext,
     EventGraph,
+    List,
 };
 use narrowcore::{
     state::{
@@ -41,7 +47,13 @@ use narrowcore::{
         MessagesViewMode,
         ViewState,
     },
-    setview::set_view,
+    setview::{
+        set_view,
+        set_view_channels,
+        set_view_channels_nav,
+        location_view,
+        restore_history_view,
+    },
 };
 use rooting::{
     set_root,
@@ -78,16 +90,39 @@ use web::{
         icon,
         ElExt,
         nol_span,
+        nol_el,
         async_block,
+        CSS_HIDE,
     },
     world::{
         World,
+        FeedId,
+        ApiError,
+    },
+    interface::u2s::{
         ChannelId,
-        U2SGet,
-        S2UChannel,
-        U2SPost,
         DateMessageId,
-        FeedId,
+        MessageId,
+        IdentityId,
+        S2UEventKind,
+        S2UChannelSummary,
+        S2UBrew,
+        BrewId,
+        U2SBrewCreate,
+        U2SBrewDelete,
+        U2SBrewUpdate,
+        U2SChannelCreate,
+        U2SChannelJoin,
+        U2SGetBrews,
+        U2SGetChannelSummaries,
+        U2SGetChannels,
+        U2SGetOwnIdentities,
+        U2SGetPushPubKey,
+        U2SGetReadPosition,
+        U2SSend,
+        U2SSetReadPosition,
+        U2SSnapGetAfter,
+        U2SSubscribePush,
     },
     util::{
         MyError,
@@ -97,40 +132,50 @@ use web::{
         MyErrorJsValue,
     },
     log,
-    noworlater::{
-        NowOrLater,
-        Hard,
-    },
+    noworlater::Hard,
     NOTIFY_CHANNEL,
     dbmodel::{
         self,
         TABLE_OUTBOX,
         OutboxEntry,
-        OutboxEntryV1,
+        OutboxEntryV2,
         outbox_sent_partial_key_unsent,
         put_outbox,
         outbox_sent_partial_key_sent,
         TABLE_OUTBOX_INDEX_SENT,
-        outbox_sent_key,
+        get_outbox,
     },
     serviceworker,
     scrollentry::FeedTime,
-    outboxfeed::OutboxFeed,
-    messagefeed::ChannelFeed,
+    outboxfeed::{
+        OutboxFeed,
+        OutboxStatus,
+    },
+    messagefeed::{
+        ChannelFeed,
+        ChannelEventHandler,
+    },
+    scrollentry::AuthorRenderer,
 };
 use web_sys::{
     HtmlInputElement,
+    HtmlSelectElement,
     Element,
     KeyboardEvent,
     BroadcastChannel,
+    EventSource,
     MessageEvent,
     IdbKeyRange,
     PushSubscription,
     PushSubscriptionOptionsInit,
 };
 use crate::narrowcore::{
-    view::Channel,
+    view::{
+        Brew,
+        Channel,
+    },
     viewid::{
+        BrewViewStateId,
         ChannelViewStateId,
         ViewStateId,
     },
@@ -140,98 +185,484 @@ use crate::narrowcore::{
 
 pub mod narrowcore;
 
-fn spawn_sender(state: &State) -> ScopeValue {
+/// Delay before reconnecting the event stream, doubled for each consecutive failure.
+const EVENTS_BACKOFF_MIN_MS: u32 = 1000;
+const EVENTS_BACKOFF_MAX_MS: u32 = 60000;
+
+fn notify_feeds(pc: &mut ProcessingContext, state: &State, server_time: DateMessageId) {
+    for f in &mut *state.0.channel_feeds.borrow_mut() {
+        f.notify(pc.eg(), server_time.clone());
+    }
+}
+
+fn notify_feeds_event(pc: &mut ProcessingContext, state: &State, id: MessageId) {
+    for f in &mut *state.0.channel_feeds.borrow_mut() {
+        f.notify_event(pc.eg(), id.clone());
+    }
+}
+
+/// Update the cached brew to match the server, adding it to the brew list if it's
+/// new.
+fn apply_brew(pc: &mut ProcessingContext, state: &State, b: S2UBrew) {
+    let brew = Brew {
+        id: b.id.clone(),
+        name: Prim::new(pc, b.name),
+        channels: List::new(pc, b.channels),
+    };
+    state.0.brews.set(pc, b.id.clone(), brew);
+    if !state.0.brew_list.borrow_values().contains(&b.id) {
+        state.0.brew_list.push(pc, b.id);
+    }
+}
+
+/// Remove a deleted brew from the brew list, leaving the brew's view if it's
+/// showing.
+fn forget_brew(pc: &mut ProcessingContext, state: &State, id: &BrewId) {
+    let pos = state.0.brew_list.borrow_values().iter().position(|b| b == id);
+    if let Some(i) = pos {
+        state.0.brew_list.splice(pc, i, 1, vec![]);
+    }
+    let showing = match &*state.0.view.borrow() {
+        ViewState::Messages(m) => match &*m.borrow() {
+            MessagesViewMode::Brew(b) => b.id == *id,
+            _ => false,
+        },
+        _ => false,
+    };
+    if showing {
+        set_view_channels(pc, state);
+    }
+}
+
+/// Apply channel events that affect shared state to the cached channels and brews.
+fn channel_event_handler(state: &State) -> ChannelEventHandler {
     let state = state.clone();
-    return spawn_rooted("Consuming outbox", async move {
-        // Get next message to send
-        let send_req;
-        let e;
-        {
-            let txn =
-                state
-                    .0
-                    .db
-                    .transaction_on_multi_with_mode(&[TABLE_OUTBOX], web_sys::IdbTransactionMode::Readonly)
-                    .context("Failed to start transaction")?;
-            let outbox = txn.object_store(TABLE_OUTBOX).context("Failed to get outbox")?;
-            let sent_index = outbox.index(TABLE_OUTBOX_INDEX_SENT).context("Failed to get sent index")?;
-            let Some(
-                cursor
-            ) = sent_index.open_cursor_with_range(
-                &IdbKeyRange::lower_bound(&outbox_sent_partial_key_unsent()).unwrap()
-            ).context("Failed to open outbox cursor") ?.await.context("Error waiting for cursor") ? else {
-                txn.abort().context("Failed to close transaction")?;
-                return Ok(());
-            };
-            e = dbmodel::from_outbox(&cursor.value());
-            match &e {
-                OutboxEntry::V1(e) => {
-                    let reply = match &e.reply {
-                        Some(reply) => match reply {
-                            FeedId::None => panic!(),
-                            FeedId::Local(_, id) => {
-                                let reply_e =
-                                    dbmodel::from_outbox(
-                                        &sent_index
-                                            .get(&outbox_sent_key(&id, true))
-                                            .context("Failed to initiate local id lookup")?
-                                            .await
-                                            .context("Failed to look up local id")?
-                                            .context(
-                                                &format!("Failed to look up message id for previous local id [{}]", id),
-                                            )?,
-                                    );
-                                match reply_e {
-                                    OutboxEntry::V1(reply_e) => {
-                                        Some(reply_e.resolved_id.unwrap())
-                                    },
-                                }
-                            },
-                            FeedId::Real(r) => Some(r.clone()),
+    return Rc::new(move |pc: &mut ProcessingContext, kind: S2UEventKind| match kind {
+        // Show the new name right away, then refetch so the saved copy is updated and an
+        // out of date event doesn't win
+        S2UEventKind::ChannelRenamed { id, name } => {
+            if let Some(channel) = state.0.channels.get_immediate(&id) {
+                channel.name.set(pc, name);
+            }
+            state.0.channels.invalidate(&id);
+        },
+        // Channels log every brew including them, only update brews of your own. New
+        // brews arrive through the brew stream.
+        S2UEventKind::BrewChanged(b) => {
+            if state.0.brews.get_immediate(&b.id).is_some() {
+                apply_brew(pc, &state, b);
+            }
+        },
+        S2UEventKind::BrewDeleted(id) => {
+            forget_brew(pc, &state, &id);
+        },
+        // There are no identity events, but a joining identity may be new or changed
+        // since it was cached. No member list ui yet.
+        S2UEventKind::MemberJoined { identity, .. } => {
+            state.0.identities.invalidate(&identity);
+        },
+        S2UEventKind::MemberLeft { .. } => { },
+        // Handled by the feed
+        S2UEventKind::MessageCreated(_) | S2UEventKind::MessageEdited { .. } | S2UEventKind::MessageDeleted(_) => { },
+    });
+}
+
+/// Show the author's avatar and name, loading the identity if necessary.
+fn author_renderer(state: &State) -> AuthorRenderer {
+    let state = state.clone();
+    return Rc::new(move |pc: &mut ProcessingContext, id: &IdentityId| {
+        return nol_el(pc, state.0.identities.get(id.clone()), |pc, identity| {
+            let avatar = image("").classes(&["avatar"]).own(|e| link!(
+                //. .
+                (_pc = pc), (avatar = identity.avatar.clone()), (), (e = e.weak()) {
+                    let e = e.upgrade()?;
+                    match &*avatar.borrow() {
+                        Some(url) => {
+                            e.ref_attr("src", url);
+                            e.ref_remove_classes(&[CSS_HIDE]);
+                        },
+                        None => {
+                            e.ref_classes(&[CSS_HIDE]);
+                        },
+                    }
+                }
+            ));
+            return hbox().extend(vec![avatar, el("span").bind_text(pc, &identity.name)]);
+        });
+    });
+}
+
+/// The user's last read message in the channel, as far as known.
+fn read_position(pc: &mut ProcessingContext, state: &State, channel: &ChannelId) -> Prim<Option<MessageId>> {
+    return state.0.read_positions.borrow_mut().entry(channel.clone()).or_insert_with(|| Prim::new(pc, None)).clone();
+}
+
+/// Move the known read position forward. Returns false if it was already at or past
+/// `id`.
+fn advance_read_position(pc: &mut ProcessingContext, state: &State, id: &MessageId) -> bool {
+    let position = read_position(pc, state, &id.0);
+    if position.borrow().as_ref().map(|p| p >= id).unwrap_or(false) {
+        return false;
+    }
+    position.set(pc, Some(id.clone()));
+    return true;
+}
+
+/// Look up the first message after the user's read position, where the "new
+/// messages" divider goes.
+async fn first_unread(state: &State, channel: &ChannelId) -> Result<Option<MessageId>, String> {
+    let read: Option<MessageId> = state.0.world.call(U2SGetReadPosition(channel.clone())).await?;
+    let resp = state.0.world.call(U2SSnapGetAfter {
+        id: read.clone().unwrap_or(MessageId(channel.clone(), 0)),
+        count: 1,
+    }).await?;
+    if let Some(read) = read {
+        state.0.eg.event(|pc| {
+            advance_read_position(pc, state, &read);
+        });
+    }
+    return Ok(resp.entries.into_iter().next().map(|m| m.id));
+}
+
+/// Receive new message notifications directly from the server while the app is
+/// open. Unlike push this works without notification permissions.
+fn spawn_event_stream(eg: &EventGraph, state: &State) -> ScopeValue {
+    let eg = eg.clone();
+    let state = state.clone();
+    return spawn_rooted("Streaming server events", async move {
+        let mut backoff = EVENTS_BACKOFF_MIN_MS;
+        loop {
+            let source =
+                EventSource::new(
+                    &format!("{}/api/events", state.0.world.origin),
+                ).context("Error opening event stream")?;
+            let _cleanup = defer({
+                let source = source.clone();
+                move || source.close()
+            });
+            let opened = Rc::new(Cell::new(false));
+            let (error_tx, error_rx) = oneshot::channel();
+            let error_tx = Cell::new(Some(error_tx));
+            let _open_listener = EventListener::new(&source, "open", {
+                let opened = opened.clone();
+                move |_| opened.set(true)
+            });
+            let _message_listener = EventListener::new(&source, "message", {
+                let state = state.clone();
+                let eg = eg.clone();
+                move |e| {
+                    let e = e.dyn_ref::<MessageEvent>().unwrap();
+                    let server_time: DateMessageId = match serde_json::from_str(&e.data().as_string().unwrap()) {
+                        Ok(t) => t,
+                        Err(e) => {
+                            log!("Received invalid event from server: {}", e);
+                            return;
                         },
-                        None => None,
                     };
-                    send_req = U2SPost::Send {
-                        channel: e.channel.clone(),
-                        reply: reply.clone(),
-                        local_id: e.local_id.clone(),
-                        body: e.body.clone(),
+                    eg.event(|pc| notify_feeds(pc, &state, server_time));
+                }
+            });
+            let _event_listener = EventListener::new(&source, "event", {
+                let state = state.clone();
+                let eg = eg.clone();
+                move |e| {
+                    let e = e.dyn_ref::<MessageEvent>().unwrap();
+                    let id: MessageId = match serde_json::from_str(&e.data().as_string().unwrap()) {
+                        Ok(t) => t,
+                        Err(e) => {
+                            log!("Received invalid event from server: {}", e);
+                            return;
+                        },
                     };
-                },
-            };
-            txn.await.into_result().context("Failed to commit transaction")?;
+                    eg.event(|pc| notify_feeds_event(pc, &state, id));
+                }
+            });
+            let _brew_listener = EventListener::new(&source, "brew", {
+                let state = state.clone();
+                let eg = eg.clone();
+                move |e| {
+                    let e = e.dyn_ref::<MessageEvent>().unwrap();
+                    let kind: S2UEventKind = match serde_json::from_str(&e.data().as_string().unwrap()) {
+                        Ok(k) => k,
+                        Err(e) => {
+                            log!("Received invalid brew change from server: {}", e);
+                            return;
+                        },
+                    };
+                    eg.event(|pc| match kind {
+                        S2UEventKind::BrewChanged(b) => apply_brew(pc, &state, b),
+                        S2UEventKind::BrewDeleted(id) => forget_brew(pc, &state, &id),
+                        _ => { },
+                    });
+                }
+            });
+            let _read_listener = EventListener::new(&source, "read", {
+                let state = state.clone();
+                let eg = eg.clone();
+                move |e| {
+                    let e = e.dyn_ref::<MessageEvent>().unwrap();
+                    let id: MessageId = match serde_json::from_str(&e.data().as_string().unwrap()) {
+                        Ok(t) => t,
+                        Err(e) => {
+                            log!("Received invalid read position from server: {}", e);
+                            return;
+                        },
+                    };
+                    eg.event(|pc| {
+                        advance_read_position(pc, &state, &id);
+                    });
+                }
+            });
+
+            // The browser retries on its own, but without backoff
+            let _error_listener = EventListener::new(&source, "error", move |_| {
+                if let Some(error_tx) = error_tx.take() {
+                    _ = error_tx.send(());
+                }
+            });
+            _ = error_rx.await;
+            source.close();
+            if opened.get() {
+                backoff = EVENTS_BACKOFF_MIN_MS;
+            } else {
+                // The status isn't exposed, so check the session with a regular request. If it
+                // expired this waits for login, which replaces this view.
+                match state.0.world.call(U2SGetPushPubKey).await {
+                    Ok(_) => { },
+                    Err(e) => {
+                        log!("Event stream failed to open and the server couldn't be reached: {}", e);
+                    },
+                }
+            }
+            TimeoutFuture::new(backoff).await;
+            backoff = (backoff * 2).min(EVENTS_BACKOFF_MAX_MS);
         }
+    });
+}
 
-        // Send it
-        let real_id = state.0.world.req_post_ret(send_req).await?;
+/// Delay before retrying failed sends, doubled for each consecutive failure.
+const SEND_BACKOFF_MIN_MS: u32 = 1000;
+const SEND_BACKOFF_MAX_MS: u32 = 300000;
 
-        // Mark entry as sent
-        {
-            let txn =
-                state
-                    .0
-                    .db
-                    .transaction_on_multi_with_mode(&[TABLE_OUTBOX], web_sys::IdbTransactionMode::Readwrite)
-                    .context("Failed to start transaction")?;
-            let outbox = txn.object_store(TABLE_OUTBOX).context("Failed to get outbox for update")?;
-            put_outbox(&outbox, match e {
-                OutboxEntry::V1(e) => {
-                    OutboxEntry::V1(OutboxEntryV1 {
-                        stamp: e.stamp,
-                        channel: e.channel,
-                        reply: e.reply,
-                        local_id: e.local_id,
-                        body: e.body,
-                        resolved_id: Some(real_id),
-                    })
+enum SendResult {
+    Sent(MessageId),
+    /// May succeed later, with the reason it failed this time
+    Retry(String),
+    /// Won't succeed, with the reason
+    Failed(String),
+}
+
+enum DrainResult {
+    /// There was nothing to send
+    Empty,
+    /// Everything read was sent or failed permanently, more may have been queued since
+    Done,
+    /// Some messages need to be retried later
+    Retry,
+}
+
+/// Unsent entries that haven't failed permanently, oldest first.
+async fn read_unsent(state: &State) -> Result<Vec<OutboxEntryV2>, String> {
+    let txn =
+        state
+            .0
+            .db
+            .transaction_on_one_with_mode(TABLE_OUTBOX, web_sys::IdbTransactionMode::Readonly)
+            .context("Failed to start transaction")?;
+    let outbox = txn.object_store(TABLE_OUTBOX).context("Failed to get outbox")?;
+    let sent_index = outbox.index(TABLE_OUTBOX_INDEX_SENT).context("Failed to get sent index")?;
+    let found =
+        sent_index
+            .get_all_with_key(
+                &IdbKeyRange::bound_with_lower_open_and_upper_open(
+                    &outbox_sent_partial_key_unsent(),
+                    &outbox_sent_partial_key_sent(),
+                    false,
+                    true,
+                ).unwrap(),
+            )
+            .context("Failed to start unsent entry lookup")?
+            .await
+            .context("Failed to look up unsent entries")?;
+    txn.await.into_result().context("Failed to commit transaction")?;
+    let mut out = found.iter().map(|e| dbmodel::from_outbox(&e).upgrade()).collect::<Vec<_>>();
+    out.sort_by(|a, b| a.stamp.cmp(&b.stamp));
+    return Ok(out);
+}
+
+async fn read_outbox(state: &State, local_id: &str) -> Result<Option<OutboxEntryV2>, String> {
+    let txn =
+        state
+            .0
+            .db
+            .transaction_on_one_with_mode(TABLE_OUTBOX, web_sys::IdbTransactionMode::Readonly)
+            .context("Failed to start transaction")?;
+    let outbox = txn.object_store(TABLE_OUTBOX).context("Failed to get outbox")?;
+    let out = get_outbox(&outbox, local_id).await?;
+    txn.await.into_result().context("Failed to commit transaction")?;
+    return Ok(out);
+}
+
+fn set_outbox_status(eg: &EventGraph, state: &State, local_id: &str, status: OutboxStatus) {
+    eg.event(|pc| {
+        if let Some(feed) = &*state.0.outbox_feed.borrow() {
+            feed.set_status(pc, local_id, status);
+        }
+    });
+}
+
+async fn update_outbox(state: &State, e: OutboxEntryV2) -> Result<(), String> {
+    let txn =
+        state
+            .0
+            .db
+            .transaction_on_one_with_mode(TABLE_OUTBOX, web_sys::IdbTransactionMode::Readwrite)
+            .context("Failed to start transaction")?;
+    let outbox = txn.object_store(TABLE_OUTBOX).context("Failed to get outbox for update")?;
+    put_outbox(&outbox, OutboxEntry::V2(e)).await?;
+    txn.await.into_result().context("Failed to commit transaction")?;
+    return Ok(());
+}
+
+async fn send_entry(state: &State, e: &OutboxEntryV2) -> Result<SendResult, String> {
+    let reply = match &e.reply {
+        Some(reply) => match reply {
+            FeedId::None => panic!(),
+            FeedId::Local(_, id) => {
+                let Some(reply_e) = read_outbox(state, id).await? else {
+                    return Ok(SendResult::Failed(format!("Replied-to message [{}] is no longer in the outbox", id)));
+                };
+                match (reply_e.resolved_id, reply_e.error) {
+                    (Some(resolved), _) => Some(resolved),
+                    (None, Some(_)) => {
+                        return Ok(SendResult::Failed(format!("Replied-to message [{}] failed to send", id)));
+                    },
+                    (None, None) => {
+                        return Ok(SendResult::Retry(format!("Replied-to message [{}] hasn't been sent yet", id)));
+                    },
+                }
+            },
+            FeedId::Real(r) => Some(r.clone()),
+        },
+        None => None,
+    };
+    match state.0.world.call(U2SSend {
+        identity: e.identity.clone(),
+        channel: e.channel.clone(),
+        reply: reply,
+        local_id: e.local_id.clone(),
+        body: e.body.clone(),
+    }).await {
+        Ok(id) => return Ok(SendResult::Sent(id)),
+        Err(ApiError::Transient(e)) => return Ok(SendResult::Retry(e)),
+        Err(ApiError::Rejected(e)) => return Ok(SendResult::Failed(e)),
+    }
+}
+
+/// Send everything currently in the outbox. Messages in a channel are sent in the
+/// order they were written, so a channel stops at its first message that needs to
+/// be retried.
+async fn drain_outbox(eg: &EventGraph, state: &State) -> Result<DrainResult, String> {
+    let unsent = read_unsent(state).await?;
+    if unsent.is_empty() {
+        return Ok(DrainResult::Empty);
+    }
+    let mut blocked = HashSet::new();
+    for e in unsent {
+        if blocked.contains(&e.channel) {
+            continue;
+        }
+
+        // Re-read, it may have been edited or cancelled while earlier messages were sent
+        let Some(mut e) = read_outbox(state, &e.local_id).await? else {
+            continue;
+        };
+        if e.resolved_id.is_some() || e.error.is_some() {
+            continue;
+        }
+        set_outbox_status(eg, state, &e.local_id, OutboxStatus::Sending);
+        match send_entry(state, &e).await? {
+            SendResult::Sent(id) => {
+                e.resolved_id = Some(id);
+                let local_id = e.local_id.clone();
+                update_outbox(state, e).await?;
+                set_outbox_status(eg, state, &local_id, OutboxStatus::Delivered);
+            },
+            SendResult::Retry(err) => {
+                log!("Failed to send message [{}], will retry: {}", e.local_id, err);
+                set_outbox_status(eg, state, &e.local_id, OutboxStatus::Queued);
+                blocked.insert(e.channel);
+            },
+            SendResult::Failed(err) => {
+                log!("Failed to send message [{}], giving up: {}", e.local_id, err);
+                e.error = Some(err.clone());
+                let local_id = e.local_id.clone();
+                update_outbox(state, e).await?;
+                set_outbox_status(eg, state, &local_id, OutboxStatus::Failed(err));
+            },
+        }
+    }
+    if blocked.is_empty() {
+        return Ok(DrainResult::Done);
+    } else {
+        return Ok(DrainResult::Retry);
+    }
+}
+
+fn spawn_sender(eg: &EventGraph, state: &State) -> ScopeValue {
+    let eg = eg.clone();
+    let state = state.clone();
+    return spawn_rooted("Consuming outbox", async move {
+        let mut backoff = SEND_BACKOFF_MIN_MS;
+        loop {
+            state.0.sending.borrow_mut().dirty = false;
+            match drain_outbox(&eg, &state).await {
+                Ok(DrainResult::Empty) => {
+                    let mut sending = state.0.sending.borrow_mut();
+                    if !sending.dirty {
+                        // Drops this task, `wake_sender` starts a new one when needed
+                        sending.task = None;
+                        return Ok(());
+                    }
+                    continue;
                 },
-            }).await;
-            txn.await.into_result().context("Failed to commit transaction")?;
+                Ok(DrainResult::Done) => {
+                    backoff = SEND_BACKOFF_MIN_MS;
+                    continue;
+                },
+                Ok(DrainResult::Retry) => { },
+                Err(e) => {
+                    log!("Error sending queued messages: {}", e);
+                },
+            }
+            let (wake_tx, wake_rx) = oneshot::channel();
+            state.0.sending.borrow_mut().wake = Some(wake_tx);
+            select!{
+                _ = TimeoutFuture::new(backoff).fuse() => {
+                    backoff = (backoff * 2).min(SEND_BACKOFF_MAX_MS);
+                },
+                _ = wake_rx.fuse() => {
+                    backoff = SEND_BACKOFF_MIN_MS;
+                },
+            };
+            state.0.sending.borrow_mut().wake = None;
         }
-        return Ok(());
     });
 }
 
+/// Start sending queued messages if not already in progress, or retry immediately
+/// if waiting after a failure.
+fn wake_sender(eg: &EventGraph, state: &State) {
+    let mut sending = state.0.sending.borrow_mut();
+    sending.dirty = true;
+    if let Some(wake) = sending.wake.take() {
+        _ = wake.send(());
+    }
+    if sending.task.is_none() {
+        sending.task = Some(spawn_sender(eg, state));
+    }
+}
+
 async fn send(
     eg: EventGraph,
     state: State,
@@ -241,6 +672,7 @@ async fn send(
 ) -> Result<(), String> {
     let textarea = textarea.dyn_ref::<HtmlInputElement>().unwrap();
     let text = textarea.value();
+    let identity = state.0.compose_identity.borrow().clone();
     let local_id =
         format!(
             "{}_{}",
@@ -259,19 +691,18 @@ async fn send(
                     .transaction_on_one_with_mode(TABLE_OUTBOX, web_sys::IdbTransactionMode::Readwrite)
                     .context("Failed to start transaction")?;
             let outbox = txn.object_store(TABLE_OUTBOX).context("Failed to get outbox")?;
-            dbmodel::put_outbox(&outbox, OutboxEntry::V1(OutboxEntryV1 {
+            dbmodel::put_outbox(&outbox, OutboxEntry::V2(OutboxEntryV2 {
                 stamp: Utc::now(),
+                identity: identity,
                 channel: channel.clone(),
                 reply: reply.clone(),
                 local_id: local_id.clone(),
                 body: text,
                 resolved_id: None,
-            })).await;
+                error: None,
+            })).await?;
             txn.await.into_result().context("Failed to commit transaction")?;
-            let mut sending = state.0.sending.borrow_mut();
-            if sending.is_none() {
-                *sending = Some(spawn_sender(&state));
-            }
+            wake_sender(&eg, &state);
             if let Some(feed) = &*state.0.outbox_feed.borrow() {
                 feed.notify(eg, channel.clone(), local_id.clone());
             }
@@ -282,6 +713,40 @@ async fn send(
     return Ok(());
 }
 
+/// Select which of the user's identities new messages are sent as.
+fn build_identity_select(pc: &mut ProcessingContext, state: &State) -> El {
+    return async_block("getting own identities for compose", {
+        let state = state.clone();
+        let eg = pc.eg();
+        async move {
+            let identities = state.0.world.call(U2SGetOwnIdentities).await?;
+            let selected = state.0.compose_identity.borrow().clone();
+            let select = el("select").classes(&["identity_select"]);
+            for (i, identity) in identities.iter().enumerate() {
+                let option = el("option").attr("value", &identity.id.0).text(&identity.name);
+
+                // The first identity is the default
+                if selected.as_ref() == Some(&identity.id) || (selected.is_none() && i == 0) {
+                    option.ref_attr("selected", "");
+                }
+                select.ref_push(option);
+            }
+            let select = select.on("change", {
+                let state = state.clone();
+                let eg = eg.clone();
+                move |e| {
+                    let select = e.target().unwrap().dyn_into::<HtmlSelectElement>().unwrap();
+                    let identity = IdentityId(select.value());
+                    eg.event(|pc| {
+                        state.0.compose_identity.set(pc, Some(identity));
+                    });
+                }
+            });
+            return Ok(vec![select]);
+        }
+    });
+}
+
 fn build_compose(
     pc: &mut ProcessingContext,
     state: &State,
@@ -295,6 +760,7 @@ fn build_compose(
     let do_async = Rc::new(do_async);
     compose.ref_classes(&["compose"]).ref_extend(vec![
         //. .
+        build_identity_select(pc, state),
         el("div").classes(&["textarea_resizer"]).push(textarea.clone().on_resize({
             let messages = messages.clone();
             move |_el, _inline_size, block_size| {
@@ -373,14 +839,17 @@ fn build_add_channel_create(pc: &mut ProcessingContext, state: &State) -> El {
                                 state
                                     .0
                                     .world
-                                    .req_post_ret::<ChannelId>(U2SPost::ChannelCreate { name: data.name.clone() })
+                                    .call(U2SChannelCreate {
+                                        identity: state.0.compose_identity.borrow().clone(),
+                                        name: data.name.clone(),
+                                    })
                                     .await?;
                             eg.event(|pc| {
                                 let channel = Channel {
                                     id: channel_id.clone(),
                                     name: Prim::new(pc, data.name),
                                 };
-                                state.0.channels.set(channel_id.clone(), channel);
+                                state.0.channels.set(pc, channel_id.clone(), channel);
                                 replace_temp_view(pc, &state, TempViewState::AddChannelCreate, None);
                                 set_view_nav(pc, &state, &ViewStateId::Channel(ChannelViewStateId {
                                     id: channel_id,
@@ -409,8 +878,6 @@ fn build_add_channel_link(pc: &mut ProcessingContext, state: &State) -> El {
     struct Data {
         #[title("Channel ID")]
         link: String,
-        #[title("Name")]
-        name: String,
     }
 
     let form = Data::new_form("");
@@ -434,16 +901,12 @@ fn build_add_channel_link(pc: &mut ProcessingContext, state: &State) -> El {
                                         &data.link,
                                     ).context("Couldn't parse channel as zbase32")?,
                                 ).context("Couldn't parse channel bytes as bincode")?;
-                            state.0.world.req_post_ret::<ChannelId>(U2SPost::ChannelJoin {
-                                name: data.name.clone(),
+                            state.0.world.call(U2SChannelJoin {
+                                identity: state.0.compose_identity.borrow().clone(),
                                 id: channel_id.clone(),
                             }).await?;
+                            state.0.channels.get_async(channel_id.clone()).await?;
                             eg.event(|pc| {
-                                let channel = Channel {
-                                    id: channel_id.clone(),
-                                    name: Prim::new(pc, data.name),
-                                };
-                                state.0.channels.set(channel_id.clone(), channel);
                                 replace_temp_view(pc, &state, TempViewState::AddChannelLink, None);
                                 set_view_nav(pc, &state, &ViewStateId::Channel(ChannelViewStateId {
                                     id: channel_id,
@@ -468,8 +931,8 @@ fn build_add_channel_link(pc: &mut ProcessingContext, state: &State) -> El {
 }
 
 async fn finish_push_reg(eg: &EventGraph, state: &State, sub: PushSubscription) -> Result<(), String> {
-    let sub_json = sub.to_json().unwrap().as_string().unwrap();
-    state.0.world.req_post(U2SPost::SubscribePush(sub_json)).await?;
+    let sub_json = js_sys::JSON::stringify(&sub.to_json().unwrap()).unwrap().as_string().unwrap();
+    state.0.world.call(U2SSubscribePush(sub_json)).await?;
     eg.event(|pc| {
         state.0.push_reg_state.set(pc, narrowcore::state::PushRegState::Init);
     });
@@ -493,7 +956,7 @@ fn build_setup_push_reg(pc: &mut ProcessingContext, state: &State) -> El {
                             state
                                 .0
                                 .world
-                                .req_get::<Vec<u8>>(U2SGet::GetPushPubKey)
+                                .call(U2SGetPushPubKey)
                                 .await
                                 .context("Error retrieving push reg server key from server")?;
                         let js_server_key = Uint8Array::new_with_length(server_key.len() as u32);
@@ -564,9 +1027,278 @@ fn build_add_channel(pc: &mut ProcessingContext, state: &State) -> El {
     ]));
 }
 
+/// End the session and discard all locally stored user data, then return to the
+/// login screen.
+async fn logout(eg: EventGraph, state: State) -> Result<(), String> {
+    state.0.world.logout().await?;
+    let txn =
+        state
+            .0
+            .db
+            .transaction_on_one_with_mode(TABLE_OUTBOX, web_sys::IdbTransactionMode::Readwrite)
+            .context("Failed to start transaction")?;
+    txn
+        .object_store(TABLE_OUTBOX)
+        .context("Failed to get outbox")?
+        .clear()
+        .context("Failed to start clearing outbox")?
+        .await
+        .context("Failed to clear outbox")?;
+    txn.await.into_result().context("Failed to commit transaction")?;
+    state.0.message_cache.clear().await?;
+    eg.event(|pc| {
+        set_view_channels(pc, &state);
+        state.0.temp_view.clear(pc);
+        state.0.brew_list.clear(pc);
+        state.0.compose_identity.set(pc, None);
+        state.0.need_auth.set(pc, true);
+    });
+
+    // Main view is gone now, so all cached values are unused
+    state.0.channel_feeds.borrow_mut().clear();
+    state.0.channels.clear_unused();
+    state.0.brews.clear_unused();
+    state.0.identities.clear_unused();
+    state.0.channels.clear_persisted();
+    state.0.brews.clear_persisted();
+    return Ok(());
+}
+
+/// Move a channel within the list by `offset` places, or remove it if `offset` is
+/// None.
+fn move_brew_channel(pc: &mut ProcessingContext, channels: &List<ChannelId>, id: &ChannelId, offset: Option<isize>) {
+    let Some(i) = channels.borrow_values().iter().position(|c| c == id) else {
+        return;
+    };
+    channels.splice(pc, i, 1, vec![]);
+    let Some(offset) = offset else {
+        return;
+    };
+    let j = (i as isize + offset).clamp(0, channels.borrow_values().len() as isize) as usize;
+    channels.splice(pc, j, 0, vec![id.clone()]);
+}
+
+fn build_edit_brew(pc: &mut ProcessingContext, state: &State, id: &Option<BrewId>) -> El {
+    let body = async_block("Retrieving channels for brew editor", {
+        let state = state.clone();
+        let eg = pc.eg();
+        let id = id.clone();
+        async move {
+            let brew = match &id {
+                Some(id) => Some(state.0.brews.get_async(id.clone()).await?),
+                None => None,
+            };
+            let channels0 = state.0.world.call(U2SGetChannels).await?;
+            return eg.event(|pc| {
+                let name = el("input").attr("type", "text").attr("placeholder", "Name");
+                let selected = List::new(pc, vec![]);
+                if let Some(brew) = &brew {
+                    name.ref_attr("value", &*brew.name.borrow());
+                    selected.splice(pc, 0, 0, brew.channels.borrow_values().clone());
+                }
+                let inner = vbox();
+                let (outer, async_do) = async_area(pc, &inner);
+                let async_do = Rc::new(async_do);
+                let mut buttons = vec![space()];
+                if let Some(id) = &id {
+                    buttons.push(button({
+                        let state = state.clone();
+                        let eg = eg.clone();
+                        let id = id.clone();
+                        let async_do = async_do.clone();
+                        move || async_do({
+                            let state = state.clone();
+                            let eg = eg.clone();
+                            let id = id.clone();
+                            Box::pin(async move {
+                                state.0.world.call(U2SBrewDelete(id.clone())).await?;
+                                eg.event(|pc| {
+                                    forget_brew(pc, &state, &id);
+                                    replace_temp_view(pc, &state, TempViewState::EditBrew(Some(id)), None);
+                                });
+                                return Ok(());
+                            })
+                        })
+                    }).push(el("span").text("Delete")));
+                }
+                buttons.push(button({
+                    let state = state.clone();
+                    let eg = eg.clone();
+                    let id = id.clone();
+                    let name = name.clone();
+                    let selected = selected.clone();
+                    let async_do = async_do.clone();
+                    move || {
+                        let name = name.raw().dyn_into::<HtmlInputElement>().unwrap().value();
+                        let channels = selected.borrow_values().clone();
+                        async_do({
+                            let state = state.clone();
+                            let eg = eg.clone();
+                            let id = id.clone();
+                            Box::pin(async move {
+                                match id {
+                                    Some(id) => {
+                                        state.0.world.call(U2SBrewUpdate {
+                                            id: id.clone(),
+                                            name: name.clone(),
+                                            channels: channels.clone(),
+                                        }).await?;
+                                        eg.event(|pc| {
+                                            apply_brew(pc, &state, S2UBrew {
+                                                id: id.clone(),
+                                                name: name,
+                                                channels: channels,
+                                            });
+                                            replace_temp_view(pc, &state, TempViewState::EditBrew(Some(id)), None);
+                                        });
+                                    },
+                                    None => {
+                                        let id =
+                                            state
+                                                .0
+                                                .world
+                                                .call(U2SBrewCreate {
+                                                    name: name.clone(),
+                                                    channels: channels.clone(),
+                                                })
+                                                .await?;
+                                        eg.event(|pc| {
+                                            apply_brew(pc, &state, S2UBrew {
+                                                id: id,
+                                                name: name,
+                                                channels: channels,
+                                            });
+                                            replace_temp_view(pc, &state, TempViewState::EditBrew(None), None);
+                                        });
+                                    },
+                                }
+                                return Ok(());
+                            })
+                        });
+                    }
+                }).push(el("span").text("Save")));
+                buttons.push(space());
+                inner.ref_extend(vec![
+                    //. .
+                    name,
+                    el("span").text("Channels"),
+                    vbox().bind_list(pc, &selected, {
+                        let state = state.clone();
+                        let eg = eg.clone();
+                        let selected = selected.clone();
+                        move |pc, c| {
+                            let move_button = |icon_id: &str, offset: Option<isize>| {
+                                return button({
+                                    let eg = eg.clone();
+                                    let selected = selected.clone();
+                                    let c = c.clone();
+                                    move || eg.event(|pc| {
+                                        move_brew_channel(pc, &selected, &c, offset);
+                                    })
+                                }).push(icon(icon_id));
+                            };
+                            return hbox().extend(vec![
+                                //. .
+                                nol_span(pc, state.0.channels.get(c.clone()), |c| c.name.clone()),
+                                space(),
+                                move_button("arrow_upward", Some(-1)),
+                                move_button("arrow_downward", Some(1)),
+                                move_button("remove", None)
+                            ]);
+                        }
+                    }),
+                    el("span").text("Add channels"),
+                    vscroll().extend(channels0.into_iter().map(|c| {
+                        return hbox().extend(vec![el("span").text(&c.name), space(), button({
+                            let eg = eg.clone();
+                            let selected = selected.clone();
+                            move || eg.event(|pc| {
+                                if !selected.borrow_values().contains(&c.id) {
+                                    selected.push(pc, c.id.clone());
+                                }
+                            })
+                        }).push(icon("add"))]);
+                    }).collect()),
+                    hbox().extend(buttons)
+                ]);
+                return Ok(vec![outer]);
+            });
+        }
+    });
+    return modal(if id.is_some() {
+        "Edit brew"
+    } else {
+        "Create brew"
+    }, {
+        let state = state.clone();
+        let eg = pc.eg();
+        let id = id.clone();
+        move || eg.event(|pc| {
+            replace_temp_view(pc, &state, TempViewState::EditBrew(id.clone()), None);
+        })
+    }, body);
+}
+
 fn build_channels(pc: &mut ProcessingContext, state: &State) -> El {
-    fn build_channel(pc: &mut ProcessingContext, channel: &Channel) -> El {
-        return hbox().extend(vec![el("span").bind_text(pc, &channel.name)]);
+    fn build_channel(
+        pc: &mut ProcessingContext,
+        state: &State,
+        channel: &Channel,
+        summary: &S2UChannelSummary,
+    ) -> El {
+        let unread = el("span").classes(&["unread"]).text(&summary.unread.to_string());
+        match &summary.last_message {
+            Some(last) if summary.unread > 0 => {
+                let read = read_position(pc, state, &channel.id);
+                unread.ref_own(|e| link!((_pc = pc), (read = read), (), (e = e.weak(), last = last.id.clone()) {
+                    let e = e.upgrade()?;
+
+                    // Read elsewhere since the summary was fetched
+                    e.ref_modify_classes(&[(CSS_HIDE, read.borrow().as_ref().map(|r| r >= last).unwrap_or(false))]);
+                }));
+            },
+            _ => {
+                unread.ref_classes(&[CSS_HIDE]);
+            },
+        }
+        return button({
+            let state = state.clone();
+            let eg = pc.eg();
+            let id = channel.id.clone();
+            move || eg.event(|pc| {
+                set_view_nav(pc, &state, &ViewStateId::Channel(ChannelViewStateId {
+                    id: id.clone(),
+                    message: None,
+                }));
+            })
+        }).classes(&["channel"]).extend(vec![
+            //. .
+            hbox().extend(vec![el("span").classes(&["name"]).bind_text(pc, &channel.name), space(), unread]),
+            el("span")
+                .classes(&["preview"])
+                .text(summary.last_message.as_ref().map(|m| m.text.as_str()).unwrap_or(""))
+        ]);
+    }
+
+    fn build_brew(pc: &mut ProcessingContext, state: &State, id: &BrewId) -> El {
+        return hbox().extend(vec![button({
+            let state = state.clone();
+            let eg = pc.eg();
+            let id = id.clone();
+            move || eg.event(|pc| {
+                set_view_nav(pc, &state, &ViewStateId::Brew(BrewViewStateId {
+                    id: id.clone(),
+                    channel: None,
+                }));
+            })
+        }).push(nol_span(pc, state.0.brews.get(id.clone()), |b| b.name.clone())), space(), button({
+            let state = state.clone();
+            let eg = pc.eg();
+            let id = id.clone();
+            move || eg.event(|pc| {
+                state.0.temp_view.push(pc, TempViewState::EditBrew(Some(id.clone())));
+            })
+        }).push(icon("edit"))]);
     }
 
     let list = el("div");
@@ -575,21 +1307,30 @@ fn build_channels(pc: &mut ProcessingContext, state: &State) -> El {
         let eg = pc.eg();
         let list = list.clone();
         async move {
-            let channels0: Vec<S2UChannel> = state.0.world.req_get(U2SGet::GetChannels).await?;
+            let brews0 = state.0.world.call(U2SGetBrews).await?;
+            let channels0 = state.0.world.call(U2SGetChannelSummaries).await?;
             eg.event(|pc| {
-                let channels1: Vec<Hard<ChannelId, Channel>> = channels0.into_iter().map(|c| {
-                    match state.0.channels.get(c.id.clone()) {
-                        NowOrLater::Now(c) => c,
-                        NowOrLater::Later(_) => {
-                            state.0.channels.set(c.id.clone(), Channel {
-                                id: c.id,
-                                name: Prim::new(pc, c.name),
-                            })
-                        },
-                    }
+                let brew_ids = brews0.into_iter().map(|b| {
+                    let brew = Brew {
+                        id: b.id.clone(),
+                        name: Prim::new(pc, b.name),
+                        channels: List::new(pc, b.channels),
+                    };
+                    state.0.brews.set(pc, b.id.clone(), brew);
+                    return b.id;
+                }).collect();
+                let len = state.0.brew_list.borrow_values().len();
+                state.0.brew_list.splice(pc, 0, len, brew_ids);
+                let channels1: Vec<(Hard<ChannelId, Channel>, S2UChannelSummary)> = channels0.into_iter().map(|s| {
+                    let channel = Channel {
+                        id: s.channel.id.clone(),
+                        name: Prim::new(pc, s.channel.name.clone()),
+                    };
+                    let channel = state.0.channels.set(pc, s.channel.id.clone(), channel);
+                    return (channel, s);
                 }).collect();
                 list.ref_clear();
-                list.ref_extend(channels1.into_iter().map(|c| build_channel(pc, &*c)).collect());
+                list.ref_extend(channels1.into_iter().map(|(c, s)| build_channel(pc, &state, &*c, &s)).collect());
             });
             return Ok(());
         }
@@ -602,8 +1343,27 @@ fn build_channels(pc: &mut ProcessingContext, state: &State) -> El {
             move || eg.event(|pc| {
                 state.0.temp_view.push(pc, TempViewState::AddChannel);
             })
-        }).push(icon("add"))]),
-        vscroll().push(list)
+        }).push(icon("add")), space(), button({
+            let state = state.clone();
+            let eg = pc.eg();
+            move || bg("Logging out", logout(eg.clone(), state.clone()))
+        }).push(icon("logout"))]),
+        vscroll().extend(vec![
+            //. .
+            hbox().extend(vec![el("span").text("Brews"), space(), button({
+                let state = state.clone();
+                let eg = pc.eg();
+                move || eg.event(|pc| {
+                    state.0.temp_view.push(pc, TempViewState::EditBrew(None));
+                })
+            }).push(icon("add"))]),
+            vbox().bind_list(pc, &state.0.brew_list, {
+                let state = state.clone();
+                move |pc, id| build_brew(pc, &state, id)
+            }),
+            el("span").text("Channels"),
+            list
+        ])
     ]);
 }
 
@@ -615,39 +1375,101 @@ fn build_messages(pc: &mut ProcessingContext, state: &State, messages_view_state
         async move {
             let mut feeds: HashMap<Option<ChannelId>, Box<dyn Feed<Option<ChannelId>, FeedTime>>> =
                 HashMap::new();
-            let outbox_feed = OutboxFeed::new(state.0.db.clone());
+            let mut brew = None;
+            let mode = messages_view_state.borrow().clone();
+            let channel_ids = match mode {
+                MessagesViewMode::Brew(b) => {
+                    let brew = brew.insert(state.0.brews.get_async(b.id.clone()).await?);
+                    let out = brew.channels.borrow_values().clone();
+                    out
+                },
+                MessagesViewMode::Channel(c) => vec![c.id],
+            };
+            let outbox_feed = OutboxFeed::new(state.0.db.clone(), channel_ids.clone(), {
+                let state = state.clone();
+                let eg = eg.clone();
+                move || wake_sender(&eg, &state)
+            });
             feeds.insert(None, Box::new(outbox_feed.clone()));
             *state.0.outbox_feed.borrow_mut() = Some(outbox_feed);
-            {
-                let mut state_feeds = state.0.channel_feeds.borrow_mut();
-                match &*messages_view_state.borrow() {
-                    MessagesViewMode::Brew(b) => {
-                        let brew = state.0.brews.get_async(b.id.clone()).await?;
-                        for channel_id in &*brew.channels.borrow_values() {
-                            let feed = ChannelFeed::new(state.0.world.clone(), channel_id.clone());
-                            feeds.insert(Some(channel_id.clone()), Box::new(feed.clone()));
-                            state_feeds.push(feed);
+            let mut channel_feeds = vec![];
+            for channel_id in channel_ids {
+                let feed = ChannelFeed::new(
+                    state.0.world.clone(),
+                    channel_id.clone(),
+                    channel_event_handler(&state),
+                    author_renderer(&state),
+                    state.0.seen_messages.clone(),
+                    state.0.message_cache.clone(),
+                );
+                feeds.insert(Some(channel_id), Box::new(feed.clone()));
+                state.0.channel_feeds.borrow_mut().push(feed.clone());
+                channel_feeds.push(feed);
+            }
+
+            // Show cached messages right away, the "new messages" divider is added once the
+            // read positions arrive
+            let load_first_unread = spawn_rooted("Looking up first unread messages", {
+                let state = state.clone();
+                let eg = eg.clone();
+                async move {
+                    let first_unreads =
+                        try_join_all(channel_feeds.iter().map(|f| first_unread(&state, f.channel()))).await?;
+                    eg.event(|pc| {
+                        for (feed, first_unread) in channel_feeds.iter().zip(first_unreads) {
+                            feed.set_first_unread(pc, first_unread);
                         }
-                    },
-                    MessagesViewMode::Channel(c) => {
-                        let feed = ChannelFeed::new(state.0.world.clone(), c.id.clone());
-                        feeds.insert(Some(c.id.clone()), Box::new(feed.clone()));
-                        state_feeds.push(feed);
-                    },
+                    });
+                    return Ok(());
                 }
-            }
+            });
             return eg.event(|pc| {
-                let messages = Infiniscroll::new(&pc.eg(), FeedTime {
+                let reset_time = state.0.restore_anchor.borrow_mut().take().unwrap_or_else(|| FeedTime {
                     stamp: Utc::now() + Duration::seconds(30),
                     id: FeedId::None,
-                }, feeds);
-                return Ok(vec![vbox().own(|_| defer({
+                });
+                let messages = Infiniscroll::new(&pc.eg(), reset_time, feeds);
+                *state.0.messages.borrow_mut() = Some(messages.clone());
+                messages.set_on_late_end_seen({
+                    let state = state.clone();
+                    let eg = pc.eg();
+                    move |latest| {
+                        for (_, time) in latest {
+                            let FeedId::Real(id) = time.id else {
+                                continue;
+                            };
+                            if !eg.event(|pc| advance_read_position(pc, &state, &id)) {
+                                continue;
+                            }
+                            bg("Advancing read position", {
+                                let state = state.clone();
+                                async move {
+                                    state.0.world.call(U2SSetReadPosition(id)).await?;
+                                    return Ok(());
+                                }
+                            });
+                        }
+                    }
+                });
+                return Ok(vec![vbox().own(|_| load_first_unread).own(|_| defer({
                     let state = state.clone();
                     move || {
                         state.0.channel_feeds.borrow_mut().clear();
                         state.0.outbox_feed.borrow_mut().take();
+                        state.0.messages.borrow_mut().take();
+                    }
+                })).own(|_| brew.map(|brew| link!(
+                    //. .
+                    (pc = pc), (channels = brew.channels.clone()), (view = state.0.view.clone()), (_brew = brew.clone()) {
+                        // Feeds are fixed at build time, so rebuild the view when the brew's channels
+                        // change
+                        if channels.borrow_changes().is_empty() {
+                            return None;
+                        }
+                        let view1 = view.borrow().clone();
+                        view.set(pc, view1);
                     }
-                })).extend(vec![
+                ))).extend(vec![
                     //. .
                     stack().extend(vec![
                         //. .
@@ -656,7 +1478,7 @@ fn build_messages(pc: &mut ProcessingContext, state: &State, messages_view_state
                             let eg = pc.eg();
                             let state = state.clone();
                             move || eg.event(|pc| {
-                                state.0.view.set(pc, ViewState::Channels);
+                                set_view_channels_nav(pc, &state);
                             })
                         }).push(icon("back")), group().own(|e| link!(
                             //. .
@@ -788,34 +1610,8 @@ fn build_messages(pc: &mut ProcessingContext, state: &State, messages_view_state
                         let state = state.clone();
                         defer(move || bg("Cleaning up outbox post-view", {
                             async move {
-                                let txn =
-                                    state
-                                        .0
-                                        .db
-                                        .transaction_on_multi_with_mode(
-                                            &[TABLE_OUTBOX],
-                                            web_sys::IdbTransactionMode::Readwrite,
-                                        )
-                                        .context("Failed to start transaction")?;
-                                let outbox = txn.object_store(TABLE_OUTBOX).context("Failed to get outbox")?;
-                                let sent_index =
-                                    outbox.index(TABLE_OUTBOX_INDEX_SENT).context("Failed to get sent index")?;
-                                if sent_index
-                                    .open_cursor_with_range(
-                                        &IdbKeyRange::bound(
-                                            &outbox_sent_partial_key_unsent(),
-                                            &outbox_sent_partial_key_sent(),
-                                        ).unwrap(),
-                                    )
-                                    .context("Failed to open outbox cursor")?
-                                    .await
-                                    .context("Error waiting for cursor")?
-                                    .is_none() {
-                                    // No elements
-                                    return Ok(());
-                                }
-                                outbox.clear().unwrap();
-                                txn.await.into_result().context("Failed to commit transaction")?;
+                                let seen = state.0.seen_messages.take();
+                                dbmodel::gc_outbox(&state.0.db, &seen).await?;
                                 return Ok(());
                             }
                         }))
@@ -828,6 +1624,7 @@ fn build_messages(pc: &mut ProcessingContext, state: &State, messages_view_state
 
 fn build_main(pc: &mut ProcessingContext, state: &State) -> El {
     return stack()
+        .own(|_| spawn_event_stream(&pc.eg(), state))
         .own(
             |_| link!(
                 (pc = pc),
@@ -899,6 +1696,9 @@ fn build_main(pc: &mut ProcessingContext, state: &State) -> El {
                         TempViewState::AddChannelLink => {
                             return build_add_channel_link(pc, &state);
                         },
+                        TempViewState::EditBrew(id) => {
+                            return build_edit_brew(pc, &state, id);
+                        },
                     }
                 }
             })
@@ -928,10 +1728,12 @@ fn build_auth(pc: &mut ProcessingContext, state: &State) -> El {
                 let Ok(details) = form.parse() else {
                     return Err(format!("There were issues with the information you provided."));
                 };
-                state.0.world.req_post(U2SPost::Auth {
-                    username: details.username.clone(),
-                    password: details.password.0,
-                }).await.log_replace("Error authing", "There was an error logging in, please try again.")?;
+                state
+                    .0
+                    .world
+                    .login(details.username.clone(), details.password.0)
+                    .await
+                    .log_replace("Error authing", "There was an error logging in, please try again.")?;
                 eg.event(|pc| {
                     state.0.need_auth.set(pc, false);
                 });
@@ -948,31 +1750,26 @@ fn main() {
         let db = dbmodel::new_db().await?;
         let swreg = serviceworker::install().await?;
         let eg = lunk::EventGraph::new();
+        let world = World::new();
+        let state = eg.event(|pc| State::new(pc, db, swreg, &world));
+        state.load_persisted().await;
         return Ok(eg.event(|pc| {
-            let world = World::new();
-            let state = State::new(pc, db, swreg, &world);
-            {
-                let mut sending = state.0.sending.borrow_mut();
-                if sending.is_none() {
-                    *sending = Some(spawn_sender(&state));
-                }
-            }
-            match (|| {
-                let search =
-                    window()
-                        .location()
-                        .search()
-                        .map_err(|e| e.dyn_ref::<Object>().unwrap().to_string())
-                        .context("Error reading window location search")?;
-                if search.is_empty() {
-                    return Ok(());
-                }
-                let query = search.strip_prefix("?").context("Missing ? at start of location search")?;
-                let nav = serde_json::from_str(&query).context("Failed to parse query as json")?;
-                set_view(pc, &state, &nav);
-                return Ok(()) as Result<(), String>;
-            })() {
-                Ok(_) => { },
+            world.set_on_need_auth({
+                let state = state.clone();
+                let eg = pc.eg();
+                move || eg.event(|pc| {
+                    // Avoid rebuilding the login form if several requests fail
+                    if !*state.0.need_auth.borrow() {
+                        state.0.need_auth.set(pc, true);
+                    }
+                })
+            });
+            wake_sender(&pc.eg(), &state);
+            match location_view() {
+                Ok(Some(nav)) => {
+                    set_view(pc, &state, &nav);
+                },
+                Ok(None) => { },
                 Err(e) => {
                     log!("Error parsing state from location, using default: {}", e);
                 },
@@ -996,15 +1793,19 @@ fn main() {
                             let e = e.dyn_ref::<MessageEvent>().unwrap();
                             let server_time: DateMessageId =
                                 serde_json::from_str(&e.data().as_string().unwrap()).unwrap();
-                            eg.event(|pc| {
-                                for f in &mut *state.0.channel_feeds.borrow_mut() {
-                                    f.notify(pc.eg(), server_time.clone());
-                                }
-                            });
+                            eg.event(|pc| notify_feeds(pc, &state, server_time));
                         }
                     });
                     return (bc, listener);
-                })
+                }).own(|_| EventListener::new(&window(), "popstate", {
+                    let state = state.clone();
+                    let eg = pc.eg();
+                    move |_| eg.event(|pc| restore_history_view(pc, &state))
+                })).own(|_| EventListener::new(&window(), "online", {
+                    let state = state.clone();
+                    let eg = pc.eg();
+                    move |_| wake_sender(&eg, &state)
+                }))
             ];
         }));
     })]);
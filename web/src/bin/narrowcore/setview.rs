@@ -3,10 +3,16 @@ use lunk::{
     ProcessingContext,
     Prim,
 };
-use wasm_bindgen::JsValue;
+use wasm_bindgen::{
+    JsCast,
+    JsValue,
+};
+use js_sys::Object;
 use web::{
     world::FeedId,
     scrollentry::FeedTime,
+    util::MyError,
+    log,
 };
 use super::{
     viewid::{
@@ -181,22 +187,98 @@ pub fn set_view_message(pc: &mut ProcessingContext, state: &State, message_time:
     });
 }
 
+/// Query string (or bare path, for the channel list) representing a view.
+fn view_url(id: Option<&ViewStateId>) -> String {
+    match id {
+        Some(id) => return format!("?{}", serde_json::to_string(id).unwrap()),
+        None => return window().location().pathname().unwrap(),
+    }
+}
+
+/// Record the current scroll position in the current history entry, so it can be
+/// restored when navigating back to it.
+fn save_anchor(state: &State) {
+    let anchor = state.0.messages.borrow().as_ref().and_then(|m| m.anchor());
+    let Some(anchor) = anchor else {
+        return;
+    };
+    window()
+        .history()
+        .unwrap()
+        .replace_state(&JsValue::from_str(&serde_json::to_string(&anchor).unwrap()), "")
+        .unwrap();
+}
+
 pub fn set_view(pc: &mut ProcessingContext, state: &State, id: &ViewStateId) {
     if set_view_(pc, state, id) {
-        window()
-            .history()
-            .unwrap()
-            .replace_state_with_url(&JsValue::NULL, "", Some(&format!("?{}", serde_json::to_string(id).unwrap())))
-            .unwrap();
+        window().history().unwrap().replace_state_with_url(&JsValue::NULL, "", Some(&view_url(Some(id)))).unwrap();
     }
 }
 
 pub fn set_view_nav(pc: &mut ProcessingContext, state: &State, id: &ViewStateId) {
+    save_anchor(state);
+    *state.0.restore_anchor.borrow_mut() = None;
     if set_view_(pc, state, id) {
+        window().history().unwrap().push_state_with_url(&JsValue::NULL, "", Some(&view_url(Some(id)))).unwrap();
+    }
+}
+
+/// Like `set_view` but for the channel list.
+pub fn set_view_channels(pc: &mut ProcessingContext, state: &State) {
+    state.0.view.set(pc, ViewState::Channels);
+    window().history().unwrap().replace_state_with_url(&JsValue::NULL, "", Some(&view_url(None))).unwrap();
+}
+
+/// Like `set_view_nav` but for the channel list.
+pub fn set_view_channels_nav(pc: &mut ProcessingContext, state: &State) {
+    save_anchor(state);
+    state.0.view.set(pc, ViewState::Channels);
+    window().history().unwrap().push_state_with_url(&JsValue::NULL, "", Some(&view_url(None))).unwrap();
+}
+
+/// Parse the view from the location query, None for the channel list.
+pub fn location_view() -> Result<Option<ViewStateId>, String> {
+    let search =
+        window()
+            .location()
+            .search()
+            .map_err(|e| e.dyn_ref::<Object>().unwrap().to_string())
+            .context("Error reading window location search")?;
+    if search.is_empty() {
+        return Ok(None);
+    }
+    let query = search.strip_prefix("?").context("Missing ? at start of location search")?;
+    return Ok(Some(serde_json::from_str(&query).context("Failed to parse query as json")?));
+}
+
+/// Switch to the view of the current history entry (after back/forward), restoring
+/// the saved scroll position.
+pub fn restore_history_view(pc: &mut ProcessingContext, state: &State) {
+    let id = match location_view() {
+        Ok(id) => id,
+        Err(e) => {
+            log!("Error parsing state from location, using default: {}", e);
+            None
+        },
+    };
+    let anchor =
         window()
             .history()
             .unwrap()
-            .push_state_with_url(&JsValue::NULL, "", Some(&format!("?{}", serde_json::to_string(id).unwrap())))
-            .unwrap();
+            .state()
+            .ok()
+            .and_then(|s| s.as_string())
+            .and_then(|s| serde_json::from_str::<FeedTime>(&s).ok());
+
+    // Always rebuild, since the messages view doesn't follow every change in place
+    state.0.view.set(pc, ViewState::Channels);
+    match id {
+        Some(id) => {
+            *state.0.restore_anchor.borrow_mut() = anchor;
+            set_view_(pc, state, &id);
+        },
+        None => {
+            *state.0.restore_anchor.borrow_mut() = None;
+        },
     }
 }
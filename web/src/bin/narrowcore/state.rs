@@ -3,8 +3,13 @@ use std::{
     rc::Rc,
     pin::pin,
     cell::RefCell,
+    collections::HashMap,
 };
-use chrono::Utc;
+use chrono::{
+    Duration,
+    Utc,
+};
+use futures::channel::oneshot;
 use indexed_db_futures::IdbDatabase;
 use lunk::{
     Prim,
@@ -14,17 +19,33 @@ use lunk::{
 };
 use rooting::ScopeValue;
 use web::{
-    world::{
-        World,
+    world::World,
+    util::MyError,
+    interface::u2s::{
         BrewId,
         ChannelId,
+        IdentityId,
+        MessageId,
         S2UBrew,
-        U2SGet,
         S2UChannel,
+        U2SGetBrewsById,
+        U2SGetChannelsById,
+        U2SGetIdentitiesById,
     },
     noworlater::NowOrLaterCollection,
+    dbmodel::{
+        nol_persist,
+        TABLE_NOL_BREWS,
+        TABLE_NOL_CHANNELS,
+    },
     outboxfeed::OutboxFeed,
-    messagefeed::ChannelFeed,
+    messagecache::MessageCache,
+    messagefeed::{
+        ChannelFeed,
+        SeenMessages,
+    },
+    infiniscroll::Infiniscroll,
+    scrollentry::FeedTime,
 };
 use web_sys::ServiceWorkerRegistration;
 use super::{
@@ -32,9 +53,17 @@ use super::{
         ViewState,
         Brew,
         Channel,
+        Identity,
     },
 };
 
+/// There are no events for identity changes, so identities in use are refetched
+/// after this long.
+const IDENTITY_TTL_MINUTES: i64 = 60;
+
+/// Most ids per batch lookup, the server's limit.
+const BATCH_MAX: usize = 200;
+
 #[derive(Clone, PartialEq)]
 pub enum PushRegState {
     Disabled,
@@ -42,6 +71,17 @@ pub enum PushRegState {
     Init,
 }
 
+/// Outbox drain loop bookkeeping.
+#[derive(Default)]
+pub struct Sending {
+    /// The running loop, if any. The loop clears this when the outbox is empty.
+    pub task: Option<ScopeValue>,
+    /// Set when entries may have been queued since the loop last read the outbox.
+    pub dirty: bool,
+    /// Present while the loop is waiting to retry, to skip the rest of the wait.
+    pub wake: Option<oneshot::Sender<()>>,
+}
+
 /// A non-session-persisted view state (menu, dialog, etc).
 #[derive(Clone, PartialEq)]
 pub enum TempViewState {
@@ -49,6 +89,8 @@ pub enum TempViewState {
     AddChannel,
     AddChannelCreate,
     AddChannelLink,
+    /// Brew editor, None to create a new brew
+    EditBrew(Option<BrewId>),
 }
 
 pub fn replace_temp_view(
@@ -89,10 +131,26 @@ pub struct State_ {
     pub view: Prim<ViewState>,
     pub temp_view: List<TempViewState>,
     pub brews: NowOrLaterCollection<BrewId, Brew>,
+    /// Brews listed in the channels view
+    pub brew_list: List<BrewId>,
     pub channels: NowOrLaterCollection<ChannelId, Channel>,
+    pub identities: NowOrLaterCollection<IdentityId, Identity>,
+    /// Identity new messages are sent as, None for the default
+    pub compose_identity: Prim<Option<IdentityId>>,
+    /// Last read message per channel, as far as known. Kept up to date by the event
+    /// stream.
+    pub read_positions: RefCell<HashMap<ChannelId, Prim<Option<MessageId>>>>,
     pub outbox_feed: RefCell<Option<OutboxFeed>>,
+    /// Scroller of the current messages view, if any
+    pub messages: RefCell<Option<Infiniscroll<Option<ChannelId>, FeedTime>>>,
+    /// Where to scroll to when the messages view is next built, when returning to a
+    /// history entry
+    pub restore_anchor: RefCell<Option<FeedTime>>,
     pub channel_feeds: RefCell<Vec<ChannelFeed>>,
-    pub sending: RefCell<Option<ScopeValue>>,
+    /// Messages loaded by channel feeds since the outbox was last cleaned up
+    pub seen_messages: SeenMessages,
+    pub sending: RefCell<Sending>,
+    pub message_cache: MessageCache,
 }
 
 #[derive(Clone)]
@@ -106,7 +164,7 @@ impl State {
         world: &World,
     ) -> State {
         return State(Rc::new(State_ {
-            db: db,
+            db: db.clone(),
             swreg: swreg,
             push_reg_state: Prim::new(pc, PushRegState::Uninit),
             eg: pc.eg(),
@@ -116,46 +174,132 @@ impl State {
             need_auth: Prim::new(pc, false),
             view: Prim::new(pc, ViewState::Channels),
             temp_view: List::new(pc, vec![]),
-            brews: NowOrLaterCollection::new({
+            brews: NowOrLaterCollection::new_batched(pc.eg(), {
+                let world = world.clone();
+                let eg = pc.eg();
+                move |ks: Vec<BrewId>| {
+                    let world = world.clone();
+                    let eg = eg.clone();
+                    Box::pin(async move {
+                        let world = pin!(world);
+                        let resp = world.call(U2SGetBrewsById(ks)).await?;
+                        return eg.event(|pc| {
+                            Ok(resp.into_iter().map(|b| match b {
+                                Some(b) => Ok(Brew {
+                                    name: Prim::new(pc, b.name),
+                                    id: b.id,
+                                    channels: List::new(pc, b.channels),
+                                }),
+                                None => Err("Unknown brew".to_string()),
+                            }).collect())
+                        });
+                    })
+                }
+            }, BATCH_MAX, |pc: &mut ProcessingContext, live: &Brew, fresh: Brew| {
+                let name = fresh.name.borrow().clone();
+                if *live.name.borrow() != name {
+                    live.name.set(pc, name);
+                }
+                let channels = fresh.channels.borrow_values().clone();
+                let old_len = live.channels.borrow_values().len();
+                if *live.channels.borrow_values() != channels {
+                    live.channels.splice(pc, 0, old_len, channels);
+                }
+            }).with_persist(nol_persist(db.clone(), TABLE_NOL_BREWS, |v: &Brew| S2UBrew {
+                id: v.id.clone(),
+                name: v.name.borrow().clone(),
+                channels: v.channels.borrow_values().clone(),
+            }, {
+                let eg = pc.eg();
+                move |p: S2UBrew| eg.event(|pc| Brew {
+                    name: Prim::new(pc, p.name),
+                    id: p.id,
+                    channels: List::new(pc, p.channels),
+                })
+            })),
+            brew_list: List::new(pc, vec![]),
+            channels: NowOrLaterCollection::new_batched(pc.eg(), {
                 let world = world.clone();
                 let eg = pc.eg();
-                move |k: BrewId| {
+                move |ks: Vec<ChannelId>| {
                     let world = world.clone();
                     let eg = eg.clone();
                     Box::pin(async move {
                         let world = pin!(world);
-                        let resp = world.req_get::<S2UBrew>(U2SGet::GetBrew(k.clone())).await?;
+                        let resp = world.call(U2SGetChannelsById(ks)).await?;
                         return eg.event(|pc| {
-                            Ok(Brew {
-                                name: Prim::new(pc, resp.name),
-                                id: k.clone(),
-                                channels: List::new(pc, resp.channels),
-                            })
+                            Ok(resp.into_iter().map(|c| match c {
+                                Some(c) => Ok(Channel {
+                                    name: Prim::new(pc, c.name),
+                                    id: c.id,
+                                }),
+                                None => Err("Unknown channel".to_string()),
+                            }).collect())
                         });
                     })
                 }
-            }),
-            channels: NowOrLaterCollection::new({
+            }, BATCH_MAX, |pc: &mut ProcessingContext, live: &Channel, fresh: Channel| {
+                let name = fresh.name.borrow().clone();
+                if *live.name.borrow() != name {
+                    live.name.set(pc, name);
+                }
+            }).with_persist(nol_persist(db.clone(), TABLE_NOL_CHANNELS, |v: &Channel| S2UChannel {
+                id: v.id.clone(),
+                name: v.name.borrow().clone(),
+            }, {
+                let eg = pc.eg();
+                move |p: S2UChannel| eg.event(|pc| Channel {
+                    name: Prim::new(pc, p.name),
+                    id: p.id,
+                })
+            })),
+            identities: NowOrLaterCollection::new_batched(pc.eg(), {
                 let world = world.clone();
                 let eg = pc.eg();
-                move |k: ChannelId| {
+                move |ks: Vec<IdentityId>| {
                     let world = world.clone();
                     let eg = eg.clone();
                     Box::pin(async move {
                         let world = pin!(world);
-                        let resp = world.req_get::<S2UChannel>(U2SGet::GetChannel(k.clone())).await?;
+                        let resp = world.call(U2SGetIdentitiesById(ks)).await?;
                         return eg.event(|pc| {
-                            Ok(Channel {
-                                name: Prim::new(pc, resp.name),
-                                id: k.clone(),
-                            })
+                            Ok(resp.into_iter().map(|i| match i {
+                                Some(i) => Ok(Identity {
+                                    name: Prim::new(pc, i.name),
+                                    avatar: Prim::new(pc, i.avatar),
+                                    id: i.id,
+                                }),
+                                None => Err("Unknown identity".to_string()),
+                            }).collect())
                         });
                     })
                 }
-            }),
+            }, BATCH_MAX, |pc: &mut ProcessingContext, live: &Identity, fresh: Identity| {
+                let name = fresh.name.borrow().clone();
+                if *live.name.borrow() != name {
+                    live.name.set(pc, name);
+                }
+                let avatar = fresh.avatar.borrow().clone();
+                if *live.avatar.borrow() != avatar {
+                    live.avatar.set(pc, avatar);
+                }
+            }).with_ttl(Duration::minutes(IDENTITY_TTL_MINUTES)),
+            compose_identity: Prim::new(pc, None),
+            read_positions: RefCell::new(HashMap::new()),
             outbox_feed: RefCell::new(None),
+            messages: RefCell::new(None),
+            restore_anchor: RefCell::new(None),
             channel_feeds: RefCell::new(vec![]),
-            sending: RefCell::new(None),
+            seen_messages: Default::default(),
+            sending: RefCell::new(Sending::default()),
+            message_cache: MessageCache::new(db),
         }));
     }
+
+    /// Restore values saved by the last session, so the first render isn't
+    /// placeholders.
+    pub async fn load_persisted(&self) {
+        self.0.channels.load_persisted().await.log_ignore("Error loading saved channels");
+        self.0.brews.load_persisted().await.log_ignore("Error loading saved brews");
+    }
 }
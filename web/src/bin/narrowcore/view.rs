@@ -3,10 +3,11 @@ use lunk::{
     List,
 };
 use web::{
-    world::{
+    interface::u2s::{
         MessageId,
         ChannelId,
         BrewId,
+        IdentityId,
     },
     scrollentry::FeedTime,
 };
@@ -17,6 +18,14 @@ pub struct Message {
     pub text: Prim<String>,
 }
 
+#[derive(Clone)]
+pub struct Identity {
+    pub id: IdentityId,
+    pub name: Prim<String>,
+    /// Image url
+    pub avatar: Prim<Option<String>>,
+}
+
 #[derive(Clone)]
 pub struct Channel {
     pub id: ChannelId,
@@ -3,7 +3,7 @@ use serde::{
     Deserialize,
 };
 use web::{
-    world::{
+    interface::u2s::{
         ChannelId,
         BrewId,
     },
@@ -12,10 +12,10 @@ use wasm_bindgen::{
 };
 use web::{
     NOTIFY_CHANNEL,
-    world::{
+    world::U2SWPost,
+    interface::u2s::{
         S2SWPush,
         DateMessageId,
-        U2SWPost,
     },
     util::{
         MyErrorJsValue,
@@ -1,11 +1,17 @@
-use std::rc::Rc;
+use std::{
+    collections::HashSet,
+    rc::Rc,
+};
 use chrono::{
     DateTime,
+    Duration,
     Utc,
 };
+use futures::future::LocalBoxFuture;
 use gloo::utils::format::JsValueSerdeExt;
 use indexed_db_futures::{
     IdbDatabase,
+    IdbQuerySource,
     IdbVersionChangeEvent,
     request::{
         OpenDbRequest,
@@ -14,39 +20,203 @@ use indexed_db_futures::{
     idb_object_store::{
         IdbObjectStore,
     },
+    idb_transaction::IdbTransaction,
     IdbKeyPath,
 };
 use serde::{
+    de::DeserializeOwned,
     Serialize,
     Deserialize,
 };
 use wasm_bindgen::JsValue;
+use web_sys::IdbKeyRange;
 use crate::{
     util::{
+        bg,
         MyErrorDomException,
     },
-    world::{
+    noworlater::{
+        NowOrLaterKey,
+        NowOrLaterPersist,
+        NowOrLaterValue,
+    },
+    log,
+    world::FeedId,
+    interface::u2s::{
         ChannelId,
-        FeedId,
+        IdentityId,
         MessageId,
     },
 };
 
+#[cfg(test)]
+mod tests;
+
 pub const TABLE_OUTBOX: &'static str = "outbox";
 pub const TABLE_OUTBOX_INDEX_SENT: &'static str = "sent";
-pub const TABLE_OUTBOX_INDEX_STAMP: &'static str = "stamp";
+/// Version 1 only, replaced by `TABLE_OUTBOX_INDEX_CHANNEL_STAMP`
+const TABLE_OUTBOX_INDEX_STAMP: &'static str = "stamp";
+pub const TABLE_OUTBOX_INDEX_CHANNEL_STAMP: &'static str = "channel_stamp";
+/// Messages received from the server, keyed by `MessageId`
+pub const TABLE_MESSAGES: &'static str = "messages";
+/// Channel then time in microseconds
+pub const TABLE_MESSAGES_INDEX_CHANNEL_TIME: &'static str = "channel_time";
+/// Per channel, which message ids are all cached
+pub const TABLE_MESSAGE_RANGES: &'static str = "message_ranges";
+/// Saved `NowOrLaterCollection` values, one store per collection
+pub const TABLE_NOL_CHANNELS: &'static str = "nol_channels";
+pub const TABLE_NOL_BREWS: &'static str = "nol_brews";
+/// Database bookkeeping, key-value
+const TABLE_META: &'static str = "meta";
+/// The number of migrations whose data step has finished
+const META_DATA_VERSION: &'static str = "data_version";
 
-pub async fn new_db() -> Result<Rc<IdbDatabase>, String> {
-    let mut db_req: OpenDbRequest = IdbDatabase::open_u32("main", 1).context("Error opening database")?;
-    db_req.set_on_upgrade_needed(Some(|evt: &IdbVersionChangeEvent| -> Result<(), JsValue> {
-        if evt.db().object_store_names().find(|n| n == TABLE_OUTBOX).is_none() {
+/// How long sent messages stay in the outbox if they're never seen in a channel
+/// feed.
+pub const OUTBOX_RETENTION_DAYS: i64 = 1;
+
+/// Rewrites records for a new database version. The transaction covers every store
+/// and is committed along with recording that the migration is done.
+type DataMigration = for<'a> fn(&'a IdbTransaction<'a>) -> LocalBoxFuture<'a, Result<(), String>>;
+
+/// Upgrades the database from the previous version.
+struct Migration {
+    /// Creates and deletes stores and indexes. Runs during the upgrade, where requests
+    /// can't be waited on.
+    schema: fn(&IdbVersionChangeEvent) -> Result<(), JsValue>,
+    /// Runs once the database is open. Retried on the next open if interrupted.
+    data: Option<DataMigration>,
+}
+
+/// Database versions, applied in order - migration `i` upgrades to version `i + 1`.
+/// Never modify a migration once released, add a new one. Data steps are run for
+/// every version not recorded in `META_DATA_VERSION`, including those from before
+/// it existed, so they must be safe to run on already current records.
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        schema: |evt| {
             let outbox = evt.db().create_object_store(TABLE_OUTBOX)?;
             outbox.create_index(TABLE_OUTBOX_INDEX_STAMP, &IdbKeyPath::str("stamp"))?;
             outbox.create_index(TABLE_OUTBOX_INDEX_SENT, &IdbKeyPath::str("sent"))?;
+            return Ok(());
+        },
+        data: None,
+    },
+    Migration {
+        schema: |evt| {
+            let txn = evt.transaction();
+            let outbox = txn.object_store(TABLE_OUTBOX)?;
+            outbox.delete_index(TABLE_OUTBOX_INDEX_STAMP)?;
+            outbox.create_index(TABLE_OUTBOX_INDEX_CHANNEL_STAMP, &IdbKeyPath::str_sequence(&["channel", "stamp"]))?;
+            return Ok(());
+        },
+        // Version 1 records lack `channel`, have `sent` in the wrong order and hold
+        // `OutboxEntryV1`
+        data: Some(|txn| Box::pin(rewrite_outbox(txn))),
+    },
+    Migration {
+        schema: |evt| {
+            evt.db().create_object_store(TABLE_META)?;
+            return Ok(());
+        },
+        data: None,
+    },
+    Migration {
+        schema: |evt| {
+            let messages = evt.db().create_object_store(TABLE_MESSAGES)?;
+
+            // `time` is stored as microseconds so the index sorts by time
+            messages.create_index(
+                TABLE_MESSAGES_INDEX_CHANNEL_TIME,
+                &IdbKeyPath::str_sequence(&["channel", "time"]),
+            )?;
+            evt.db().create_object_store(TABLE_MESSAGE_RANGES)?;
+            return Ok(());
+        },
+        data: None,
+    },
+    Migration {
+        schema: |evt| {
+            evt.db().create_object_store(TABLE_NOL_CHANNELS)?;
+            evt.db().create_object_store(TABLE_NOL_BREWS)?;
+            return Ok(());
+        },
+        data: None,
+    },
+];
+
+pub async fn new_db() -> Result<Rc<IdbDatabase>, String> {
+    return Ok(Rc::new(open_db("main").await?));
+}
+
+/// Open the database, upgrading it to the current version.
+async fn open_db(name: &str) -> Result<IdbDatabase, String> {
+    let mut db_req: OpenDbRequest =
+        IdbDatabase::open_u32(name, MIGRATIONS.len() as u32).context("Error opening database")?;
+    db_req.set_on_upgrade_needed(Some(|evt: &IdbVersionChangeEvent| -> Result<(), JsValue> {
+        for m in MIGRATIONS.iter().skip(evt.old_version() as usize) {
+            (m.schema)(evt)?;
         }
         Ok(())
     }));
-    return Ok(Rc::new(db_req.await.context("Error waiting for database to open")?));
+    let db = db_req.await.context("Error waiting for database to open")?;
+
+    // Finish upgrading records
+    let data_version = {
+        let txn =
+            db
+                .transaction_on_one_with_mode(TABLE_META, web_sys::IdbTransactionMode::Readonly)
+                .context("Failed to start transaction")?;
+        let meta = txn.object_store(TABLE_META).context("Failed to get meta")?;
+        let version =
+            meta
+                .get_owned(META_DATA_VERSION)
+                .context("Failed to initiate data version lookup")?
+                .await
+                .context("Failed to look up data version")?
+                .and_then(|v| v.as_f64())
+                .unwrap_or(0.) as usize;
+        txn.await.into_result().context("Failed to commit transaction")?;
+        version
+    };
+    for (i, m) in MIGRATIONS.iter().enumerate().skip(data_version) {
+        let stores = db.object_store_names().collect::<Vec<_>>();
+        let txn =
+            db
+                .transaction_on_multi_with_mode(
+                    &stores.iter().map(|s| s.as_str()).collect::<Vec<_>>(),
+                    web_sys::IdbTransactionMode::Readwrite,
+                )
+                .context("Failed to start transaction")?;
+        if let Some(data) = m.data {
+            data(&txn).await.map_err(|e| format!("Error migrating data to version {}: {}", i + 1, e))?;
+        }
+        txn
+            .object_store(TABLE_META)
+            .context("Failed to get meta")?
+            .put_key_val_owned(META_DATA_VERSION, &JsValue::from((i + 1) as f64))
+            .context("Failed to initiate data version update")?
+            .await
+            .context("Failed to update data version")?;
+        txn.await.into_result().context("Failed to commit transaction")?;
+    }
+    return Ok(db);
+}
+
+/// Write every outbox record again as the current entry version, updating the
+/// indexed fields.
+async fn rewrite_outbox<'a>(txn: &'a IdbTransaction<'a>) -> Result<(), String> {
+    let outbox = txn.object_store(TABLE_OUTBOX).context("Failed to get outbox")?;
+    let all =
+        outbox
+            .get_all()
+            .context("Failed to start outbox listing")?
+            .await
+            .context("Failed to list outbox entries")?;
+    for e in all.iter() {
+        put_outbox(&outbox, OutboxEntry::V2(from_outbox(&e).upgrade())).await?;
+    }
+    return Ok(());
 }
 
 #[derive(Serialize, Deserialize)]
@@ -59,15 +229,59 @@ pub struct OutboxEntryV1 {
     pub resolved_id: Option<MessageId>,
 }
 
+impl OutboxEntryV1 {
+    fn upgrade(self) -> OutboxEntryV2 {
+        return OutboxEntryV2 {
+            stamp: self.stamp,
+            identity: None,
+            channel: self.channel,
+            reply: self.reply,
+            local_id: self.local_id,
+            body: self.body,
+            resolved_id: self.resolved_id,
+            error: None,
+        };
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct OutboxEntryV2 {
+    pub stamp: DateTime<Utc>,
+    /// None sends as the user's first identity
+    pub identity: Option<IdentityId>,
+    pub channel: ChannelId,
+    pub reply: Option<FeedId>,
+    pub local_id: String,
+    pub body: String,
+    pub resolved_id: Option<MessageId>,
+    /// Set if the server refused the message, it won't be retried automatically.
+    pub error: Option<String>,
+}
+
 #[derive(Serialize, Deserialize)]
 pub enum OutboxEntry {
     V1(OutboxEntryV1),
+    V2(OutboxEntryV2),
+}
+
+impl OutboxEntry {
+    /// The entry as the current version. Records are upgraded when the database is
+    /// opened, this only matters for entries read mid-upgrade.
+    pub fn upgrade(self) -> OutboxEntryV2 {
+        match self {
+            OutboxEntry::V1(e) => return e.upgrade(),
+            OutboxEntry::V2(e) => return e,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize)]
 struct OutboxEntryInner {
     entry: OutboxEntry,
     sent: Vec<String>,
+    /// Missing in records written before version 2
+    #[serde(default)]
+    channel: Option<ChannelId>,
     stamp: DateTime<Utc>,
 }
 
@@ -90,6 +304,9 @@ fn sent_key(sent: bool) -> &'static str {
     };
 }
 
+/// Entries that failed to send sort after sent entries.
+const SENT_KEY_FAILED: &'static str = "2";
+
 pub fn outbox_sent_key(local_id: &str, sent: bool) -> JsValue {
     return <JsValue as JsValueSerdeExt>::from_serde(&[sent_key(sent), local_id]).unwrap();
 }
@@ -98,20 +315,206 @@ pub fn outbox_key(local_id: &str) -> JsValue {
     return <JsValue as JsValueSerdeExt>::from_serde(local_id).unwrap();
 }
 
-pub async fn put_outbox<'a>(store: &IdbObjectStore<'a>, e: OutboxEntry) {
+pub async fn put_outbox<'a>(store: &IdbObjectStore<'a>, e: OutboxEntry) -> Result<(), String> {
     let local_id;
-    let resolved;
+    let sent;
+    let channel;
     let stamp;
     match &e {
         OutboxEntry::V1(e) => {
             local_id = e.local_id.clone();
-            resolved = e.resolved_id.is_some();
+            channel = e.channel.clone();
+            sent = sent_key(e.resolved_id.is_some());
+            stamp = e.stamp.clone();
+        },
+        OutboxEntry::V2(e) => {
+            local_id = e.local_id.clone();
+            channel = e.channel.clone();
+            sent = match (&e.resolved_id, &e.error) {
+                (Some(_), _) => sent_key(true),
+                (None, Some(_)) => SENT_KEY_FAILED,
+                (None, None) => sent_key(false),
+            };
             stamp = e.stamp.clone();
         },
     };
-    store.put_key_val(&outbox_key(&local_id), &<JsValue as JsValueSerdeExt>::from_serde(&OutboxEntryInner {
-        entry: e,
-        sent: vec![local_id, sent_key(resolved).to_string()],
-        stamp: stamp,
-    }).unwrap()).unwrap().await.unwrap();
+    store
+        .put_key_val(&outbox_key(&local_id), &<JsValue as JsValueSerdeExt>::from_serde(&OutboxEntryInner {
+            entry: e,
+            sent: vec![sent.to_string(), local_id],
+            channel: Some(channel),
+            stamp: stamp,
+        }).unwrap())
+        .context("Failed to initiate outbox entry write")?
+        .await
+        .context("Failed to write outbox entry")?;
+    return Ok(());
+}
+
+pub async fn get_outbox<'a>(store: &IdbObjectStore<'a>, local_id: &str) -> Result<Option<OutboxEntryV2>, String> {
+    return Ok(
+        store
+            .get(&outbox_key(local_id))
+            .context("Failed to initiate outbox lookup")?
+            .await
+            .context("Failed to look up outbox entry")?
+            .map(|e| from_outbox(&e).upgrade()),
+    );
+}
+
+/// Delete sent entries from the outbox once the real message has been `seen` in a
+/// channel feed, or once they're older than `OUTBOX_RETENTION_DAYS`. Entries that
+/// unsent messages reply to are kept since the reply needs their resolved id.
+pub async fn gc_outbox(db: &IdbDatabase, seen: &HashSet<MessageId>) -> Result<(), String> {
+    let txn =
+        db
+            .transaction_on_one_with_mode(TABLE_OUTBOX, web_sys::IdbTransactionMode::Readwrite)
+            .context("Failed to start transaction")?;
+    let outbox = txn.object_store(TABLE_OUTBOX).context("Failed to get outbox")?;
+    let all =
+        outbox
+            .get_all()
+            .context("Failed to start outbox listing")?
+            .await
+            .context("Failed to list outbox entries")?
+            .iter()
+            .map(|e| from_outbox(&e).upgrade())
+            .collect::<Vec<_>>();
+    let mut reply_targets = HashSet::new();
+    for e in &all {
+        if e.resolved_id.is_some() {
+            continue;
+        }
+        if let Some(FeedId::Local(_, id)) = &e.reply {
+            reply_targets.insert(id.clone());
+        }
+    }
+    let expire_before = Utc::now() - Duration::days(OUTBOX_RETENTION_DAYS);
+    for e in all {
+        let Some(resolved_id) = &e.resolved_id else {
+            continue;
+        };
+        if reply_targets.contains(&e.local_id) {
+            continue;
+        }
+        if !seen.contains(resolved_id) && e.stamp >= expire_before {
+            continue;
+        }
+        outbox
+            .delete(&outbox_key(&e.local_id))
+            .context("Failed to initiate outbox entry deletion")?
+            .await
+            .context("Failed to delete outbox entry")?;
+    }
+    txn.await.into_result().context("Failed to commit transaction")?;
+    return Ok(());
+}
+
+/// Key range for `TABLE_OUTBOX_INDEX_CHANNEL_STAMP` covering entries in `channel`
+/// before (`early` true) or after `stamp`, inclusive.
+pub fn outbox_channel_stamp_range(channel: &ChannelId, stamp: &DateTime<Utc>, early: bool) -> IdbKeyRange {
+    let pivot = <JsValue as JsValueSerdeExt>::from_serde(&(channel, stamp)).unwrap();
+    if early {
+        return IdbKeyRange::bound(&<JsValue as JsValueSerdeExt>::from_serde(&(channel,)).unwrap(), &pivot).unwrap();
+    } else {
+        // Arrays sort after strings
+        return IdbKeyRange::bound(
+            &pivot,
+            &<JsValue as JsValueSerdeExt>::from_serde(&(channel, Vec::<()>::new())).unwrap(),
+        ).unwrap();
+    }
+}
+
+/// Save `NowOrLaterCollection` values in `store`, keyed by the collection key.
+/// Values are stored as `P` since live values (with `Prim`s etc) can't be
+/// serialized directly.
+pub fn nol_persist<
+    K: NowOrLaterKey + Serialize + DeserializeOwned,
+    V: NowOrLaterValue,
+    P: Serialize + DeserializeOwned,
+>(
+    db: Rc<IdbDatabase>,
+    store: &'static str,
+    to_persisted: impl 'static + Fn(&V) -> P,
+    from_persisted: impl 'static + Fn(P) -> V,
+) -> NowOrLaterPersist<K, V> {
+    let from_persisted = Rc::new(from_persisted);
+    return NowOrLaterPersist {
+        load: Box::new({
+            let db = db.clone();
+            move || {
+                let db = db.clone();
+                let from_persisted = from_persisted.clone();
+                Box::pin(async move {
+                    let txn =
+                        db
+                            .transaction_on_one_with_mode(store, web_sys::IdbTransactionMode::Readonly)
+                            .context("Failed to start transaction")?;
+                    let all =
+                        txn
+                            .object_store(store)
+                            .context("Failed to get saved values")?
+                            .get_all()
+                            .context("Failed to start saved value listing")?
+                            .await
+                            .context("Failed to list saved values")?;
+                    txn.await.into_result().context("Failed to commit transaction")?;
+                    let mut out = vec![];
+                    for e in all.iter() {
+                        let (k, p) = match JsValueSerdeExt::into_serde::<(K, P)>(&e) {
+                            Ok(e) => e,
+                            Err(e) => {
+                                // Saved by an older version, will be refetched when needed
+                                log!("Skipping unreadable saved value in {}: {}", store, e);
+                                continue;
+                            },
+                        };
+                        out.push((k, from_persisted(p)));
+                    }
+                    return Ok(out);
+                })
+            }
+        }),
+        save: Box::new({
+            let db = db.clone();
+            move |k, v| {
+                let db = db.clone();
+                let key = <JsValue as JsValueSerdeExt>::from_serde(k).unwrap();
+                let value = <JsValue as JsValueSerdeExt>::from_serde(&(k, to_persisted(v))).unwrap();
+                bg("Saving value", async move {
+                    let txn =
+                        db
+                            .transaction_on_one_with_mode(store, web_sys::IdbTransactionMode::Readwrite)
+                            .context("Failed to start transaction")?;
+                    txn
+                        .object_store(store)
+                        .context("Failed to get saved values")?
+                        .put_key_val(&key, &value)
+                        .context("Failed to initiate saved value write")?
+                        .await
+                        .context("Failed to write saved value")?;
+                    txn.await.into_result().context("Failed to commit transaction")?;
+                    return Ok(());
+                });
+            }
+        }),
+        clear: Box::new(move || {
+            let db = db.clone();
+            bg("Clearing saved values", async move {
+                let txn =
+                    db
+                        .transaction_on_one_with_mode(store, web_sys::IdbTransactionMode::Readwrite)
+                        .context("Failed to start transaction")?;
+                txn
+                    .object_store(store)
+                    .context("Failed to get saved values")?
+                    .clear()
+                    .context("Failed to initiate saved value clear")?
+                    .await
+                    .context("Failed to clear saved values")?;
+                txn.await.into_result().context("Failed to commit transaction")?;
+                return Ok(());
+            });
+        }),
+    };
 }
@@ -0,0 +1,233 @@
+//! Upgrades from older database versions. These need IndexedDB, run them in a
+//! browser with `wasm-pack test --headless --firefox`.
+use chrono::{
+    DateTime,
+    TimeZone,
+    Utc,
+};
+use gloo::utils::format::JsValueSerdeExt;
+use indexed_db_futures::{
+    IdbDatabase,
+    IdbQuerySource,
+    IdbVersionChangeEvent,
+    request::{
+        IdbOpenDbRequestLike,
+        OpenDbRequest,
+    },
+};
+use serde_json::json;
+use wasm_bindgen::JsValue;
+use wasm_bindgen_test::{
+    wasm_bindgen_test,
+    wasm_bindgen_test_configure,
+};
+use web_sys::{
+    IdbKeyRange,
+    IdbTransactionMode,
+};
+use crate::interface::u2s::{
+    ChannelId,
+    IdentityId,
+    MessageId,
+};
+use super::{
+    get_outbox,
+    open_db,
+    outbox_channel_stamp_range,
+    outbox_key,
+    outbox_sent_partial_key_sent,
+    outbox_sent_partial_key_unsent,
+    put_outbox,
+    OutboxEntry,
+    OutboxEntryV1,
+    OutboxEntryV2,
+    META_DATA_VERSION,
+    MIGRATIONS,
+    TABLE_META,
+    TABLE_MESSAGES,
+    TABLE_MESSAGE_RANGES,
+    TABLE_NOL_BREWS,
+    TABLE_NOL_CHANNELS,
+    TABLE_OUTBOX,
+    TABLE_OUTBOX_INDEX_CHANNEL_STAMP,
+    TABLE_OUTBOX_INDEX_SENT,
+};
+
+wasm_bindgen_test_configure!(run_in_browser);
+
+fn channel() -> ChannelId {
+    return ChannelId(IdentityId("owner".to_string()), 1);
+}
+
+/// Seconds after an arbitrary start time.
+fn t(seconds: i64) -> DateTime<Utc> {
+    return Utc.timestamp_opt(1_700_000_000 + seconds, 0).unwrap();
+}
+
+fn v1(local_id: &str, stamp: DateTime<Utc>, resolved_id: Option<MessageId>) -> OutboxEntryV1 {
+    return OutboxEntryV1 {
+        stamp: stamp,
+        channel: channel(),
+        reply: None,
+        local_id: local_id.to_string(),
+        body: format!("body {}", local_id),
+        resolved_id: resolved_id,
+    };
+}
+
+/// An outbox record as version 1 wrote it: no `channel`, `sent` reversed.
+fn record_v1(e: OutboxEntryV1) -> serde_json::Value {
+    let sent = if e.resolved_id.is_some() {
+        "1"
+    } else {
+        "0"
+    };
+    return json!({
+        "sent": [e.local_id.clone(), sent],
+        "stamp": e.stamp,
+        "entry": OutboxEntry::V1(e),
+    });
+}
+
+/// Create a database at an old `version`, with `records` written to the outbox as
+/// is. Returns the database name.
+async fn old_db(version: usize, records: Vec<(&str, serde_json::Value)>) -> String {
+    let name = format!("test-{}", js_sys::Math::random());
+    let mut db_req: OpenDbRequest = IdbDatabase::open_u32(&name, version as u32).unwrap();
+    db_req.set_on_upgrade_needed(Some(move |evt: &IdbVersionChangeEvent| -> Result<(), JsValue> {
+        for m in &MIGRATIONS[..version] {
+            (m.schema)(evt)?;
+        }
+        Ok(())
+    }));
+    let db = db_req.await.unwrap();
+    let txn = db.transaction_on_one_with_mode(TABLE_OUTBOX, IdbTransactionMode::Readwrite).unwrap();
+    let outbox = txn.object_store(TABLE_OUTBOX).unwrap();
+    for (local_id, record) in records {
+        outbox
+            .put_key_val(&outbox_key(local_id), &<JsValue as JsValueSerdeExt>::from_serde(&record).unwrap())
+            .unwrap()
+            .await
+            .unwrap();
+    }
+    txn.await.into_result().unwrap();
+    db.close();
+    return name;
+}
+
+async fn delete_db(db: IdbDatabase) {
+    db.delete().unwrap().await.unwrap();
+}
+
+/// The record as stored.
+async fn raw_outbox(db: &IdbDatabase, local_id: &str) -> serde_json::Value {
+    let txn = db.transaction_on_one_with_mode(TABLE_OUTBOX, IdbTransactionMode::Readonly).unwrap();
+    let out = txn.object_store(TABLE_OUTBOX).unwrap().get(&outbox_key(local_id)).unwrap().await.unwrap().unwrap();
+    txn.await.into_result().unwrap();
+    return JsValueSerdeExt::into_serde(&out).unwrap();
+}
+
+async fn read_outbox(db: &IdbDatabase, local_id: &str) -> OutboxEntryV2 {
+    let txn = db.transaction_on_one_with_mode(TABLE_OUTBOX, IdbTransactionMode::Readonly).unwrap();
+    let out = get_outbox(&txn.object_store(TABLE_OUTBOX).unwrap(), local_id).await.unwrap().unwrap();
+    txn.await.into_result().unwrap();
+    return out;
+}
+
+/// Local ids found through an outbox index.
+async fn outbox_index(db: &IdbDatabase, index: &str, range: &IdbKeyRange) -> Vec<String> {
+    let txn = db.transaction_on_one_with_mode(TABLE_OUTBOX, IdbTransactionMode::Readonly).unwrap();
+    let found =
+        txn.object_store(TABLE_OUTBOX).unwrap().index(index).unwrap().get_all_with_key(range).unwrap().await.unwrap();
+    txn.await.into_result().unwrap();
+    return found
+        .iter()
+        .map(|e| JsValueSerdeExt::into_serde::<serde_json::Value>(&e).unwrap()["entry"]["V2"]["local_id"].clone())
+        .map(|id| id.as_str().unwrap().to_string())
+        .collect();
+}
+
+async fn data_version(db: &IdbDatabase) -> Option<f64> {
+    let txn = db.transaction_on_one_with_mode(TABLE_META, IdbTransactionMode::Readonly).unwrap();
+    let out = txn.object_store(TABLE_META).unwrap().get_owned(META_DATA_VERSION).unwrap().await.unwrap();
+    txn.await.into_result().unwrap();
+    return out.and_then(|v| v.as_f64());
+}
+
+fn unsent_range() -> IdbKeyRange {
+    return IdbKeyRange::bound_with_lower_open_and_upper_open(
+        &outbox_sent_partial_key_unsent(),
+        &outbox_sent_partial_key_sent(),
+        false,
+        true,
+    ).unwrap();
+}
+
+/// Check a database upgraded from `record_v1` entries "a" (unsent,
+/// stamp 10) and "b" (sent, stamp 20).
+async fn check_upgraded(db: &IdbDatabase) {
+    assert_eq!(db.version(), MIGRATIONS.len() as f64);
+    assert_eq!(data_version(db).await, Some(MIGRATIONS.len() as f64));
+    let stores = db.object_store_names().collect::<Vec<_>>();
+    for store in [TABLE_OUTBOX, TABLE_META, TABLE_MESSAGES, TABLE_MESSAGE_RANGES, TABLE_NOL_CHANNELS, TABLE_NOL_BREWS] {
+        assert!(stores.iter().any(|s| s == store), "Missing store {}", store);
+    }
+
+    // Lifted to the current entry version, with the indexed fields rewritten
+    let a = raw_outbox(db, "a").await;
+    assert_eq!(a["sent"], json!(["0", "a"]));
+    assert_eq!(a["channel"], json!(channel()));
+    assert_eq!(a["entry"]["V2"]["body"], json!("body a"));
+    assert_eq!(a["entry"]["V2"]["identity"], json!(null));
+    assert_eq!(a["entry"]["V2"]["error"], json!(null));
+    let b = read_outbox(db, "b").await;
+    assert_eq!(b.resolved_id, Some(MessageId(channel(), 5)));
+    assert_eq!(b.stamp, t(20));
+    assert_eq!(outbox_index(db, TABLE_OUTBOX_INDEX_SENT, &unsent_range()).await, vec!["a".to_string()]);
+    assert_eq!(
+        outbox_index(db, TABLE_OUTBOX_INDEX_CHANNEL_STAMP, &outbox_channel_stamp_range(&channel(), &t(0), false)).await,
+        vec!["a".to_string(), "b".to_string()]
+    );
+}
+
+#[wasm_bindgen_test]
+async fn upgrade_from_version_1() {
+    let name =
+        old_db(
+            1,
+            vec![
+                ("a", record_v1(v1("a", t(10), None))),
+                ("b", record_v1(v1("b", t(20), Some(MessageId(channel(), 5))))),
+            ],
+        ).await;
+    let db = open_db(&name).await.unwrap();
+    check_upgraded(&db).await;
+    delete_db(db).await;
+}
+
+#[wasm_bindgen_test]
+async fn reopen_keeps_current_records() {
+    let name = old_db(MIGRATIONS.len(), vec![]).await;
+    let db = open_db(&name).await.unwrap();
+    let txn = db.transaction_on_one_with_mode(TABLE_OUTBOX, IdbTransactionMode::Readwrite).unwrap();
+    put_outbox(&txn.object_store(TABLE_OUTBOX).unwrap(), OutboxEntry::V2(OutboxEntryV2 {
+        stamp: t(10),
+        identity: Some(IdentityId("alt".to_string())),
+        channel: channel(),
+        reply: None,
+        local_id: "a".to_string(),
+        body: "body a".to_string(),
+        resolved_id: None,
+        error: Some("Refused".to_string()),
+    })).await.unwrap();
+    txn.await.into_result().unwrap();
+    db.close();
+    let db = open_db(&name).await.unwrap();
+    assert_eq!(data_version(&db).await, Some(MIGRATIONS.len() as f64));
+    let a = read_outbox(&db, "a").await;
+    assert_eq!(a.identity, Some(IdentityId("alt".to_string())));
+    assert_eq!(a.error.as_deref(), Some("Refused"));
+    assert_eq!(raw_outbox(&db, "a").await["sent"], json!(["2", "a"]));
+    assert!(outbox_index(&db, TABLE_OUTBOX_INDEX_SENT, &unsent_range()).await.is_empty());
+    delete_db(db).await;
+}
@@ -230,15 +230,62 @@ pub fn nol_span<
                 let out = out.weak();
                 let eg = pc.eg();
                 async move {
+                    let Ok(v) = r.await else {
+                        return;
+                    };
                     let Some(out) = out.upgrade() else {
                         return;
                     };
+                    match v {
+                        Ok(v) => {
+                            eg.event(|pc| {
+                                out.ref_bind_text(pc, &f(&*v));
+                            });
+                        },
+                        Err(e) => {
+                            out.ref_text("?");
+                            out.ref_classes(&["error"]);
+                            out.ref_attr("title", &e);
+                        },
+                    }
+                }
+            })
+        },
+    }
+    return out;
+}
+
+/// Like `nol_span` but for arbitrary content, built once the value is available.
+pub fn nol_el<
+    K: NowOrLaterKey,
+    V: NowOrLaterValue,
+>(pc: &mut ProcessingContext, nol: NowOrLater<K, V>, f: impl 'static + FnOnce(&mut ProcessingContext, &V) -> El) -> El {
+    let out = group();
+    match nol {
+        NowOrLater::Now(v) => {
+            out.ref_push(f(pc, &*v));
+        },
+        NowOrLater::Later(r) => {
+            spawn_local({
+                let out = out.weak();
+                let eg = pc.eg();
+                async move {
                     let Ok(v) = r.await else {
                         return;
                     };
-                    eg.event(|pc| {
-                        out.ref_bind_text(pc, &f(&*v));
-                    });
+                    let Some(out) = out.upgrade() else {
+                        return;
+                    };
+                    match v {
+                        Ok(v) => {
+                            eg.event(|pc| {
+                                out.ref_push(f(pc, &*v));
+                            });
+                        },
+                        Err(e) => {
+                            out.ref_push(el("span").classes(&["error"]).text("?").attr("title", &e));
+                        },
+                    }
                 }
             })
         },
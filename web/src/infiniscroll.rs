@@ -133,9 +133,16 @@ use crate::{
 const PX_PER_CM: f64 = 96. / 2.54;
 const BUFFER: f64 = PX_PER_CM * 40.;
 const CSS_HIDE: &'static str = "hide";
+const CSS_GROUPED: &'static str = "grouped";
 pub const REQUEST_COUNT: usize = 50;
 const MIN_RESERVE: usize = 50;
 const MAX_RESERVE: usize = MIN_RESERVE + 2 * REQUEST_COUNT;
+/// How long the late end must stay in view before it's reported as seen.
+const LATE_END_SEEN_MS: u32 = 1500;
+
+/// Called with the latest entry of each feed once the late end of the infiniscroll
+/// has been in view for a moment.
+pub type LateEndSeenCb<FeedId, Time> = Rc<dyn Fn(Vec<(FeedId, Time)>)>;
 
 trait ElExt {
     fn offset_top(&self) -> f64;
@@ -164,6 +171,13 @@ impl<T: 'static + Clone + std::fmt::Debug + PartialEq + Eq + PartialOrd + Hash>
 pub trait Entry<Id> {
     fn create_el(&self, pc: &mut ProcessingContext) -> El;
     fn time(&self) -> Id;
+
+    /// Adjacent realized entries with the same key are displayed as a group (ex:
+    /// consecutive messages by the same author). Entries continuing a group get the
+    /// `grouped` class.
+    fn group_key(&self) -> Option<String> {
+        return None;
+    }
 }
 
 struct EntryState<FeedId, Id> {
@@ -273,6 +287,10 @@ struct Infiniscroll_<FeedId, Time: Clone + Hash + PartialEq> {
     // After human-volitional scrolling, more scrolling may soon come so push back
     // shake for this number of ms.
     delay_shake: u32,
+    late_end_seen_cb: Option<LateEndSeenCb<FeedId, Time>>,
+    late_end_seen_timer: Option<Timeout>,
+    /// Last entries passed to `late_end_seen_cb`, to avoid repeats
+    late_end_seen: Vec<(FeedId, Time)>,
 }
 
 fn calc_anchor_offset(real_origin_y: f64, anchor_top: f64, anchor_height: f64, anchor_alignment: f64) -> f64 {
@@ -290,6 +308,60 @@ fn calc_anchor_offset(real_origin_y: f64, anchor_top: f64, anchor_height: f64, a
     return anchor_offset;
 }
 
+impl<FeedId: FeedIdTraits, Time: TimeTraits> Infiniscroll_<FeedId, Time> {
+    /// The latest entry of every feed is realized and the view is aligned to it.
+    fn at_late_end(&self) -> bool {
+        return !self.real.is_empty() && self.anchor_alignment == 1. &&
+            self.feeds.values().all(|f| f.late_stop && f.late_reserve.is_empty());
+    }
+
+    /// Latest realized entry of each feed.
+    fn latest_real(&self) -> Vec<(FeedId, Time)> {
+        let mut out: Vec<(FeedId, Time)> = vec![];
+        for e_state in self.real.iter().rev() {
+            if out.iter().any(|(feed_id, _)| feed_id == &e_state.feed_id) {
+                continue;
+            }
+            out.push((e_state.feed_id.clone(), e_state.entry.time()));
+        }
+        return out;
+    }
+
+    /// Start the seen timer if the late end came into view, or cancel it if it left.
+    fn update_late_end_seen(&mut self, weak: WeakInfiniscroll<FeedId, Time>) {
+        if self.late_end_seen_cb.is_none() {
+            return;
+        }
+        if !self.at_late_end() {
+            self.late_end_seen_timer = None;
+            return;
+        }
+        if self.late_end_seen_timer.is_some() || self.latest_real() == self.late_end_seen {
+            return;
+        }
+        self.late_end_seen_timer = Some(Timeout::new(LATE_END_SEEN_MS, move || {
+            let Some(state) = weak.upgrade() else {
+                return;
+            };
+            let cb;
+            let latest;
+            {
+                let mut self1 = state.0.borrow_mut();
+                self1.late_end_seen_timer = None;
+
+                // Not seen if the page is in the background
+                if !self1.at_late_end() || gloo::utils::document().hidden() {
+                    return;
+                }
+                latest = self1.latest_real();
+                self1.late_end_seen = latest.clone();
+                cb = self1.late_end_seen_cb.clone().unwrap();
+            }
+            cb(latest);
+        }));
+    }
+}
+
 impl<FeedId, Time: TimeTraits> Infiniscroll_<FeedId, Time> {
     fn reanchor_inner(&mut self, mut anchor_i: usize, real_origin_y: f64) {
         // Move anchor pointer down until directly after desired element
@@ -572,6 +644,9 @@ impl<FeedIdT: FeedIdTraits, TimeT: TimeTraits + 'static> Infiniscroll<FeedIdT, T
             entry_resize_observer: None,
             mute_scroll: Utc::now() + Duration::milliseconds(300),
             delay_shake: 0,
+            late_end_seen_cb: None,
+            late_end_seen_timer: None,
+            late_end_seen: vec![],
         })));
         let entry_resize_observer = Some(ResizeObserver::new({
             let state = state.weak();
@@ -758,6 +833,19 @@ impl<FeedIdT: FeedIdTraits, TimeT: TimeTraits + 'static> Infiniscroll<FeedIdT, T
         }
     }
 
+    /// The entry the view is anchored to, for restoring the scroll position later by
+    /// passing it as the reset time.
+    pub fn anchor(&self) -> Option<TimeT> {
+        let self1 = self.0.borrow();
+        return self1.anchor_i.and_then(|i| self1.real.get(i)).map(|e| e.entry.time());
+    }
+
+    /// Set a callback for when the latest entries have been in view for a moment.
+    pub fn set_on_late_end_seen(&self, cb: impl 'static + Fn(Vec<(FeedIdT, TimeT)>)) {
+        self.0.borrow_mut().late_end_seen_cb = Some(Rc::new(cb));
+        self.shake();
+    }
+
     pub fn clear_sticky(&self) {
         let mut changed = false;
         {
@@ -776,6 +864,7 @@ impl<FeedIdT: FeedIdTraits, TimeT: TimeTraits + 'static> Infiniscroll<FeedIdT, T
 
     fn shake_immediate(&self) {
         logd!("shake immediate ------------");
+        let weak = self.weak();
         let mut self1 = self.0.borrow_mut();
         let self1 = &mut *self1;
         let eg = self1.eg.clone();
@@ -989,6 +1078,14 @@ impl<FeedIdT: FeedIdTraits, TimeT: TimeTraits + 'static> Infiniscroll<FeedIdT, T
                     self1.reserve_sticky_entry = Some(evicted_e_state);
                 }
             }
+
+            // ### Group styling
+            let mut prev_group = None;
+            for e_state in &self1.real {
+                let group = e_state.entry.group_key();
+                e_state.entry_el.ref_modify_classes(&[(CSS_GROUPED, group.is_some() && group == prev_group)]);
+                prev_group = group;
+            }
             if let Some(anchor_i) = &self1.anchor_i {
                 let anchor = self1.real.get(*anchor_i).unwrap();
                 logn!(
@@ -1145,6 +1242,7 @@ impl<FeedIdT: FeedIdTraits, TimeT: TimeTraits + 'static> Infiniscroll<FeedIdT, T
             );
             self1.frame.raw().set_scroll_top(self1.logical_scroll_top.round() as i32);
             self1.mute_scroll = Utc::now() + Duration::milliseconds(50);
+            self1.update_late_end_seen(weak);
             logd!("shake immediate ------------ done");
         });
     }
@@ -1514,4 +1612,158 @@ impl<FeedIdT: FeedIdTraits, TimeT: TimeTraits + 'static> Infiniscroll<FeedIdT, T
         }
         return Some((get_pivot_late(&self1.real, &feed_id, f_state).unwrap(), REQUEST_COUNT));
     }
+
+    /// Called by feed when it learns of entries it didn't respond with, ex: when
+    /// checking a response from a cache against the server. Entries between the feed's
+    /// earliest and latest entries are sorted into the view and reserves, entries past
+    /// them are left for the next request.
+    ///
+    /// * `entries` must not include entries already responded with.
+    ///
+    /// * `early_stop`/`late_stop`, if given, replace the feed's stop status. Ignored
+    ///   for an end if entries past it were left out.
+    pub fn add_entries(
+        &self,
+        feed_id: &FeedIdT,
+        entries: Vec<Rc<dyn Entry<TimeT>>>,
+        mut early_stop: Option<bool>,
+        mut late_stop: Option<bool>,
+    ) {
+        {
+            let mut self1 = self.0.borrow_mut();
+            let self1 = &mut *self1;
+            let f_state = self1.feeds.get(feed_id).unwrap();
+            if f_state.initial {
+                return;
+            }
+            let (Some(earliest), Some(latest)) =
+                (get_pivot_early(&self1.real, feed_id, f_state), get_pivot_late(&self1.real, feed_id, f_state)) else {
+                    return;
+                };
+            let eg = self1.eg.clone();
+            eg.event(|pc| {
+                let f_state = self1.feeds.get_mut(feed_id).unwrap();
+                for entry in entries {
+                    let entry_time = entry.time();
+                    if entry_time < earliest {
+                        logd!("add entries, left out early {:?}", entry_time);
+                        early_stop = None;
+                        f_state.early_stop = false;
+                        continue;
+                    }
+                    if entry_time > latest {
+                        logd!("add entries, left out late {:?}", entry_time);
+                        late_stop = None;
+                        f_state.late_stop = false;
+                        continue;
+                    }
+                    let real_range = match (self1.real.first(), self1.real.last()) {
+                        (Some(first), Some(last)) => Some((first.entry.time(), last.entry.time())),
+                        _ => None,
+                    };
+                    let early_reserve_latest = f_state.early_reserve.front().map(|e| e.time());
+                    let to_early = match &real_range {
+                        Some((real_earliest, _)) => entry_time < *real_earliest,
+                        None => early_reserve_latest.map(|t| entry_time < t).unwrap_or(false),
+                    };
+                    let to_late = match &real_range {
+                        Some((_, real_latest)) => entry_time > *real_latest,
+                        None => !to_early,
+                    };
+                    if to_early {
+                        logd!("add entries, sort into early reserve {:?}", entry_time);
+                        if self1.want_sticky.iter().any(|s| s == &entry_time) {
+                            let real =
+                                realize_entry(
+                                    pc,
+                                    self1.entry_resize_observer.as_ref().unwrap(),
+                                    feed_id,
+                                    entry.clone(),
+                                );
+                            self1.early_sticky.ref_push(real.el().clone());
+                            self1.reserve_sticky_entry = Some(real);
+                        }
+                        let i =
+                            f_state
+                                .early_reserve
+                                .iter()
+                                .position(|e| e.time() < entry_time)
+                                .unwrap_or(f_state.early_reserve.len());
+                        f_state.early_reserve.insert(i, entry);
+                    } else if to_late {
+                        logd!("add entries, sort into late reserve {:?}", entry_time);
+                        if self1.want_sticky.iter().any(|s| s == &entry_time) {
+                            let real =
+                                realize_entry(
+                                    pc,
+                                    self1.entry_resize_observer.as_ref().unwrap(),
+                                    feed_id,
+                                    entry.clone(),
+                                );
+                            self1.late_sticky.ref_push(real.el().clone());
+                            self1.reserve_sticky_entry = Some(real);
+                        }
+                        let i =
+                            f_state
+                                .late_reserve
+                                .iter()
+                                .position(|e| e.time() > entry_time)
+                                .unwrap_or(f_state.late_reserve.len());
+                        f_state.late_reserve.insert(i, entry);
+                    } else {
+                        logd!("add entries, sort into real {:?}", entry_time);
+                        let insert_before_i = self1.real.iter().position(|e| e.entry.time() > entry_time).unwrap();
+                        let real = realize_entry(pc, self1.entry_resize_observer.as_ref().unwrap(), feed_id, entry);
+                        let anchor_i = self1.anchor_i.unwrap();
+                        if insert_before_i <= anchor_i {
+                            self1.anchor_i = Some(anchor_i + 1);
+                        }
+                        self1.real.insert(insert_before_i, real);
+                    }
+                }
+                if let Some(stop) = early_stop {
+                    f_state.early_stop = stop;
+                }
+                if let Some(stop) = late_stop {
+                    f_state.late_stop = stop;
+                }
+            });
+        }
+        self.shake();
+    }
+
+    /// Called by feed when an entry no longer exists (ex: deleted). Removes it from
+    /// the reserves and view if present.
+    pub fn remove_entry(&self, feed_id: &FeedIdT, time: &TimeT) {
+        {
+            let mut self1 = self.0.borrow_mut();
+            let self1 = &mut *self1;
+            let f_state = self1.feeds.get_mut(feed_id).unwrap();
+            f_state.early_reserve.retain(|e| &e.time() != time);
+            f_state.late_reserve.retain(|e| &e.time() != time);
+            if let Some(i) = self1.real.iter().position(|e| &e.feed_id == feed_id && &e.entry.time() == time) {
+                self1.real.remove(i);
+                let anchor_i = self1.anchor_i.unwrap();
+                if self1.real.is_empty() {
+                    self1.anchor_i = None;
+                } else if i < anchor_i {
+                    self1.anchor_i = Some(anchor_i - 1);
+                } else if i == anchor_i {
+                    self1.anchor_i = Some(anchor_i.min(self1.real.len() - 1));
+                    self1.anchor_offset = 0.;
+                }
+            }
+            if self1.reserve_sticky_entry.as_ref().map(|e| &e.entry.time() == time).unwrap_or(false) {
+                self1.reserve_sticky_entry.take().unwrap().entry_el.ref_replace(vec![]);
+            }
+
+            // Nothing left to pivot from, start over
+            if get_pivot_early(&self1.real, feed_id, f_state).is_none() {
+                f_state.initial = true;
+                f_state.earliest_known = None;
+                f_state.latest_known = None;
+            }
+        }
+        self.shake();
+    }
 }
@@ -0,0 +1,391 @@
+//! Wire types shared by the client (`web`) and the server (`webserver`). The server
+//! includes this module by path, so it must only depend on `serde` and `chrono`.
+use chrono::{
+    DateTime,
+    Utc,
+};
+use serde::{
+    de::DeserializeOwned,
+    Serialize,
+    Deserialize,
+};
+
+#[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize, PartialOrd, Ord, Hash)]
+pub struct IdentityId(pub String);
+
+#[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize, PartialOrd, Ord, Hash)]
+pub struct ChannelId(pub IdentityId, pub u16);
+
+#[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize, PartialOrd, Ord, Hash)]
+pub struct MessageId(pub ChannelId, pub u64);
+
+#[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize, PartialOrd, Ord, Hash)]
+pub struct DateMessageId(pub DateTime<Utc>, pub MessageId);
+
+#[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize, PartialOrd, Ord, Hash)]
+pub struct BrewId(pub usize);
+
+#[derive(Serialize, Deserialize)]
+pub struct S2SWPush {
+    pub id: MessageId,
+    pub time: DateTime<Utc>,
+    pub title: String,
+    pub quote: String,
+    pub icon_url: String,
+}
+
+/// A request to the server, paired with the type of its successful response.
+pub trait U2SReq {
+    /// `Send` as required by the server's json responses
+    type Resp: Serialize + DeserializeOwned + Send;
+
+    fn into_any(self) -> U2SAny;
+}
+
+/// Any request, by how it's sent.
+pub enum U2SAny {
+    Get(U2SGet),
+    Post(U2SPost),
+}
+
+/// Declares the wire enum for a set of requests, with a variant per request, and
+/// the requests' `U2SReq` implementations.
+macro_rules! u2s_requests{
+    ($enum: ident, $any: ident, {
+        $($variant: ident($req: ident) => $resp: ty,) *
+    }) => {
+        #[derive(Serialize, Deserialize)]
+        pub enum $enum {
+            $($variant($req),) *
+        }
+
+        $(
+            impl U2SReq for $req {
+                type Resp = $resp;
+
+                fn into_any(self) -> U2SAny {
+                    return U2SAny::$any($enum::$variant(self));
+                }
+            }
+        ) *
+    };
+}
+
+/// The push subscription, as json
+#[derive(Serialize, Deserialize)]
+pub struct U2SSubscribePush(pub String);
+
+#[derive(Serialize, Deserialize)]
+pub struct U2SAuth {
+    pub username: String,
+    pub password: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct U2SLogout;
+
+#[derive(Serialize, Deserialize)]
+pub struct U2SIdentityCreate {
+    pub name: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct U2SIdentityRename {
+    pub id: IdentityId,
+    pub name: String,
+}
+
+/// The channel is owned by the identity, which becomes its first member.
+#[derive(Serialize, Deserialize)]
+pub struct U2SChannelCreate {
+    /// One of `GetOwnIdentities`. Defaults to the first.
+    pub identity: Option<IdentityId>,
+    pub name: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct U2SChannelJoin {
+    /// One of `GetOwnIdentities`. Defaults to the first.
+    pub identity: Option<IdentityId>,
+    pub id: ChannelId,
+}
+
+/// Leave the channel with all of your identities.
+#[derive(Serialize, Deserialize)]
+pub struct U2SChannelLeave(pub ChannelId);
+
+/// Requires a member identity in the channel.
+#[derive(Serialize, Deserialize)]
+pub struct U2SChannelRename {
+    pub id: ChannelId,
+    pub name: String,
+}
+
+/// Brews are private to the user that creates them. Every channel needs one of your
+/// identities as a member.
+#[derive(Serialize, Deserialize)]
+pub struct U2SBrewCreate {
+    pub name: String,
+    pub channels: Vec<ChannelId>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct U2SBrewRename {
+    pub id: BrewId,
+    pub name: String,
+}
+
+/// Replace the brew's channels, in display order. Every channel needs one of your
+/// identities as a member.
+#[derive(Serialize, Deserialize)]
+pub struct U2SBrewSetChannels {
+    pub id: BrewId,
+    pub channels: Vec<ChannelId>,
+}
+
+/// Replace the brew's name and channels together, channels in display order. Every
+/// channel needs one of your identities as a member.
+#[derive(Serialize, Deserialize)]
+pub struct U2SBrewUpdate {
+    pub id: BrewId,
+    pub name: String,
+    pub channels: Vec<ChannelId>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct U2SBrewDelete(pub BrewId);
+
+#[derive(Serialize, Deserialize)]
+pub struct U2SSend {
+    /// The identity to send as, one of `GetOwnIdentities`. Defaults to the first. The
+    /// identity must be a member of the channel.
+    pub identity: Option<IdentityId>,
+    pub channel: ChannelId,
+    pub reply: Option<MessageId>,
+    pub local_id: String,
+    pub body: String,
+}
+
+/// Mark the channel read up to and including the message. Read positions only move
+/// forward.
+#[derive(Serialize, Deserialize)]
+pub struct U2SSetReadPosition(pub MessageId);
+
+#[derive(Serialize, Deserialize)]
+pub struct U2SEdit {
+    pub id: MessageId,
+    pub body: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct U2SDelete(pub MessageId);
+
+u2s_requests!(U2SPost, Post, {
+    SubscribePush(U2SSubscribePush) => (),
+    Auth(U2SAuth) => (),
+    Logout(U2SLogout) => (),
+    IdentityCreate(U2SIdentityCreate) => S2UIdentity,
+    IdentityRename(U2SIdentityRename) => (),
+    ChannelCreate(U2SChannelCreate) => ChannelId,
+    ChannelJoin(U2SChannelJoin) => ChannelId,
+    ChannelLeave(U2SChannelLeave) => (),
+    ChannelRename(U2SChannelRename) => (),
+    BrewCreate(U2SBrewCreate) => BrewId,
+    BrewRename(U2SBrewRename) => (),
+    BrewSetChannels(U2SBrewSetChannels) => (),
+    BrewUpdate(U2SBrewUpdate) => (),
+    BrewDelete(U2SBrewDelete) => (),
+    Send(U2SSend) => MessageId,
+    SetReadPosition(U2SSetReadPosition) => (),
+    Edit(U2SEdit) => (),
+    Delete(U2SDelete) => (),
+});
+
+#[derive(Serialize, Deserialize)]
+pub struct U2SGetPushPubKey;
+
+#[derive(Serialize, Deserialize)]
+pub struct U2SGetBrew(pub BrewId);
+
+#[derive(Serialize, Deserialize)]
+pub struct U2SGetChannel(pub ChannelId);
+
+#[derive(Serialize, Deserialize)]
+pub struct U2SGetIdentity(pub IdentityId);
+
+/// Results are in request order, `None` for unknown brews or other users' brews
+#[derive(Serialize, Deserialize)]
+pub struct U2SGetBrewsById(pub Vec<BrewId>);
+
+/// Results are in request order, `None` for unknown channels or channels you have no
+/// member identity in
+#[derive(Serialize, Deserialize)]
+pub struct U2SGetChannelsById(pub Vec<ChannelId>);
+
+/// Results are in request order, `None` for unknown identities
+#[derive(Serialize, Deserialize)]
+pub struct U2SGetIdentitiesById(pub Vec<IdentityId>);
+
+/// Channels you have a member identity in
+#[derive(Serialize, Deserialize)]
+pub struct U2SGetChannels;
+
+/// Most recently active first, only channels you have a member identity in
+#[derive(Serialize, Deserialize)]
+pub struct U2SGetChannelSummaries;
+
+/// The last read message in the channel
+#[derive(Serialize, Deserialize)]
+pub struct U2SGetReadPosition(pub ChannelId);
+
+/// Your brews
+#[derive(Serialize, Deserialize)]
+pub struct U2SGetBrews;
+
+/// The identities you can act as, in creation order. A first identity is created if
+/// you have none.
+#[derive(Serialize, Deserialize)]
+pub struct U2SGetOwnIdentities;
+
+#[derive(Serialize, Deserialize)]
+pub struct U2SEventsGetAfter {
+    pub id: MessageId,
+    pub count: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct U2SSnapGetAround {
+    pub channel: ChannelId,
+    pub time: DateTime<Utc>,
+    pub count: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct U2SSnapGetBefore {
+    pub id: MessageId,
+    pub count: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct U2SSnapGetAfter {
+    pub id: MessageId,
+    pub count: u64,
+}
+
+u2s_requests!(U2SGet, Get, {
+    GetPushPubKey(U2SGetPushPubKey) => Vec<u8>,
+    GetBrew(U2SGetBrew) => S2UBrew,
+    GetChannel(U2SGetChannel) => S2UChannel,
+    GetIdentity(U2SGetIdentity) => S2UIdentity,
+    GetBrewsById(U2SGetBrewsById) => Vec<Option<S2UBrew>>,
+    GetChannelsById(U2SGetChannelsById) => Vec<Option<S2UChannel>>,
+    GetIdentitiesById(U2SGetIdentitiesById) => Vec<Option<S2UIdentity>>,
+    GetChannels(U2SGetChannels) => Vec<S2UChannel>,
+    GetChannelSummaries(U2SGetChannelSummaries) => Vec<S2UChannelSummary>,
+    GetReadPosition(U2SGetReadPosition) => Option<MessageId>,
+    GetBrews(U2SGetBrews) => Vec<S2UBrew>,
+    GetOwnIdentities(U2SGetOwnIdentities) => Vec<S2UIdentity>,
+    EventsGetAfter(U2SEventsGetAfter) => S2UEventsGetAfterResp,
+    SnapGetAround(U2SSnapGetAround) => S2USnapGetAroundResp,
+    SnapGetBefore(U2SSnapGetBefore) => S2UGetBeforeResp,
+    SnapGetAfter(U2SSnapGetAfter) => S2UGetAfterResp,
+});
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct S2UIdentity {
+    pub id: IdentityId,
+    pub name: String,
+    /// Image url
+    pub avatar: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct S2UChannel {
+    pub id: ChannelId,
+    pub name: String,
+}
+
+/// A channel with its activity, relative to the requesting user.
+#[derive(Serialize, Deserialize)]
+pub struct S2UChannelSummary {
+    pub channel: S2UChannel,
+    pub last_message: Option<S2UMessage>,
+    /// Messages after the user's read position
+    pub unread: u64,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct S2UBrew {
+    pub id: BrewId,
+    pub name: String,
+    pub channels: Vec<ChannelId>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct S2UMessage {
+    pub id: MessageId,
+    pub author: IdentityId,
+    pub time: DateTime<Utc>,
+    pub text: String,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub enum S2UEventKind {
+    MessageCreated(S2UMessage),
+    MessageEdited {
+        id: MessageId,
+        text: String,
+    },
+    MessageDeleted(MessageId),
+    ChannelRenamed {
+        id: ChannelId,
+        name: String,
+    },
+    MemberJoined {
+        channel: ChannelId,
+        identity: IdentityId,
+    },
+    MemberLeft {
+        channel: ChannelId,
+        identity: IdentityId,
+    },
+    BrewChanged(S2UBrew),
+    BrewDeleted(BrewId),
+}
+
+/// An entry in a channel's event log. Events and messages share the channel's id
+/// sequence - a message's id is the id of the event that created it.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct S2UEvent {
+    pub id: MessageId,
+    pub time: DateTime<Utc>,
+    pub kind: S2UEventKind,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct S2UEventsGetAfterResp {
+    pub server_time: MessageId,
+    pub entries: Vec<S2UEvent>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct S2USnapGetAroundResp {
+    pub server_time: MessageId,
+    pub entries: Vec<S2UMessage>,
+    pub early_stop: bool,
+    pub late_stop: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct S2UGetBeforeResp {
+    pub server_time: MessageId,
+    pub entries: Vec<S2UMessage>,
+    pub early_stop: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct S2UGetAfterResp {
+    pub server_time: MessageId,
+    pub entries: Vec<S2UMessage>,
+    pub late_stop: bool,
+}
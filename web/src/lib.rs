@@ -8,6 +8,7 @@ pub mod dbmodel;
 pub mod serviceworker;
 pub mod messagefeed;
 pub mod outboxfeed;
+pub mod messagecache;
 pub mod scrollentry;
 
 pub const NOTIFY_CHANNEL: &'static str = "notify";
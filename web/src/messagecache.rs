@@ -0,0 +1,427 @@
+//! Messages received from the server, kept in IndexedDB so channels can be shown
+//! immediately (and offline) when revisited.
+//!
+//! Besides the messages, each channel has a list of id ranges known to have no
+//! missing messages. Messages are only read from the cache within one range, so
+//! results never silently skip messages that haven't been downloaded.
+use std::rc::Rc;
+use chrono::{
+    DateTime,
+    Utc,
+};
+use gloo::utils::format::JsValueSerdeExt;
+use indexed_db_futures::{
+    IdbDatabase,
+    IdbQuerySource,
+};
+use serde::{
+    Serialize,
+    Deserialize,
+};
+use wasm_bindgen::JsValue;
+use web_sys::{
+    IdbCursorDirection,
+    IdbKeyRange,
+};
+use crate::{
+    bb,
+    dbmodel::{
+        TABLE_MESSAGES,
+        TABLE_MESSAGES_INDEX_CHANNEL_TIME,
+        TABLE_MESSAGE_RANGES,
+    },
+    interface::u2s::{
+        ChannelId,
+        MessageId,
+        S2UMessage,
+    },
+    util::MyErrorDomException,
+};
+
+/// Messages kept across all channels. Past this, the channels stored to least
+/// recently are dropped.
+const CACHE_MESSAGE_LIMIT: u32 = 5000;
+
+#[derive(Serialize, Deserialize)]
+struct CachedMessage {
+    message: S2UMessage,
+    /// Indexed with `time`
+    channel: ChannelId,
+    /// Microseconds since the epoch, so the index sorts by time
+    time: i64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CachedRanges {
+    channel: ChannelId,
+    /// Inclusive message id ranges with every message cached, sorted and not touching
+    ranges: Vec<(u64, u64)>,
+    /// Last time messages were stored, for eviction
+    used: DateTime<Utc>,
+}
+
+impl CachedRanges {
+    fn containing(&self, seq: u64) -> Option<(u64, u64)> {
+        return self.ranges.iter().find(|r| r.0 <= seq && seq <= r.1).cloned();
+    }
+
+    fn add(&mut self, lo: u64, hi: u64) {
+        self.ranges.push((lo, hi));
+        self.ranges.sort();
+        let mut merged: Vec<(u64, u64)> = vec![];
+        for r in self.ranges.drain(..) {
+            match merged.last_mut() {
+                Some(last) if r.0 <= last.1.saturating_add(1) => {
+                    last.1 = last.1.max(r.1);
+                },
+                _ => merged.push(r),
+            }
+        }
+        self.ranges = merged;
+    }
+}
+
+fn js<T: Serialize>(v: &T) -> JsValue {
+    return <JsValue as JsValueSerdeExt>::from_serde(v).unwrap();
+}
+
+/// Keys of messages in `channel` with ids from `lo` to `hi`, inclusive.
+fn seq_range(channel: &ChannelId, lo: u64, hi: u64) -> IdbKeyRange {
+    return IdbKeyRange::bound(&js(&MessageId(channel.clone(), lo)), &js(&MessageId(channel.clone(), hi))).unwrap();
+}
+
+/// Keys of all messages in `channel`.
+fn channel_range(channel: &ChannelId) -> IdbKeyRange {
+    // Arrays sort after numbers
+    return IdbKeyRange::bound(&js(&(channel,)), &js(&(channel, Vec::<()>::new()))).unwrap();
+}
+
+/// Read up to `count` messages in `range`, nearest first.
+async fn read_messages<S: IdbQuerySource>(
+    source: &S,
+    range: &IdbKeyRange,
+    direction: IdbCursorDirection,
+    count: usize,
+) -> Result<Vec<S2UMessage>, String> {
+    let mut out = vec![];
+    bb!{
+        'read_done _;
+        if count == 0 {
+            break 'read_done;
+        }
+        let Some(
+            cursor
+        ) = source.open_cursor_with_range_and_direction(
+            range,
+            direction
+        ).context("Failed to open message cache cursor") ?.await.context("Error waiting for cursor") ? else {
+            break 'read_done;
+        };
+        loop {
+            out.push(JsValueSerdeExt::into_serde::<CachedMessage>(&cursor.value()).unwrap().message);
+            if out.len() >= count {
+                break 'read_done;
+            }
+            if !cursor
+                .continue_cursor()
+                .context("Error moving cursor forward")?
+                .await
+                .context("Error retrieving cursor advance result")? {
+                break 'read_done;
+            }
+        }
+    }
+    return Ok(out);
+}
+
+#[derive(Clone)]
+pub struct MessageCache(Rc<IdbDatabase>);
+
+impl MessageCache {
+    pub fn new(db: Rc<IdbDatabase>) -> MessageCache {
+        return MessageCache(db);
+    }
+
+    /// Messages around `time`, like `SnapGetAround`, if the cache has any. Returns
+    /// messages in ascending order and whether there are no earlier messages.
+    pub async fn get_around(
+        &self,
+        channel: &ChannelId,
+        time: DateTime<Utc>,
+        count: usize,
+    ) -> Result<Option<(Vec<S2UMessage>, bool)>, String> {
+        let txn =
+            self
+                .0
+                .transaction_on_multi_with_mode(
+                    &[TABLE_MESSAGES, TABLE_MESSAGE_RANGES],
+                    web_sys::IdbTransactionMode::Readonly,
+                )
+                .context("Failed to start transaction")?;
+        let messages = txn.object_store(TABLE_MESSAGES).context("Failed to get message cache")?;
+        let Some(ranges) = get_ranges(&txn, channel).await? else {
+            return Ok(None);
+        };
+        let time_index =
+            messages
+                .index(TABLE_MESSAGES_INDEX_CHANNEL_TIME)
+                .context("Failed to get message cache time index")?;
+        let pivot = js(&(channel, time.timestamp_micros()));
+        let mut before =
+            read_messages(
+                &time_index,
+                &IdbKeyRange::bound_with_lower_open_and_upper_open(&js(&(channel,)), &pivot, false, true).unwrap(),
+                IdbCursorDirection::Prev,
+                count,
+            ).await?;
+        let after =
+            read_messages(
+                &time_index,
+                &IdbKeyRange::bound(&pivot, &js(&(channel, Vec::<()>::new()))).unwrap(),
+                IdbCursorDirection::Next,
+                count,
+            ).await?;
+        txn.await.into_result().context("Failed to commit transaction")?;
+        let early_stop_candidate = before.len() < count;
+        before.reverse();
+        let mut all = before;
+        all.extend(after);
+
+        // Only answer if there's nothing missing between the results
+        let (Some(first), Some(last)) = (all.first(), all.last()) else {
+            return Ok(None);
+        };
+        let Some(range) = ranges.containing(first.id.1) else {
+            return Ok(None);
+        };
+        if last.id.1 > range.1 {
+            return Ok(None);
+        }
+        let early_stop = early_stop_candidate && range.0 == 0;
+        return Ok(Some((all, early_stop)));
+    }
+
+    /// Up to `count` messages before `id`, like `SnapGetBefore`, if the cache has
+    /// any. Returns messages in ascending order and whether there are no earlier
+    /// messages.
+    pub async fn get_before(&self, id: &MessageId, count: usize) -> Result<Option<(Vec<S2UMessage>, bool)>, String> {
+        let txn =
+            self
+                .0
+                .transaction_on_multi_with_mode(
+                    &[TABLE_MESSAGES, TABLE_MESSAGE_RANGES],
+                    web_sys::IdbTransactionMode::Readonly,
+                )
+                .context("Failed to start transaction")?;
+        let messages = txn.object_store(TABLE_MESSAGES).context("Failed to get message cache")?;
+        let Some(ranges) = get_ranges(&txn, &id.0).await? else {
+            return Ok(None);
+        };
+        let Some(range) = ranges.containing(id.1) else {
+            return Ok(None);
+        };
+        if id.1 == 0 {
+            return Ok(Some((vec![], true)));
+        }
+        let mut found =
+            read_messages(&messages, &seq_range(&id.0, range.0, id.1 - 1), IdbCursorDirection::Prev, count).await?;
+        txn.await.into_result().context("Failed to commit transaction")?;
+        let early_stop = found.len() < count && range.0 == 0;
+        if found.is_empty() && !early_stop {
+            return Ok(None);
+        }
+        found.reverse();
+        return Ok(Some((found, early_stop)));
+    }
+
+    /// Up to `count` messages after `id`, like `SnapGetAfter`, if the cache has any.
+    /// Newer messages may always exist, so there's no stop flag.
+    pub async fn get_after(&self, id: &MessageId, count: usize) -> Result<Option<Vec<S2UMessage>>, String> {
+        let txn =
+            self
+                .0
+                .transaction_on_multi_with_mode(
+                    &[TABLE_MESSAGES, TABLE_MESSAGE_RANGES],
+                    web_sys::IdbTransactionMode::Readonly,
+                )
+                .context("Failed to start transaction")?;
+        let messages = txn.object_store(TABLE_MESSAGES).context("Failed to get message cache")?;
+        let Some(ranges) = get_ranges(&txn, &id.0).await? else {
+            return Ok(None);
+        };
+        let Some(range) = ranges.containing(id.1) else {
+            return Ok(None);
+        };
+        if range.1 == id.1 {
+            return Ok(None);
+        }
+        let found =
+            read_messages(&messages, &seq_range(&id.0, id.1 + 1, range.1), IdbCursorDirection::Next, count).await?;
+        txn.await.into_result().context("Failed to commit transaction")?;
+        if found.is_empty() {
+            return Ok(None);
+        }
+        return Ok(Some(found));
+    }
+
+    /// Store messages received from the server, along with the range of ids (`lo` to
+    /// `hi`, inclusive) the server confirmed has no other messages. Evicts other
+    /// channels if the cache is over its limit.
+    pub async fn store(&self, channel: &ChannelId, found: Vec<S2UMessage>, lo: u64, hi: u64) -> Result<(), String> {
+        let txn =
+            self
+                .0
+                .transaction_on_multi_with_mode(
+                    &[TABLE_MESSAGES, TABLE_MESSAGE_RANGES],
+                    web_sys::IdbTransactionMode::Readwrite,
+                )
+                .context("Failed to start transaction")?;
+        let messages = txn.object_store(TABLE_MESSAGES).context("Failed to get message cache")?;
+        let ranges_store = txn.object_store(TABLE_MESSAGE_RANGES).context("Failed to get message cache ranges")?;
+        for m in found {
+            messages.put_key_val(&js(&m.id), &js(&CachedMessage {
+                channel: m.id.0.clone(),
+                time: m.time.timestamp_micros(),
+                message: m,
+            })).context("Failed to initiate message cache write")?.await.context("Failed to write to message cache")?;
+        }
+        let mut ranges = get_ranges(&txn, channel).await?.unwrap_or_else(|| CachedRanges {
+            channel: channel.clone(),
+            ranges: vec![],
+            used: Utc::now(),
+        });
+        ranges.add(lo, hi);
+        ranges.used = Utc::now();
+        ranges_store
+            .put_key_val(&js(channel), &js(&ranges))
+            .context("Failed to initiate message cache range write")?
+            .await
+            .context("Failed to write message cache ranges")?;
+
+        // Evict
+        let mut total = messages.count().context("Failed to start message cache count")?.await.context("Failed to count cached messages")?;
+        if total > CACHE_MESSAGE_LIMIT {
+            let mut all_ranges =
+                ranges_store
+                    .get_all()
+                    .context("Failed to start message cache range listing")?
+                    .await
+                    .context("Failed to list message cache ranges")?
+                    .iter()
+                    .map(|r| JsValueSerdeExt::into_serde::<CachedRanges>(&r).unwrap())
+                    .collect::<Vec<_>>();
+            all_ranges.sort_by_key(|r| r.used);
+            for r in all_ranges {
+                if total <= CACHE_MESSAGE_LIMIT {
+                    break;
+                }
+                if &r.channel == channel {
+                    continue;
+                }
+                let evict_range = channel_range(&r.channel);
+                let evict_count =
+                    messages
+                        .count_with_key(&evict_range)
+                        .context("Failed to start evicted message count")?
+                        .await
+                        .context("Failed to count evicted messages")?;
+                messages
+                    .delete(&evict_range)
+                    .context("Failed to initiate message eviction")?
+                    .await
+                    .context("Failed to evict messages")?;
+                ranges_store
+                    .delete(&js(&r.channel))
+                    .context("Failed to initiate message range eviction")?
+                    .await
+                    .context("Failed to evict message ranges")?;
+                total -= evict_count;
+            }
+        }
+        txn.await.into_result().context("Failed to commit transaction")?;
+        return Ok(());
+    }
+
+    /// Update the text of a cached message, if cached.
+    pub async fn edit(&self, id: &MessageId, text: String) -> Result<(), String> {
+        let txn =
+            self
+                .0
+                .transaction_on_one_with_mode(TABLE_MESSAGES, web_sys::IdbTransactionMode::Readwrite)
+                .context("Failed to start transaction")?;
+        let messages = txn.object_store(TABLE_MESSAGES).context("Failed to get message cache")?;
+        let key = js(id);
+        if let Some(found) =
+            messages
+                .get(&key)
+                .context("Failed to initiate message cache lookup")?
+                .await
+                .context("Failed to look up cached message")? {
+            let mut found = JsValueSerdeExt::into_serde::<CachedMessage>(&found).unwrap();
+            found.message.text = text;
+            messages
+                .put_key_val(&key, &js(&found))
+                .context("Failed to initiate message cache write")?
+                .await
+                .context("Failed to write to message cache")?;
+        }
+        txn.await.into_result().context("Failed to commit transaction")?;
+        return Ok(());
+    }
+
+    /// Remove a deleted message. Ranges stay valid since deleted messages are never
+    /// returned by the server either.
+    pub async fn delete(&self, id: &MessageId) -> Result<(), String> {
+        let txn =
+            self
+                .0
+                .transaction_on_one_with_mode(TABLE_MESSAGES, web_sys::IdbTransactionMode::Readwrite)
+                .context("Failed to start transaction")?;
+        let messages = txn.object_store(TABLE_MESSAGES).context("Failed to get message cache")?;
+        messages
+            .delete(&js(id))
+            .context("Failed to initiate cached message deletion")?
+            .await
+            .context("Failed to delete cached message")?;
+        txn.await.into_result().context("Failed to commit transaction")?;
+        return Ok(());
+    }
+
+    /// Remove everything, ex: on logout.
+    pub async fn clear(&self) -> Result<(), String> {
+        let txn =
+            self
+                .0
+                .transaction_on_multi_with_mode(
+                    &[TABLE_MESSAGES, TABLE_MESSAGE_RANGES],
+                    web_sys::IdbTransactionMode::Readwrite,
+                )
+                .context("Failed to start transaction")?;
+        for store in [TABLE_MESSAGES, TABLE_MESSAGE_RANGES] {
+            txn
+                .object_store(store)
+                .context("Failed to get message cache")?
+                .clear()
+                .context("Failed to start clearing message cache")?
+                .await
+                .context("Failed to clear message cache")?;
+        }
+        txn.await.into_result().context("Failed to commit transaction")?;
+        return Ok(());
+    }
+}
+
+async fn get_ranges(
+    txn: &indexed_db_futures::idb_transaction::IdbTransaction<'_>,
+    channel: &ChannelId,
+) -> Result<Option<CachedRanges>, String> {
+    let ranges = txn.object_store(TABLE_MESSAGE_RANGES).context("Failed to get message cache ranges")?;
+    return Ok(
+        ranges
+            .get(&js(channel))
+            .context("Failed to initiate message cache range lookup")?
+            .await
+            .context("Failed to look up message cache ranges")?
+            .map(|r| JsValueSerdeExt::into_serde::<CachedRanges>(&r).unwrap()),
+    );
+}
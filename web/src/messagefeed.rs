@@ -1,11 +1,16 @@
 use std::{
     cell::RefCell,
+    collections::{
+        HashMap,
+        HashSet,
+    },
     rc::{
         Rc,
     },
 };
 use lunk::{
     EventGraph,
+    ProcessingContext,
 };
 use rooting::{
     ScopeValue,
@@ -22,30 +27,48 @@ use crate::{
         bg,
         spawn_rooted,
     },
+    log,
+    messagecache::MessageCache,
     enum_unwrap,
     world::{
-        S2USnapGetAroundResp,
-        U2SGet,
+        FeedId,
+        World,
+    },
+    interface::u2s::{
+        U2SEventsGetAfter,
+        U2SSnapGetAround,
+        U2SSnapGetBefore,
+        U2SSnapGetAfter,
         ChannelId,
         MessageId,
         DateMessageId,
-        S2UEventsGetAfterResp,
-        FeedId,
-        World,
+        S2UEventKind,
+        S2UMessage,
     },
 };
 use super::{
     scrollentry::{
+        Author,
+        AuthorRenderer,
         EntryMap,
         FeedEntry,
         FeedTime,
     },
 };
 
+/// Handles channel events that affect more than the feed's messages (renames,
+/// membership, etc).
+pub type ChannelEventHandler = Rc<dyn Fn(&mut ProcessingContext, S2UEventKind)>;
+
+/// Ids of messages received by channel feeds, shared between feeds. Used to tell
+/// when sent messages no longer need to be kept in the outbox.
+pub type SeenMessages = Rc<RefCell<HashSet<MessageId>>>;
+
 struct ChannelFeedMut {
     parent: Option<WeakInfiniscroll<Option<ChannelId>, FeedTime>>,
     server_time: Option<MessageId>,
     refreshing: Option<ScopeValue>,
+    first_unread: Option<MessageId>,
 }
 
 pub struct ChannelFeed_ {
@@ -53,13 +76,24 @@ pub struct ChannelFeed_ {
     world: World,
     mut_: RefCell<ChannelFeedMut>,
     entries: EntryMap,
+    on_event: ChannelEventHandler,
+    render_author: AuthorRenderer,
+    seen: SeenMessages,
+    cache: MessageCache,
 }
 
 #[derive(Clone)]
 pub struct ChannelFeed(Rc<ChannelFeed_>);
 
 impl ChannelFeed {
-    pub fn new(world: World, id: ChannelId) -> Self {
+    pub fn new(
+        world: World,
+        id: ChannelId,
+        on_event: ChannelEventHandler,
+        render_author: AuthorRenderer,
+        seen: SeenMessages,
+        cache: MessageCache,
+    ) -> Self {
         return ChannelFeed(Rc::new(ChannelFeed_ {
             id: id,
             world: world,
@@ -67,11 +101,144 @@ impl ChannelFeed {
                 parent: None,
                 server_time: None,
                 refreshing: None,
+                first_unread: None,
             }),
             entries: EntryMap::new(),
+            on_event: on_event,
+            render_author: render_author,
+            seen: seen,
+            cache: cache,
         }));
     }
 
+    /// Mark the first message after the read position, moving the "new messages"
+    /// divider if it's already shown. The read position is looked up separately so
+    /// the feed can show cached messages without waiting for the server.
+    pub fn set_first_unread(&self, pc: &mut ProcessingContext, first_unread: Option<MessageId>) {
+        let old = std::mem::replace(&mut self.0.mut_.borrow_mut().first_unread, first_unread.clone());
+        if let Some(e) = old.and_then(|id| self.0.entries.get(&FeedId::Real(id))) {
+            e.unread_start.set(pc, false);
+        }
+        if let Some(e) = first_unread.and_then(|id| self.0.entries.get(&FeedId::Real(id))) {
+            e.unread_start.set(pc, true);
+        }
+    }
+
+    fn entry(&self, pc: &mut ProcessingContext, m: S2UMessage) -> Rc<dyn Entry<FeedTime>> {
+        let unread_start = self.0.mut_.borrow().first_unread.as_ref() == Some(&m.id);
+        self.0.seen.borrow_mut().insert(m.id.clone());
+        return Rc::new(FeedEntry::new(pc, FeedTime {
+            stamp: m.time,
+            id: FeedId::Real(m.id),
+        }, Some(Author {
+            id: m.author,
+            render: self.0.render_author.clone(),
+        }), m.text, unread_start, &self.0.entries));
+    }
+
+    fn parent(&self) -> Option<crate::infiniscroll::Infiniscroll<Option<ChannelId>, FeedTime>> {
+        return self.0.mut_.borrow().parent.as_ref().and_then(|p| p.upgrade());
+    }
+
+    /// Record the server time from a response, returning true if events need to be
+    /// pulled.
+    fn update_server_time(&self, server_time: MessageId) -> bool {
+        let mut mut_ = self.0.mut_.borrow_mut();
+        match &mut_.server_time {
+            None => {
+                mut_.server_time = Some(server_time);
+                return true;
+            },
+            Some(have) => {
+                // Responses can arrive out of order, only move forward
+                if &server_time <= have {
+                    return false;
+                }
+                mut_.server_time = Some(server_time);
+                return true;
+            },
+        }
+    }
+
+    /// Save a server response covering ids `lo` to `hi` (inclusive) to the cache.
+    fn store_cache(&self, entries: &[S2UMessage], lo: u64, hi: u64) {
+        let cache = self.0.cache.clone();
+        let channel = self.0.id.clone();
+        let entries = entries.to_vec();
+        bg("Channel feed, caching messages", async move {
+            return cache.store(&channel, entries, lo, hi).await;
+        });
+    }
+
+    /// After answering from the cache, bring shown entries with ids from `lo` to `hi`
+    /// (inclusive) in line with the server's response for the same range: update
+    /// edited text, remove deleted messages, add missing messages and take the
+    /// server's stop status for the ends the response covers.
+    fn reconcile(
+        &self,
+        pc: &mut ProcessingContext,
+        entries: Vec<S2UMessage>,
+        lo: u64,
+        hi: u64,
+        early_stop: Option<bool>,
+        late_stop: Option<bool>,
+    ) {
+        let mut missing = entries.into_iter().map(|e| (e.id.1, e)).collect::<HashMap<_, _>>();
+        let shown = self.0.entries.0.borrow().values().filter_map(|e| e.upgrade()).filter(|e| {
+            let FeedId::Real(id) = &e.id.id else {
+                return false;
+            };
+            return id.0 == self.0.id && lo <= id.1 && id.1 <= hi;
+        }).collect::<Vec<_>>();
+        let parent = self.parent();
+        for e in shown {
+            let FeedId::Real(id) = &e.id.id else {
+                continue;
+            };
+            match missing.remove(&id.1) {
+                Some(m) => {
+                    if *e.text.borrow() != m.text {
+                        e.text.set(pc, m.text);
+                    }
+                },
+                None => {
+                    if let Some(parent) = &parent {
+                        parent.remove_entry(&Some(self.0.id.clone()), &e.id);
+                    }
+                },
+            }
+        }
+        let Some(parent) = parent else {
+            return;
+        };
+        let mut missing = missing.into_values().collect::<Vec<_>>();
+        missing.sort_by_key(|m| m.id.1);
+        parent.add_entries(
+            &Some(self.0.id.clone()),
+            missing.into_iter().map(|m| self.entry(pc, m)).collect(),
+            early_stop,
+            late_stop,
+        );
+    }
+
+    /// Called when notified of a channel event other than a new message.
+    pub fn notify_event(&self, eg: EventGraph, id: MessageId) {
+        if id.0 != self.0.id {
+            return;
+        }
+        {
+            let mut_ = self.0.mut_.borrow();
+            match &mut_.server_time {
+                Some(server_time) => if &id <= server_time {
+                    return;
+                },
+                // Not loaded yet, will refresh once loaded
+                None => return,
+            }
+        }
+        self.trigger_refresh(eg);
+    }
+
     pub fn notify(&self, eg: EventGraph, id: DateMessageId) {
         if id.1.0 != self.0.id {
             return;
@@ -105,6 +272,11 @@ impl ChannelFeed {
         if mut_.refreshing.is_some() {
             return;
         }
+
+        // Not loaded yet, the first response sets the server time and refreshes
+        if mut_.server_time.is_none() {
+            return;
+        }
         mut_.refreshing = Some(spawn_rooted("pulling new channel events", {
             let self1 = self.clone();
             async move {
@@ -115,8 +287,11 @@ impl ChannelFeed {
                     }
                 });
                 loop {
-                    let resp = self1.0.world.req_get::<S2UEventsGetAfterResp>(U2SGet::EventsGetAfter {
-                        id: self1.0.mut_.borrow().server_time.clone(),
+                    let Some(after) = self1.0.mut_.borrow().server_time.clone() else {
+                        break;
+                    };
+                    let resp = self1.0.world.call(U2SEventsGetAfter {
+                        id: after,
                         count: REQUEST_COUNT as u64,
                     }).await?;
                     if resp.entries.is_empty() {
@@ -124,18 +299,67 @@ impl ChannelFeed {
                     }
                     {
                         let mut mut_ = self1.0.mut_.borrow_mut();
+                        let parent = mut_.parent.as_ref().and_then(|p| p.upgrade());
                         let mut server_time = None;
+                        let mut want_after = None;
                         eg.event(|pc| {
                             for entry in resp.entries {
                                 server_time = Some(entry.id.clone());
-                                let mut entries = self1.0.entries.0.borrow_mut();
-                                let Some(e) = entries.get_mut(&FeedId::Real(entry.id.clone())) else {
-                                    continue;
-                                };
-                                e.0.text.set(pc, entry.text);
+                                match entry.kind {
+                                    S2UEventKind::MessageCreated(m) => {
+                                        let Some(parent) = &parent else {
+                                            continue;
+                                        };
+                                        let want = parent.want_after(Some(self1.0.id.clone()), FeedTime {
+                                            stamp: m.time,
+                                            id: FeedId::Real(m.id),
+                                        });
+                                        if want_after.is_none() {
+                                            want_after = want;
+                                        }
+                                    },
+                                    S2UEventKind::MessageEdited { id, text } => {
+                                        bg("Channel feed, updating cached message", {
+                                            let cache = self1.0.cache.clone();
+                                            let id = id.clone();
+                                            let text = text.clone();
+                                            async move {
+                                                return cache.edit(&id, text).await;
+                                            }
+                                        });
+                                        let Some(e) = self1.0.entries.get(&FeedId::Real(id)) else {
+                                            continue;
+                                        };
+                                        e.text.set(pc, text);
+                                    },
+                                    S2UEventKind::MessageDeleted(id) => {
+                                        bg("Channel feed, removing cached message", {
+                                            let cache = self1.0.cache.clone();
+                                            let id = id.clone();
+                                            async move {
+                                                return cache.delete(&id).await;
+                                            }
+                                        });
+                                        let Some(parent) = &parent else {
+                                            continue;
+                                        };
+                                        let Some(e) = self1.0.entries.get(&FeedId::Real(id)) else {
+                                            continue;
+                                        };
+                                        parent.remove_entry(&Some(self1.0.id.clone()), &e.id);
+                                    },
+                                    kind => (self1.0.on_event)(pc, kind),
+                                }
                             }
                         });
-                        mut_.server_time = Some(server_time.unwrap());
+                        let server_time = server_time.unwrap();
+                        if mut_.server_time.as_ref().map(|t| t < &server_time).unwrap_or(true) {
+                            mut_.server_time = Some(server_time);
+                        }
+                        drop(mut_);
+                        if let Some((pivot, count)) = want_after {
+                            self1.request_after(eg.clone(), pivot, count);
+                        }
                     }
                 }
                 return Ok(());
@@ -153,40 +377,64 @@ impl Feed<Option<ChannelId>, FeedTime> for ChannelFeed {
         bg("Channel feed - requesting messages around", {
             let self1 = self.clone();
             async move {
-                let resp: S2USnapGetAroundResp = self1.0.world.req_get(U2SGet::SnapGetAround {
+                let cached = match self1.0.cache.get_around(&self1.0.id, time.stamp, count).await {
+                    Ok(c) => c,
+                    Err(e) => {
+                        log!("Error reading message cache, using server: {}", e);
+                        None
+                    },
+                };
+                let answered = cached.is_some();
+                if let Some((entries, early_stop)) = cached {
+                    eg.event(|pc| {
+                        let Some(parent) = self1.parent() else {
+                            return;
+                        };
+                        parent.respond_entries_around(
+                            Some(self1.0.id.clone()),
+                            time.clone(),
+                            entries.into_iter().map(|e| self1.entry(pc, e)).collect(),
+                            early_stop,
+                            // The cache can't tell if there are newer messages, the server's answer
+                            // is applied when reconciling
+                            false,
+                        );
+                    });
+                }
+                let resp = self1.0.world.call(U2SSnapGetAround {
                     channel: self1.0.id.clone(),
                     time: time.stamp,
                     count: count as u64,
                 }).await?;
+                let lo = if resp.early_stop {
+                    0
+                } else {
+                    resp.entries.first().map(|e| e.id.1).unwrap_or(resp.server_time.1)
+                };
+                let hi = if resp.late_stop {
+                    resp.server_time.1
+                } else {
+                    resp.entries.last().map(|e| e.id.1).unwrap_or(0)
+                };
+                if lo <= hi {
+                    self1.store_cache(&resp.entries, lo, hi);
+                }
                 eg.event(|pc| {
-                    let refresh;
-                    {
-                        let mut mut_ = self1.0.mut_.borrow_mut();
-                        let Some(parent) = mut_.parent.as_ref().and_then(|p| p.upgrade()) else {
+                    if answered {
+                        self1.reconcile(pc, resp.entries, lo, hi, Some(resp.early_stop), Some(resp.late_stop));
+                    } else {
+                        let Some(parent) = self1.parent() else {
                             return;
                         };
                         parent.respond_entries_around(
                             Some(self1.0.id.clone()),
                             time,
-                            resp.entries.into_iter().map(|e| Rc::new(FeedEntry::new(pc, FeedTime {
-                                stamp: e.time,
-                                id: FeedId::Real(e.id),
-                            }, e.text, &self1.0.entries)) as Rc<dyn Entry<FeedTime>>).collect(),
+                            resp.entries.into_iter().map(|e| self1.entry(pc, e)).collect(),
                             resp.early_stop,
                             resp.late_stop,
                         );
-                        if mut_.server_time.is_none() {
-                            refresh = true;
-                        } else if mut_.server_time.as_ref().unwrap() != &resp.server_time {
-                            if &resp.server_time < mut_.server_time.as_ref().unwrap() {
-                                mut_.server_time = Some(resp.server_time);
-                            }
-                            refresh = true;
-                        } else {
-                            refresh = false;
-                        }
                     }
-                    if refresh {
+                    if self1.update_server_time(resp.server_time) {
                         self1.trigger_refresh(pc.eg());
                     }
                 });
@@ -199,38 +447,58 @@ impl Feed<Option<ChannelId>, FeedTime> for ChannelFeed {
         bg("Channel feed, requesting messages before", {
             let self1 = self.clone();
             async move {
-                let resp: S2USnapGetAroundResp = self1.0.world.req_get(U2SGet::SnapGetBefore {
-                    id: enum_unwrap!(&time.id, FeedId:: Real(x) => x.clone()),
+                let pivot = enum_unwrap!(&time.id, FeedId:: Real(x) => x.clone());
+                let cached = match self1.0.cache.get_before(&pivot, count).await {
+                    Ok(c) => c,
+                    Err(e) => {
+                        log!("Error reading message cache, using server: {}", e);
+                        None
+                    },
+                };
+                let answered = cached.is_some();
+                if let Some((entries, early_stop)) = cached {
+                    eg.event(|pc| {
+                        let Some(parent) = self1.parent() else {
+                            return;
+                        };
+                        parent.respond_entries_before(
+                            &Some(self1.0.id.clone()),
+                            &time,
+                            // Cache returns ascending
+                            entries.into_iter().rev().map(|e| self1.entry(pc, e)).collect(),
+                            early_stop,
+                        );
+                    });
+                }
+                let resp = self1.0.world.call(U2SSnapGetBefore {
+                    id: pivot.clone(),
                     count: count as u64,
                 }).await?;
+                let lo = if resp.early_stop {
+                    0
+                } else {
+                    resp.entries.first().map(|e| e.id.1).unwrap_or(pivot.1)
+                };
+                let hi = pivot.1.saturating_sub(1);
+                if lo <= hi {
+                    self1.store_cache(&resp.entries, lo, hi);
+                }
                 eg.event(|pc| {
-                    let refresh;
-                    {
-                        let mut mut_ = self1.0.mut_.borrow_mut();
-                        let Some(parent) = mut_.parent.as_ref().and_then(|p| p.upgrade()) else {
+                    if answered {
+                        self1.reconcile(pc, resp.entries, lo, hi, Some(resp.early_stop), None);
+                    } else {
+                        let Some(parent) = self1.parent() else {
                             return;
                         };
                         parent.respond_entries_before(
                             &Some(self1.0.id.clone()),
                             &time,
-                            resp.entries.into_iter().map(|e| Rc::new(FeedEntry::new(pc, FeedTime {
-                                stamp: e.time,
-                                id: FeedId::Real(e.id),
-                            }, e.text, &self1.0.entries)) as Rc<dyn Entry<FeedTime>>).collect(),
+                            // Server returns ascending
+                            resp.entries.into_iter().rev().map(|e| self1.entry(pc, e)).collect(),
                             resp.early_stop,
                         );
-                        if mut_.server_time.is_none() {
-                            refresh = true;
-                        } else if mut_.server_time.as_ref().unwrap() != &resp.server_time {
-                            if &resp.server_time < mut_.server_time.as_ref().unwrap() {
-                                mut_.server_time = Some(resp.server_time);
-                            }
-                            refresh = true;
-                        } else {
-                            refresh = false;
-                        }
                     }
-                    if refresh {
+                    if self1.update_server_time(resp.server_time) {
                         self1.trigger_refresh(pc.eg());
                     }
                 });
@@ -243,38 +511,57 @@ impl Feed<Option<ChannelId>, FeedTime> for ChannelFeed {
         bg("Channel feed, requesting messages after", {
             let self1 = self.clone();
             async move {
-                let resp: S2USnapGetAroundResp = self1.0.world.req_get(U2SGet::SnapGetAfter {
-                    id: enum_unwrap!(&time.id, FeedId:: Real(x) => x.clone()),
+                let pivot = enum_unwrap!(&time.id, FeedId:: Real(x) => x.clone());
+                let cached = match self1.0.cache.get_after(&pivot, count).await {
+                    Ok(c) => c,
+                    Err(e) => {
+                        log!("Error reading message cache, using server: {}", e);
+                        None
+                    },
+                };
+                let answered = cached.is_some();
+                if let Some(entries) = cached {
+                    eg.event(|pc| {
+                        let Some(parent) = self1.parent() else {
+                            return;
+                        };
+                        parent.respond_entries_after(
+                            &Some(self1.0.id.clone()),
+                            &time,
+                            entries.into_iter().map(|e| self1.entry(pc, e)).collect(),
+                            // Replaced by the server's answer when reconciling
+                            false,
+                        );
+                    });
+                }
+                let resp = self1.0.world.call(U2SSnapGetAfter {
+                    id: pivot.clone(),
                     count: count as u64,
                 }).await?;
+                let lo = pivot.1 + 1;
+                let hi = if resp.late_stop {
+                    resp.server_time.1
+                } else {
+                    resp.entries.last().map(|e| e.id.1).unwrap_or(pivot.1)
+                };
+                if lo <= hi {
+                    self1.store_cache(&resp.entries, lo, hi);
+                }
                 eg.event(|pc| {
-                    let refresh;
-                    {
-                        let mut mut_ = self1.0.mut_.borrow_mut();
-                        let Some(parent) = mut_.parent.as_ref().and_then(|p| p.upgrade()) else {
+                    if answered {
+                        self1.reconcile(pc, resp.entries, lo, hi, None, Some(resp.late_stop));
+                    } else {
+                        let Some(parent) = self1.parent() else {
                             return;
                         };
                         parent.respond_entries_after(
                             &Some(self1.0.id.clone()),
                             &time,
-                            resp.entries.into_iter().map(|e| Rc::new(FeedEntry::new(pc, FeedTime {
-                                stamp: e.time,
-                                id: FeedId::Real(e.id),
-                            }, e.text, &self1.0.entries)) as Rc<dyn Entry<FeedTime>>).collect(),
+                            resp.entries.into_iter().map(|e| self1.entry(pc, e)).collect(),
                             resp.late_stop,
                         );
-                        if mut_.server_time.is_none() {
-                            refresh = true;
-                        } else if mut_.server_time.as_ref().unwrap() != &resp.server_time {
-                            if &resp.server_time < mut_.server_time.as_ref().unwrap() {
-                                mut_.server_time = Some(resp.server_time);
-                            }
-                            refresh = true;
-                        } else {
-                            refresh = false;
-                        }
                     }
-                    if refresh {
+                    if self1.update_server_time(resp.server_time) {
                         self1.trigger_refresh(pc.eg());
                     }
                 });
@@ -8,7 +8,8 @@
 //!
 //! 3. Unified interface for async data
 //!
-//! 4. Caching
+//! 4. Caching, optionally persisted so values are available immediately after a
+//!    reload
 use std::{
     rc::{
         Weak,
@@ -22,6 +23,11 @@ use std::{
     },
     pin::Pin,
 };
+use chrono::{
+    DateTime,
+    Duration,
+    Utc,
+};
 use caches::{
     WTinyLFUCache,
     Cache,
@@ -34,6 +40,10 @@ use futures::{
     },
     Future,
 };
+use lunk::{
+    EventGraph,
+    ProcessingContext,
+};
 use wasm_bindgen_futures::spawn_local;
 use crate::log;
 
@@ -47,7 +57,8 @@ impl<K: 'static + Clone> NowOrLaterValue for K { }
 
 pub enum NowOrLater<K: NowOrLaterKey, V: NowOrLaterValue> {
     Now(Hard<K, V>),
-    Later(Receiver<Hard<K, V>>),
+    /// Resolves with an error if fetching fails. The next `get` retries.
+    Later(Receiver<Result<Hard<K, V>, String>>),
 }
 
 struct Hard_<K: NowOrLaterKey, V: NowOrLaterValue> {
@@ -80,42 +91,257 @@ impl<K: NowOrLaterKey, V: NowOrLaterValue> Deref for Hard<K, V> {
     }
 }
 
+/// Storage for values between sessions. Values loaded from here are shown
+/// immediately and refetched in the background the first time they're used.
+pub struct NowOrLaterPersist<K: NowOrLaterKey, V: NowOrLaterValue> {
+    /// Read all saved values
+    pub load: Box<dyn Fn() -> Pin<Box<dyn Future<Output = Result<Vec<(K, V)>, String>>>>>,
+    /// Save a value (in the background)
+    pub save: Box<dyn Fn(&K, &V)>,
+    /// Remove all saved values (in the background)
+    pub clear: Box<dyn Fn()>,
+}
+
+/// Fetches one value.
+pub type NowOrLaterGetOne<K, V> = Box<dyn Fn(K) -> Pin<Box<dyn Future<Output = Result<V, String>>>>>;
+
+/// Fetches several values at once. Results must be in key order.
+pub type NowOrLaterGetBatch<K, V> =
+    Box<dyn Fn(Vec<K>) -> Pin<Box<dyn Future<Output = Result<Vec<Result<V, String>>, String>>>>>;
+
+enum Getter<K, V> {
+    One(NowOrLaterGetOne<K, V>),
+    Batch {
+        get: NowOrLaterGetBatch<K, V>,
+        /// Keys requested in this tick, to be fetched together
+        queue: RefCell<Vec<K>>,
+        /// Most keys per request
+        max: usize,
+    },
+}
+
 struct NowOrLaterCollection_<K: NowOrLaterKey, V: NowOrLaterValue> {
+    eg: EventGraph,
     unused: RefCell<WTinyLFUCache<K, V>>,
     used: RefCell<HashMap<K, Weak<Hard_<K, V>>>>,
-    get: Box<dyn Fn(K) -> Pin<Box<dyn Future<Output = Result<V, String>>>>>,
+    get: Getter<K, V>,
+    /// Update a live value to match a newly fetched one
+    refresh: Box<dyn Fn(&mut ProcessingContext, &V, V)>,
     in_flight: RefCell<HashSet<K>>,
-    pending: RefCell<HashMap<K, Vec<Sender<Hard<K, V>>>>>,
+    /// In flight keys invalidated since the request was sent, refetched when the
+    /// request finishes
+    dirty: RefCell<HashSet<K>>,
+    pending: RefCell<HashMap<K, Vec<Sender<Result<Hard<K, V>, String>>>>>,
+    persist: Option<NowOrLaterPersist<K, V>>,
+    /// When each value was last fetched or set this session. Values without an entry
+    /// were loaded from `persist`.
+    fetched: RefCell<HashMap<K, DateTime<Utc>>>,
+    /// Refetch values older than this when they're used
+    ttl: Option<Duration>,
 }
 
 #[derive(Clone)]
 pub struct NowOrLaterCollection<K: NowOrLaterKey, V: NowOrLaterValue>(Rc<NowOrLaterCollection_<K, V>>);
 
 impl<K: NowOrLaterKey, V: NowOrLaterValue> NowOrLaterCollection<K, V> {
-    pub fn new(f: impl 'static + Fn(K) -> Pin<Box<dyn Future<Output = Result<V, String>>>>) -> Self {
+    /// `f` fetches a value. `refresh` updates a value in use in place when it's
+    /// refetched.
+    pub fn new(
+        eg: EventGraph,
+        f: impl 'static + Fn(K) -> Pin<Box<dyn Future<Output = Result<V, String>>>>,
+        refresh: impl 'static + Fn(&mut ProcessingContext, &V, V),
+    ) -> Self {
+        return Self::new_(eg, Getter::One(Box::new(f)), Box::new(refresh));
+    }
+
+    /// Like `new`, but all keys requested in the same tick are fetched together with
+    /// `f`, at most `max` per call.
+    pub fn new_batched(
+        eg: EventGraph,
+        f: impl 'static + Fn(Vec<K>) -> Pin<Box<dyn Future<Output = Result<Vec<Result<V, String>>, String>>>>,
+        max: usize,
+        refresh: impl 'static + Fn(&mut ProcessingContext, &V, V),
+    ) -> Self {
+        return Self::new_(eg, Getter::Batch {
+            get: Box::new(f),
+            queue: Default::default(),
+            max: max,
+        }, Box::new(refresh));
+    }
+
+    fn new_(eg: EventGraph, get: Getter<K, V>, refresh: Box<dyn Fn(&mut ProcessingContext, &V, V)>) -> Self {
         return NowOrLaterCollection(Rc::new(NowOrLaterCollection_ {
+            eg: eg,
             unused: RefCell::new(WTinyLFUCache::<K, V>::builder().set_window_cache_size(100).finalize().unwrap()),
             used: Default::default(),
-            get: Box::new(f),
+            get: get,
+            refresh: refresh,
             in_flight: Default::default(),
+            dirty: Default::default(),
             pending: Default::default(),
+            persist: None,
+            fetched: Default::default(),
+            ttl: None,
         }));
     }
 
-    pub fn get_immediate(&self, k: &K) -> Option<Hard<K, V>> {
-        if let Some(v) = self.0.used.borrow().get(&k) {
-            return Some(Hard(v.upgrade().unwrap()));
+    /// Also save values in `persist`. Call `load_persisted` at startup to restore
+    /// them. Must be called before the collection is cloned.
+    pub fn with_persist(mut self, persist: NowOrLaterPersist<K, V>) -> Self {
+        Rc::get_mut(&mut self.0).unwrap().persist = Some(persist);
+        return self;
+    }
+
+    /// Refetch values in the background when they're used, if they were fetched
+    /// longer than `ttl` ago. Must be called before the collection is cloned.
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        Rc::get_mut(&mut self.0).unwrap().ttl = Some(ttl);
+        return self;
+    }
+
+    /// Fill the cache with values saved in previous sessions.
+    pub async fn load_persisted(&self) -> Result<(), String> {
+        let Some(persist) = &self.0.persist else {
+            return Ok(());
         };
-        if let Some(v) = self.0.unused.borrow_mut().remove(&k) {
-            let out = Hard(Rc::new(Hard_ {
+        for (k, v) in (persist.load)().await? {
+            if self.0.used.borrow().contains_key(&k) {
+                continue;
+            }
+            self.0.unused.borrow_mut().put(k, v);
+        }
+        return Ok(());
+    }
+
+    /// Discard saved values, ex: when logging out.
+    pub fn clear_persisted(&self) {
+        if let Some(persist) = &self.0.persist {
+            (persist.clear)();
+        }
+    }
+
+    fn is_stale(&self, k: &K) -> bool {
+        match self.0.fetched.borrow().get(k) {
+            None => return true,
+            Some(at) => match self.0.ttl {
+                Some(ttl) => return Utc::now() - *at > ttl,
+                None => return false,
+            },
+        }
+    }
+
+    /// Fetch a value in the background, unless already being fetched. If the value
+    /// is in use it's updated in place, otherwise it replaces any cached value.
+    /// Waiters get the result either way.
+    fn fetch(&self, k: K) {
+        if !self.0.in_flight.borrow_mut().insert(k.clone()) {
+            return;
+        }
+        match &self.0.get {
+            Getter::One(get) => {
+                let self1 = self.clone();
+                let getter = get(k.clone());
+                spawn_local(async move {
+                    let v = getter.await;
+                    self1.finish_fetch(k, v);
+                });
+            },
+            Getter::Batch { queue, .. } => {
+                let mut queue = queue.borrow_mut();
+                queue.push(k);
+                if queue.len() > 1 {
+                    // Already scheduled
+                    return;
+                }
+
+                // Runs after the current tick, once all of its keys are queued
+                let self1 = self.clone();
+                spawn_local(async move {
+                    let Getter::Batch { get, queue, max } = &self1.0.get else {
+                        unreachable!();
+                    };
+                    let keys = queue.take();
+                    for chunk in keys.chunks(*max) {
+                        match get(chunk.to_vec()).await {
+                            Ok(values) if values.len() == chunk.len() => {
+                                for (k, v) in chunk.iter().zip(values) {
+                                    self1.finish_fetch(k.clone(), v);
+                                }
+                            },
+                            Ok(values) => {
+                                let e =
+                                    format!(
+                                        "Batch fetch returned {} results for {} keys",
+                                        values.len(),
+                                        chunk.len()
+                                    );
+                                for k in chunk {
+                                    self1.finish_fetch(k.clone(), Err(e.clone()));
+                                }
+                            },
+                            Err(e) => {
+                                for k in chunk {
+                                    self1.finish_fetch(k.clone(), Err(e.clone()));
+                                }
+                            },
+                        }
+                    }
+                });
+            },
+        }
+    }
+
+    fn finish_fetch(&self, k: K, v: Result<V, String>) {
+        self.0.in_flight.borrow_mut().remove(&k);
+        if self.0.dirty.borrow_mut().remove(&k) {
+            // The result may predate the change, waiters get the refetched value
+            self.fetch(k);
+            return;
+        }
+        match v {
+            Ok(v) => {
+                self.0.eg.event(|pc| {
+                    self.set(pc, k, v);
+                });
+            },
+            Err(e) => {
+                log!("Error fetching remote value: {}", e);
+                self.resolve(&k, Err(e));
+            },
+        }
+    }
+
+    fn resolve(&self, k: &K, v: Result<Hard<K, V>, String>) {
+        let Some(pending) = self.0.pending.borrow_mut().remove(k) else {
+            return;
+        };
+        for s in pending {
+            // Waiter may have been dropped
+            _ = s.send(v.clone());
+        }
+    }
+
+    pub fn get_immediate(&self, k: &K) -> Option<Hard<K, V>> {
+        let live = self.0.used.borrow().get(&k).map(|v| v.upgrade().unwrap());
+        let out;
+        if let Some(live) = live {
+            out = Hard(live);
+        } else {
+            let found = self.0.unused.borrow_mut().remove(&k);
+            let Some(v) = found else {
+                return None;
+            };
+            out = Hard(Rc::new(Hard_ {
                 noler: Rc::downgrade(&self.0),
                 k: k.clone(),
                 v: Some(v),
             }));
             self.0.used.borrow_mut().insert(k.clone(), Rc::downgrade(&out.0));
-            return Some(out);
         }
-        return None;
+        if self.is_stale(k) {
+            self.fetch(k.clone());
+        }
+        return Some(out);
     }
 
     pub async fn get_async(&self, k: K) -> Result<Hard<K, V>, String> {
@@ -124,7 +350,7 @@ impl<K: NowOrLaterKey, V: NowOrLaterValue> NowOrLaterCollection<K, V> {
             NowOrLater::Later(l) => {
                 // Senders are owned by this, and this can't be dropped while get_async is
                 // operating
-                return Ok(l.await.unwrap());
+                return l.await.unwrap();
             },
         }
     }
@@ -135,36 +361,74 @@ impl<K: NowOrLaterKey, V: NowOrLaterValue> NowOrLaterCollection<K, V> {
         }
         let (send, recv) = channel();
         self.0.pending.borrow_mut().entry(k.clone()).or_default().push(send);
-        if self.0.in_flight.borrow_mut().insert(k.clone()) {
-            let self1 = self.clone();
-            spawn_local(async move {
-                let getter = (self1.0.get)(k.clone());
-                let v = getter.await;
-                match v {
-                    Ok(v) => {
-                        self1.set(k, v);
-                    },
-                    Err(e) => {
-                        self1.0.in_flight.borrow_mut().remove(&k);
-                        log!("Error fetching remote value: {}", e);
-                    },
-                }
-            });
-        }
+        self.fetch(k);
         return NowOrLater::Later(recv);
     }
 
-    pub fn set(&self, k: K, v: V) -> Hard<K, V> {
-        self.0.in_flight.borrow_mut().remove(&k);
-        let out = Hard(Rc::new(Hard_ {
-            noler: Rc::downgrade(&self.0),
-            k: k.clone(),
-            v: Some(v),
-        }));
-        self.0.used.borrow_mut().insert(k.clone(), Rc::downgrade(&out.0));
-        for s in self.0.pending.borrow_mut().remove(&k).unwrap() {
-            s.send(out.clone()).map_err(|_| ()).unwrap();
+    /// Refetch a value now, updating it in place if it's in use. If the value is
+    /// already being fetched it's fetched again once that finishes.
+    pub fn refresh(&self, k: K) {
+        if self.0.in_flight.borrow().contains(&k) {
+            self.0.dirty.borrow_mut().insert(k);
+            return;
+        }
+        self.fetch(k);
+    }
+
+    /// Mark a value as out of date, ex: after a server event that isn't reflected in
+    /// the value. Values in use or being fetched are refetched, others are dropped
+    /// and fetched on the next `get`.
+    pub fn invalidate(&self, k: &K) {
+        self.0.fetched.borrow_mut().remove(k);
+        if self.0.in_flight.borrow().contains(k) {
+            self.0.dirty.borrow_mut().insert(k.clone());
+            return;
+        }
+        let live = self.0.used.borrow().get(k).and_then(|v| v.upgrade()).is_some();
+        if live {
+            self.fetch(k.clone());
+        } else {
+            self.0.unused.borrow_mut().remove(k);
+        }
+    }
+
+    /// Discard cached values that aren't currently in use.
+    pub fn clear_unused(&self) {
+        self.0.unused.borrow_mut().purge();
+    }
+
+    /// Store a value, resolving any waiters. If the value is in use it's updated in
+    /// place so existing users see the change.
+    pub fn set(&self, pc: &mut ProcessingContext, k: K, v: V) -> Hard<K, V> {
+        self.0.fetched.borrow_mut().insert(k.clone(), Utc::now());
+
+        // A fetch sent before this may return older data
+        if self.0.in_flight.borrow().contains(&k) {
+            self.0.dirty.borrow_mut().insert(k.clone());
         }
+        let live = self.0.used.borrow().get(&k).and_then(|v| v.upgrade());
+        let out = match live {
+            Some(live) => {
+                (self.0.refresh)(pc, live.v.as_ref().unwrap(), v);
+                Hard(live)
+            },
+            None => {
+                self.0.unused.borrow_mut().remove(&k);
+                let out = Hard(Rc::new(Hard_ {
+                    noler: Rc::downgrade(&self.0),
+                    k: k.clone(),
+                    v: Some(v),
+                }));
+                self.0.used.borrow_mut().insert(k.clone(), Rc::downgrade(&out.0));
+                out
+            },
+        };
+        if let Some(persist) = &self.0.persist {
+            (persist.save)(&k, &out);
+        }
+
+        // No pending requests if the value was created locally
+        self.resolve(&k, Ok(out.clone()));
         return out;
     }
 }
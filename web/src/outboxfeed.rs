@@ -2,21 +2,28 @@ use std::{
     cell::RefCell,
     rc::{
         Rc,
+        Weak,
     },
+    collections::HashMap,
 };
 use chrono::{
     Utc,
 };
-use gloo::utils::format::JsValueSerdeExt;
 use indexed_db_futures::{
     IdbQuerySource,
     IdbDatabase,
 };
 use lunk::{
+    link,
+    Prim,
     ProcessingContext,
     EventGraph,
 };
-use wasm_bindgen::JsValue;
+use rooting::{
+    el,
+    El,
+};
+use wasm_bindgen::JsCast;
 use crate::{
     infiniscroll::{
         Entry,
@@ -27,36 +34,66 @@ use crate::{
         bg,
         MyErrorDomException,
     },
-    enum_unwrap,
-    world::{
-        ChannelId,
-        FeedId,
+    html::{
+        button,
+        hbox,
+        space,
+        vbox,
+        ElExt,
     },
+    world::FeedId,
+    interface::u2s::ChannelId,
     dbmodel::{
         TABLE_OUTBOX,
         OutboxEntry,
-        TABLE_OUTBOX_INDEX_STAMP,
+        OutboxEntryV2,
+        TABLE_OUTBOX_INDEX_CHANNEL_STAMP,
         from_outbox,
+        get_outbox,
+        put_outbox,
         outbox_key,
+        outbox_channel_stamp_range,
     },
     bb,
-    scrollentry::{
-        FeedEntry,
-        EntryMap,
-        FeedTime,
-    },
+    scrollentry::FeedTime,
 };
 use web_sys::{
+    HtmlInputElement,
     IdbCursorDirection,
-    IdbKeyRange,
 };
 
+/// Delivery state of a message in the outbox.
+#[derive(Clone, PartialEq)]
+pub enum OutboxStatus {
+    Queued,
+    Sending,
+    /// Refused by the server, with the reason
+    Failed(String),
+    Delivered,
+}
+
+impl OutboxStatus {
+    fn of(e: &OutboxEntryV2) -> OutboxStatus {
+        return match (&e.resolved_id, &e.error) {
+            (Some(_), _) => OutboxStatus::Delivered,
+            (None, Some(error)) => OutboxStatus::Failed(error.clone()),
+            (None, None) => OutboxStatus::Queued,
+        };
+    }
+}
+
 struct OutboxFeedMut {
     parent: Option<WeakInfiniscroll<Option<ChannelId>, FeedTime>>,
+    /// Live entries by local id, for showing status changes
+    entries: HashMap<String, Weak<OutboxFeedEntry_>>,
 }
 
 struct OutboxFeed_ {
     db: Rc<IdbDatabase>,
+    /// Only entries in these channels are shown
+    channels: Vec<ChannelId>,
+    /// Called when a failed message is queued to be sent again
+    on_retry: Box<dyn Fn()>,
     mut_: RefCell<OutboxFeedMut>,
 }
 
@@ -64,14 +101,30 @@ struct OutboxFeed_ {
 pub struct OutboxFeed(Rc<OutboxFeed_>);
 
 impl OutboxFeed {
-    pub fn new(db: Rc<IdbDatabase>) -> OutboxFeed {
+    pub fn new(db: Rc<IdbDatabase>, channels: Vec<ChannelId>, on_retry: impl Fn() + 'static) -> OutboxFeed {
         return OutboxFeed(Rc::new(OutboxFeed_ {
             db: db,
-            mut_: RefCell::new(OutboxFeedMut { parent: None }),
+            channels: channels,
+            on_retry: Box::new(on_retry),
+            mut_: RefCell::new(OutboxFeedMut {
+                parent: None,
+                entries: HashMap::new(),
+            }),
         }));
     }
 
+    /// Update the status shown on an entry, if it's loaded.
+    pub fn set_status(&self, pc: &mut ProcessingContext, local_id: &str, status: OutboxStatus) {
+        let Some(entry) = self.0.mut_.borrow().entries.get(local_id).and_then(|e| e.upgrade()) else {
+            return;
+        };
+        entry.status.set(pc, status);
+    }
+
     pub fn notify(&self, eg: EventGraph, channel: ChannelId, id: String) {
+        if !self.0.channels.contains(&channel) {
+            return;
+        }
         let pivot;
         let count;
         {
@@ -92,115 +145,330 @@ impl OutboxFeed {
     }
 }
 
-fn finish_entries(pc: &mut ProcessingContext, v: Vec<OutboxEntry>) -> Vec<Rc<dyn Entry<FeedTime>>> {
-    return v.into_iter().map(|e| match e {
-        OutboxEntry::V1(e) => Rc::new(FeedEntry::new(pc, FeedTime {
-            stamp: e.stamp,
-            id: match e.resolved_id {
-                Some(id) => FeedId::Real(id),
-                None => FeedId::Local(e.channel, e.local_id),
-            },
-        }, e.body, &EntryMap::new())) as Rc<dyn Entry<FeedTime>>,
-    }).collect();
+struct OutboxFeedEntry_ {
+    feed: Weak<OutboxFeed_>,
+    time: FeedTime,
+    local_id: String,
+    body: Prim<String>,
+    status: Prim<OutboxStatus>,
+    editing: Prim<bool>,
 }
 
-impl Feed<Option<ChannelId>, FeedTime> for OutboxFeed {
-    fn set_parent(&self, parent: crate::infiniscroll::WeakInfiniscroll<Option<ChannelId>, FeedTime>) {
-        self.0.mut_.borrow_mut().parent = Some(parent);
+impl Drop for OutboxFeedEntry_ {
+    fn drop(&mut self) {
+        let Some(feed) = self.feed.upgrade() else {
+            return;
+        };
+        let mut mut_ = feed.mut_.borrow_mut();
+
+        // May have been replaced by a newer copy of the same entry
+        if mut_.entries.get(&self.local_id).map(|e| e.strong_count() == 0).unwrap_or(false) {
+            mut_.entries.remove(&self.local_id);
+        }
     }
+}
 
-    fn request_around(&self, eg: EventGraph, time: FeedTime, count: usize) {
-        bg("Outbox feed, request around", {
-            let self1 = self.clone();
-            async move {
-                let txn =
-                    self1
-                        .0
-                        .db
-                        .transaction_on_multi_with_mode(&[TABLE_OUTBOX], web_sys::IdbTransactionMode::Readonly)
-                        .context("Failed to start transaction")?;
-                let outbox = txn.object_store(TABLE_OUTBOX).context("Failed to get outbox")?;
-                let time_index = outbox.index(TABLE_OUTBOX_INDEX_STAMP).context("Failed to get outbox stamp index")?;
+struct OutboxFeedEntry(Rc<OutboxFeedEntry_>);
+
+impl OutboxFeedEntry {
+    /// Queue a failed message to be sent again.
+    fn retry(&self, eg: EventGraph) {
+        let self1 = self.0.clone();
+        bg("Outbox feed, retrying message", async move {
+            let Some(feed) = self1.feed.upgrade() else {
+                return Ok(());
+            };
+            let txn =
+                feed
+                    .db
+                    .transaction_on_one_with_mode(TABLE_OUTBOX, web_sys::IdbTransactionMode::Readwrite)
+                    .context("Failed to start transaction")?;
+            let outbox = txn.object_store(TABLE_OUTBOX).context("Failed to get outbox")?;
+            let Some(mut e) = get_outbox(&outbox, &self1.local_id).await? else {
+                return Ok(());
+            };
+            if e.resolved_id.is_some() {
+                return Ok(());
+            }
+            e.error = None;
+            put_outbox(&outbox, OutboxEntry::V2(e)).await?;
+            txn.await.into_result().context("Failed to commit transaction")?;
+            eg.event(|pc| self1.status.set(pc, OutboxStatus::Queued));
+            (feed.on_retry)();
+            return Ok(());
+        });
+    }
 
-                // Get elements before pivot
-                let mut early_stop = true;
-                let mut before = vec![];
+    /// Replace the body of a message that hasn't been sent yet.
+    fn edit(&self, eg: EventGraph, body: String) {
+        let self1 = self.0.clone();
+        bg("Outbox feed, editing message", async move {
+            let Some(feed) = self1.feed.upgrade() else {
+                return Ok(());
+            };
+            let txn =
+                feed
+                    .db
+                    .transaction_on_one_with_mode(TABLE_OUTBOX, web_sys::IdbTransactionMode::Readwrite)
+                    .context("Failed to start transaction")?;
+            let outbox = txn.object_store(TABLE_OUTBOX).context("Failed to get outbox")?;
+            let Some(mut e) = get_outbox(&outbox, &self1.local_id).await? else {
+                return Ok(());
+            };
+            if e.resolved_id.is_some() {
+                return Err(format!("Message [{}] was already sent", self1.local_id));
+            }
+            e.body = body.clone();
+            put_outbox(&outbox, OutboxEntry::V2(e)).await?;
+            txn.await.into_result().context("Failed to commit transaction")?;
+            eg.event(|pc| {
+                self1.body.set(pc, body);
+                self1.editing.set(pc, false);
+            });
+            return Ok(());
+        });
+    }
 
-                bb!{
-                    'read_done _;
-                    let Some(
-                        cursor
-                    ) = time_index.open_cursor_with_range_and_direction(
-                        &IdbKeyRange::upper_bound_with_open(
-                            &<JsValue as JsValueSerdeExt>::from_serde(&time).unwrap(),
-                            true,
-                        ).unwrap(),
-                        IdbCursorDirection::Prev
-                    ).context("Failed to open outbox cursor") ?.await.context("Error waiting for cursor") ? else {
-                        break 'read_done;
-                    };
-                    loop {
-                        if before.len() >= count {
-                            early_stop = false;
-                            break 'read_done;
+    /// Delete a message that hasn't been sent yet from the outbox and the view.
+    fn cancel(&self) {
+        let self1 = self.0.clone();
+        bg("Outbox feed, cancelling message", async move {
+            let Some(feed) = self1.feed.upgrade() else {
+                return Ok(());
+            };
+            let txn =
+                feed
+                    .db
+                    .transaction_on_one_with_mode(TABLE_OUTBOX, web_sys::IdbTransactionMode::Readwrite)
+                    .context("Failed to start transaction")?;
+            let outbox = txn.object_store(TABLE_OUTBOX).context("Failed to get outbox")?;
+            if let Some(e) = get_outbox(&outbox, &self1.local_id).await? {
+                if e.resolved_id.is_some() {
+                    return Err(format!("Message [{}] was already sent", self1.local_id));
+                }
+                outbox
+                    .delete(&outbox_key(&self1.local_id))
+                    .context("Failed to initiate outbox entry deletion")?
+                    .await
+                    .context("Failed to delete outbox entry")?;
+            }
+            txn.await.into_result().context("Failed to commit transaction")?;
+            let parent = feed.mut_.borrow().parent.as_ref().and_then(|p| p.upgrade());
+            if let Some(parent) = parent {
+                parent.remove_entry(&None, &self1.time);
+            }
+            return Ok(());
+        });
+    }
+}
+
+impl Entry<FeedTime> for OutboxFeedEntry {
+    fn create_el(&self, pc: &mut ProcessingContext) -> El {
+        let status = el("span").classes(&["status"]);
+        let actions = hbox().classes(&["actions"]);
+        return vbox().classes(&["message", "outbox"]).extend(vec![
+            //. .
+            el("span").text(&self.0.time.stamp.to_rfc3339()),
+            el("span").bind_text(pc, &self.0.body),
+            status.own(|status| link!((_pc = pc), (s = self.0.status.clone()), (), (status = status.weak()) {
+                let status = status.upgrade()?;
+                let (text, class) = match &*s.borrow() {
+                    OutboxStatus::Queued => ("Queued".to_string(), "queued"),
+                    OutboxStatus::Sending => ("Sending...".to_string(), "sending"),
+                    OutboxStatus::Failed(e) => (format!("Failed: {}", e), "failed"),
+                    OutboxStatus::Delivered => ("Delivered".to_string(), "delivered"),
+                };
+                status.ref_text(&text);
+                status.ref_modify_classes(
+                    &[
+                        ("queued", class == "queued"),
+                        ("sending", class == "sending"),
+                        ("failed", class == "failed"),
+                        ("delivered", class == "delivered"),
+                    ],
+                );
+            })),
+            actions.own(
+                |actions| link!(
+                    (pc = pc),
+                    (s = self.0.status.clone(), editing = self.0.editing.clone()),
+                    (),
+                    (actions = actions.weak(), entry = self.0.clone()) {
+                        let actions = actions.upgrade()?;
+                        actions.ref_clear();
+                        let entry = OutboxFeedEntry(entry.clone());
+                        let unsent = match &*s.borrow() {
+                            OutboxStatus::Queued | OutboxStatus::Failed(_) => true,
+                            OutboxStatus::Sending | OutboxStatus::Delivered => false,
+                        };
+                        if !unsent {
+                            return None;
                         }
-                        if !cursor
-                            .continue_cursor()
-                            .context("Error moving cursor forward")?
-                            .await
-                            .context("Error retrieving cursor advance result")? {
-                            break 'read_done;
+                        if *editing.borrow() {
+                            let input = el("input").attr("type", "text");
+                            input.ref_attr("value", &*entry.0.body.borrow());
+                            actions.ref_extend(vec![input.clone(), space(), button({
+                                let editing = editing.clone();
+                                let eg = pc.eg();
+                                move || eg.event(|pc| editing.set(pc, false))
+                            }).push(el("span").text("Discard")), button({
+                                let entry = OutboxFeedEntry(entry.0.clone());
+                                let eg = pc.eg();
+                                move || {
+                                    let body = input.raw().dyn_into::<HtmlInputElement>().unwrap().value();
+                                    entry.edit(eg.clone(), body);
+                                }
+                            }).push(el("span").text("Save"))]);
+                            return None;
+                        }
+                        let mut buttons = vec![space()];
+                        if let OutboxStatus::Failed(_) = &*s.borrow() {
+                            buttons.push(button({
+                                let entry = OutboxFeedEntry(entry.0.clone());
+                                let eg = pc.eg();
+                                move || entry.retry(eg.clone())
+                            }).push(el("span").text("Retry")));
                         }
-                        before.push(from_outbox(&cursor.value()));
+                        buttons.push(button({
+                            let editing = editing.clone();
+                            let eg = pc.eg();
+                            move || eg.event(|pc| editing.set(pc, true))
+                        }).push(el("span").text("Edit")));
+                        buttons.push(button({
+                            let entry = OutboxFeedEntry(entry.0.clone());
+                            move || entry.cancel()
+                        }).push(el("span").text("Cancel")));
+                        actions.ref_extend(buttons);
                     }
-                }
+                ),
+            )
+        ]);
+    }
 
-                before.reverse();
+    fn time(&self) -> FeedTime {
+        return self.0.time.clone();
+    }
+}
 
-                // Get elements including and after pivot
-                let mut late_stop = true;
-                let mut after_including: Vec<OutboxEntry> = vec![];
+fn entry_time(e: &OutboxEntryV2) -> FeedTime {
+    return FeedTime {
+        stamp: e.stamp,
+        id: match &e.resolved_id {
+            Some(id) => FeedId::Real(id.clone()),
+            None => FeedId::Local(e.channel.clone(), e.local_id.clone()),
+        },
+    };
+}
+
+impl OutboxFeed {
+    fn finish_entries(&self, pc: &mut ProcessingContext, v: Vec<OutboxEntryV2>) -> Vec<Rc<dyn Entry<FeedTime>>> {
+        let mut mut_ = self.0.mut_.borrow_mut();
+        return v.into_iter().map(|e| {
+            let entry = Rc::new(OutboxFeedEntry_ {
+                feed: Rc::downgrade(&self.0),
+                time: entry_time(&e),
+                local_id: e.local_id.clone(),
+                status: Prim::new(pc, OutboxStatus::of(&e)),
+                body: Prim::new(pc, e.body),
+                editing: Prim::new(pc, false),
+            });
+            mut_.entries.insert(entry.local_id.clone(), Rc::downgrade(&entry));
+            Rc::new(OutboxFeedEntry(entry)) as Rc<dyn Entry<FeedTime>>
+        }).collect();
+    }
 
-                bb!{
-                    'read_done _;
-                    let Some(
-                        cursor
-                    ) = time_index.open_cursor_with_range_and_direction(
-                        &IdbKeyRange::lower_bound(
-                            &<JsValue as JsValueSerdeExt>::from_serde(&time.stamp).unwrap(),
-                        ).unwrap(),
+    /// Read up to `count` entries of the visible channels starting at `time` and going
+    /// back (`early`) or forward, nearest first. Entries are skipped unless `keep`
+    /// returns true. Also returns true if there are no more entries in that direction.
+    async fn read_from(
+        &self,
+        time: &FeedTime,
+        early: bool,
+        count: usize,
+        keep: impl Fn(&FeedTime) -> bool,
+    ) -> Result<(Vec<OutboxEntryV2>, bool), String> {
+        let txn =
+            self
+                .0
+                .db
+                .transaction_on_one_with_mode(TABLE_OUTBOX, web_sys::IdbTransactionMode::Readonly)
+                .context("Failed to start transaction")?;
+        let outbox = txn.object_store(TABLE_OUTBOX).context("Failed to get outbox")?;
+        let index =
+            outbox.index(TABLE_OUTBOX_INDEX_CHANNEL_STAMP).context("Failed to get outbox channel stamp index")?;
+        let mut out = vec![];
+        let mut stop = true;
+        for channel in &self.0.channels {
+            let mut found = 0;
+            bb!{
+                'read_done _;
+                let Some(
+                    cursor
+                ) = index.open_cursor_with_range_and_direction(
+                    &outbox_channel_stamp_range(channel, &time.stamp, early),
+                    if early {
+                        IdbCursorDirection::Prev
+                    } else {
                         IdbCursorDirection::Next
-                    ).context("Failed to open outbox cursor") ?.await.context("Error waiting for cursor") ? else {
-                        break 'read_done;
-                    };
-                    loop {
-                        if after_including.len() >= count + 1 {
-                            late_stop = false;
-                            break 'read_done;
-                        }
-                        if !cursor
-                            .continue_cursor()
-                            .context("Error moving cursor forward")?
-                            .await
-                            .context("Error retrieving cursor advance result")? {
+                    }
+                ).context("Failed to open outbox cursor") ?.await.context("Error waiting for cursor") ? else {
+                    break 'read_done;
+                };
+                loop {
+                    let e = from_outbox(&cursor.value()).upgrade();
+                    if keep(&entry_time(&e)) {
+                        if found >= count {
+                            stop = false;
                             break 'read_done;
                         }
-                        after_including.push(from_outbox(&cursor.value()));
+                        found += 1;
+                        out.push(e);
+                    }
+                    if !cursor
+                        .continue_cursor()
+                        .context("Error moving cursor forward")?
+                        .await
+                        .context("Error retrieving cursor advance result")? {
+                        break 'read_done;
                     }
                 }
+            }
+        }
+        txn.await.into_result().context("Failed to commit transaction")?;
 
-                // Finish read
-                txn.await.into_result().context("Failed to commit transaction")?;
+        // Merge channels
+        out.sort_by_key(entry_time);
+        if early {
+            out.reverse();
+        }
+        if out.len() > count {
+            out.truncate(count);
+            stop = false;
+        }
+        return Ok((out, stop));
+    }
+}
+
+impl Feed<Option<ChannelId>, FeedTime> for OutboxFeed {
+    fn set_parent(&self, parent: crate::infiniscroll::WeakInfiniscroll<Option<ChannelId>, FeedTime>) {
+        self.0.mut_.borrow_mut().parent = Some(parent);
+    }
 
-                // Combine and send
+    fn request_around(&self, eg: EventGraph, time: FeedTime, count: usize) {
+        bg("Outbox feed, request around", {
+            let self1 = self.clone();
+            async move {
+                let (mut before, early_stop) = self1.read_from(&time, true, count, |t| t < &time).await?;
+                before.reverse();
+                let (after_including, late_stop) = self1.read_from(&time, false, count, |t| t >= &time).await?;
                 let mut all = before;
                 all.extend(after_including);
                 eg.event(|pc| {
-                    let mut_ = self1.0.mut_.borrow();
-                    let Some(parent) = mut_.parent.as_ref().and_then(|p| p.upgrade()) else {
+                    let parent = self1.0.mut_.borrow().parent.as_ref().and_then(|p| p.upgrade());
+                    let Some(parent) = parent else {
                         return;
                     };
-                    parent.respond_entries_around(None, time, finish_entries(pc, all), early_stop, late_stop);
+                    parent.respond_entries_around(None, time, self1.finish_entries(pc, all), early_stop, late_stop);
                 });
                 return Ok(());
             }
@@ -211,57 +479,14 @@ impl Feed<Option<ChannelId>, FeedTime> for OutboxFeed {
         bg("Outbox feed, request before", {
             let self1 = self.clone();
             async move {
-                let txn =
-                    self1
-                        .0
-                        .db
-                        .transaction_on_multi_with_mode(&[TABLE_OUTBOX], web_sys::IdbTransactionMode::Readonly)
-                        .context("Failed to start transaction")?;
-                let outbox = txn.object_store(TABLE_OUTBOX).context("Failed to get outbox")?;
-
-                // Get entries
-                let mut early_stop = true;
-                let mut before = vec![];
-
-                bb!{
-                    'read_done _;
-                    let Some(
-                        cursor
-                    ) = outbox.open_cursor_with_range_and_direction(
-                        &IdbKeyRange::upper_bound_with_open(
-                            &outbox_key(enum_unwrap!(&time.id, FeedId:: Local(_, id) => id)),
-                            true,
-                        ).unwrap(),
-                        IdbCursorDirection::Prev
-                    ).context("Failed to open outbox cursor") ?.await.context("Error waiting for cursor") ? else {
-                        break 'read_done;
-                    };
-                    loop {
-                        if before.len() >= count {
-                            early_stop = false;
-                            break 'read_done;
-                        }
-                        if !cursor
-                            .continue_cursor()
-                            .context("Error moving cursor forward")?
-                            .await
-                            .context("Error retrieving cursor advance result")? {
-                            break 'read_done;
-                        }
-                        before.push(from_outbox(&cursor.value()));
-                    }
-                }
-
-                // Finish read
-                txn.await.into_result().context("Failed to commit transaction")?;
-
-                // Combine and send
+                let (mut before, early_stop) = self1.read_from(&time, true, count, |t| t < &time).await?;
+                before.reverse();
                 eg.event(|pc| {
-                    let mut_ = self1.0.mut_.borrow();
-                    let Some(parent) = mut_.parent.as_ref().and_then(|p| p.upgrade()) else {
+                    let parent = self1.0.mut_.borrow().parent.as_ref().and_then(|p| p.upgrade());
+                    let Some(parent) = parent else {
                         return;
                     };
-                    parent.respond_entries_before(&None, &time, finish_entries(pc, before), early_stop);
+                    parent.respond_entries_before(&None, &time, self1.finish_entries(pc, before), early_stop);
                 });
                 return Ok(());
             }
@@ -272,57 +497,13 @@ impl Feed<Option<ChannelId>, FeedTime> for OutboxFeed {
         bg("Outbox feed, request after", {
             let self1 = self.clone();
             async move {
-                let txn =
-                    self1
-                        .0
-                        .db
-                        .transaction_on_multi_with_mode(&[TABLE_OUTBOX], web_sys::IdbTransactionMode::Readonly)
-                        .context("Failed to start transaction")?;
-                let outbox = txn.object_store(TABLE_OUTBOX).context("Failed to get outbox")?;
-
-                // Get entries
-                let mut late_stop = true;
-                let mut after: Vec<OutboxEntry> = vec![];
-
-                bb!{
-                    'read_done _;
-                    let Some(
-                        cursor
-                    ) = outbox.open_cursor_with_range_and_direction(
-                        &IdbKeyRange::lower_bound_with_open(
-                            &outbox_key(enum_unwrap!(&time.id, FeedId:: Local(_, id) => id)),
-                            true,
-                        ).unwrap(),
-                        IdbCursorDirection::Next
-                    ).context("Failed to open outbox cursor") ?.await.context("Error waiting for cursor") ? else {
-                        break 'read_done;
-                    };
-                    loop {
-                        if after.len() >= count + 1 {
-                            late_stop = false;
-                            break 'read_done;
-                        }
-                        if !cursor
-                            .continue_cursor()
-                            .context("Error moving cursor forward")?
-                            .await
-                            .context("Error retrieving cursor advance result")? {
-                            break 'read_done;
-                        }
-                        after.push(from_outbox(&cursor.value()));
-                    }
-                }
-
-                // Finish read
-                txn.await.into_result().context("Failed to commit transaction")?;
-
-                // Combine and send
+                let (after, late_stop) = self1.read_from(&time, false, count, |t| t > &time).await?;
                 eg.event(|pc| {
-                    let mut_ = self1.0.mut_.borrow();
-                    let Some(parent) = mut_.parent.as_ref().and_then(|p| p.upgrade()) else {
+                    let parent = self1.0.mut_.borrow().parent.as_ref().and_then(|p| p.upgrade());
+                    let Some(parent) = parent else {
                         return;
                     };
-                    parent.respond_entries_after(&None, &time, finish_entries(pc, after), late_stop);
+                    parent.respond_entries_after(&None, &time, self1.finish_entries(pc, after), late_stop);
                 });
                 return Ok(());
             }
@@ -11,6 +11,7 @@ use chrono::{
     DateTime,
 };
 use lunk::{
+    link,
     Prim,
     ProcessingContext,
 };
@@ -27,12 +28,14 @@ use crate::{
         Entry,
     },
     html::{
+        group,
         vbox,
         ElExt,
     },
     world::{
         FeedId,
     },
+    interface::u2s::IdentityId,
 };
 
 #[derive(Debug, Hash, PartialEq, Eq, PartialOrd, Ord, Clone, Serialize, Deserialize)]
@@ -41,49 +44,104 @@ pub struct FeedTime {
     pub id: FeedId,
 }
 
-pub struct EntryMap(pub Rc<RefCell<HashMap<FeedId, FeedEntry>>>);
+/// Live entries by id, for applying updates to entries that have already been
+/// loaded.
+pub struct EntryMap(pub Rc<RefCell<HashMap<FeedId, Weak<MessageFeedEntry_>>>>);
 
 impl EntryMap {
     pub fn new() -> Self {
         return Self(Rc::new(RefCell::new(HashMap::new())));
     }
+
+    pub fn get(&self, id: &FeedId) -> Option<Rc<MessageFeedEntry_>> {
+        return self.0.borrow().get(id).and_then(|e| e.upgrade());
+    }
+}
+
+/// Builds the author header (name, avatar, etc) shown above messages.
+pub type AuthorRenderer = Rc<dyn Fn(&mut ProcessingContext, &IdentityId) -> El>;
+
+#[derive(Clone)]
+pub struct Author {
+    pub id: IdentityId,
+    pub render: AuthorRenderer,
 }
 
 pub struct MessageFeedEntry_ {
-    pub entry_map: Weak<RefCell<HashMap<FeedId, FeedEntry>>>,
+    pub entry_map: Weak<RefCell<HashMap<FeedId, Weak<MessageFeedEntry_>>>>,
     pub id: FeedTime,
+    pub author: Option<Author>,
     pub text: Prim<String>,
+    /// First message after the read position, shown with a "new messages" divider.
+    /// Can be set after the entry is shown, once the read position is known.
+    pub unread_start: Prim<bool>,
 }
 
 pub struct FeedEntry(pub Rc<MessageFeedEntry_>);
 
 impl FeedEntry {
-    pub fn new(pc: &mut ProcessingContext, id: FeedTime, text: String, map: &EntryMap) -> Self {
-        return FeedEntry(Rc::new(MessageFeedEntry_ {
+    pub fn new(
+        pc: &mut ProcessingContext,
+        id: FeedTime,
+        author: Option<Author>,
+        text: String,
+        unread_start: bool,
+        map: &EntryMap,
+    ) -> Self {
+        let entry = Rc::new(MessageFeedEntry_ {
             entry_map: Rc::downgrade(&map.0),
             id: id,
+            author: author,
             text: Prim::new(pc, text),
-        }));
+            unread_start: Prim::new(pc, unread_start),
+        });
+        map.0.borrow_mut().insert(entry.id.id.clone(), Rc::downgrade(&entry));
+        return FeedEntry(entry);
     }
 }
 
 impl Entry<FeedTime> for FeedEntry {
     fn create_el(&self, pc: &mut ProcessingContext) -> El {
-        return vbox().extend(
-            vec![el("span").text(&self.0.id.stamp.to_rfc3339()), el("span").bind_text(pc, &self.0.text)],
-        );
+        let mut children = vec![];
+        children.push(group().own(|divider| link!(
+            (_pc = pc),
+            (unread_start = self.0.unread_start.clone()),
+            (),
+            (divider = divider.weak()) {
+                let divider = divider.upgrade()?;
+                divider.ref_clear();
+                if *unread_start.borrow() {
+                    divider.ref_push(el("div").classes(&["unread_divider"]).text("New messages"));
+                }
+            }
+        )));
+        if let Some(author) = &self.0.author {
+            children.push((author.render)(pc, &author.id).classes(&["author"]));
+        }
+        children.push(el("span").text(&self.0.id.stamp.to_rfc3339()));
+        children.push(el("span").bind_text(pc, &self.0.text));
+        return vbox().classes(&["message"]).extend(children);
     }
 
     fn time(&self) -> FeedTime {
         return self.0.id.clone();
     }
+
+    fn group_key(&self) -> Option<String> {
+        return self.0.author.as_ref().map(|a| a.id.0.clone());
+    }
 }
 
-impl Drop for FeedEntry {
+impl Drop for MessageFeedEntry_ {
     fn drop(&mut self) {
-        let Some(map) = self.0.entry_map.upgrade() else {
+        let Some(map) = self.entry_map.upgrade() else {
             return;
         };
-        map.borrow_mut().remove(&self.0.id.id);
+        let mut map = map.borrow_mut();
+
+        // May have been replaced by a newer copy of the same entry
+        if map.get(&self.id.id).map(|e| e.strong_count() == 0).unwrap_or(false) {
+            map.remove(&self.id.id);
+        }
     }
 }
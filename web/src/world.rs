@@ -1,23 +1,25 @@
-use chrono::{
-    DateTime,
-    Utc,
+use std::{
+    cell::RefCell,
+    fmt::Display,
+    rc::Rc,
 };
+use futures::channel::oneshot;
 use gloo::utils::window;
 use reqwasm::http::Request;
 use serde::{
-    de::DeserializeOwned,
     Serialize,
     Deserialize,
 };
-
-#[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize, PartialOrd, Ord, Hash)]
-pub struct IdentityId(pub String);
-
-#[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize, PartialOrd, Ord, Hash)]
-pub struct ChannelId(pub IdentityId, pub u16);
-
-#[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize, PartialOrd, Ord, Hash)]
-pub struct MessageId(pub ChannelId, pub u64);
+use crate::interface::u2s::{
+    ChannelId,
+    MessageId,
+    U2SAny,
+    U2SAuth,
+    U2SGet,
+    U2SLogout,
+    U2SPost,
+    U2SReq,
+};
 
 /// Not sent over wire
 #[derive(Debug, Hash, PartialEq, Eq, PartialOrd, Ord, Clone, Serialize, Deserialize)]
@@ -27,141 +29,78 @@ pub enum FeedId {
     Real(MessageId),
 }
 
-#[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize, PartialOrd, Ord, Hash)]
-pub struct DateMessageId(pub DateTime<Utc>, pub MessageId);
-
-#[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize, PartialOrd, Ord, Hash)]
-pub struct BrewId(pub usize);
-
-#[derive(Serialize, Deserialize)]
-pub struct S2SWPush {
-    pub id: MessageId,
-    pub time: DateTime<Utc>,
-    pub title: String,
-    pub quote: String,
-    pub icon_url: String,
-}
-
-#[derive(Serialize, Deserialize)]
-pub enum U2SPost {
-    // Json
-    SubscribePush(String),
-    Auth {
-        username: String,
-        password: String,
-    },
-    ChannelCreate {
-        name: String,
-    },
-    ChannelJoin {
-        name: String,
-        id: ChannelId,
-    },
-    Send {
-        channel: ChannelId,
-        reply: Option<MessageId>,
-        local_id: String,
-        body: String,
-    },
-}
-
-#[derive(Serialize, Deserialize)]
-pub enum U2SGet {
-    GetPushPubKey,
-    GetBrew(BrewId),
-    GetChannel(ChannelId),
-    GetIdentity(IdentityId),
-    GetChannels,
-    GetBrews,
-    GetOwnIdentities,
-    EventsGetAfter {
-        id: Option<MessageId>,
-        count: u64,
-    },
-    SnapGetAround {
-        channel: ChannelId,
-        time: DateTime<Utc>,
-        count: u64,
-    },
-    SnapGetBefore {
-        id: MessageId,
-        count: u64,
-    },
-    SnapGetAfter {
-        id: MessageId,
-        count: u64,
-    },
-}
-
-#[derive(Serialize, Deserialize)]
-pub struct S2UChannel {
-    pub id: ChannelId,
-    pub name: String,
-}
-
-#[derive(Serialize, Deserialize)]
-pub struct S2UBrew {
-    pub id: BrewId,
-    pub name: String,
-    pub channels: Vec<ChannelId>,
-}
-
 #[derive(Serialize, Deserialize)]
-pub struct S2UMessage {
-    pub id: MessageId,
-    pub time: DateTime<Utc>,
-    pub text: String,
+pub enum U2SWPost {
+    Ping,
 }
 
-#[derive(Serialize, Deserialize)]
-pub struct S2UEventsGetAfterResp {
-    pub server_time: MessageId,
-    pub entries: Vec<S2UMessage>,
+enum ReqError {
+    /// The server rejected the request because there's no valid session.
+    Unauthorized(String),
+    /// The server refused the request for some other reason (4xx).
+    Rejected(String),
+    Other(String),
 }
 
-#[derive(Serialize, Deserialize)]
-pub struct S2USnapGetAroundResp {
-    pub server_time: MessageId,
-    pub entries: Vec<S2UMessage>,
-    pub early_stop: bool,
-    pub late_stop: bool,
+/// Why a request failed, for callers that retry failed requests.
+#[derive(Debug)]
+pub enum ApiError {
+    /// The request may succeed if retried later: network errors, server errors.
+    Transient(String),
+    /// The server refused the request, retrying won't help.
+    Rejected(String),
 }
 
-#[derive(Serialize, Deserialize)]
-pub struct S2UGetBeforeResp {
-    pub server_time: MessageId,
-    pub entries: Vec<S2UMessage>,
-    pub early_stop: bool,
+impl ApiError {
+    pub fn message(self) -> String {
+        match self {
+            ApiError::Transient(e) | ApiError::Rejected(e) => return e,
+        }
+    }
 }
 
-#[derive(Serialize, Deserialize)]
-pub struct S2UGetAfterResp {
-    pub server_time: MessageId,
-    pub entries: Vec<S2UMessage>,
-    pub late_stop: bool,
+impl Display for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ApiError::Transient(e) | ApiError::Rejected(e) => return e.fmt(f),
+        }
+    }
 }
 
-#[derive(Serialize, Deserialize)]
-pub enum U2SWPost {
-    Ping,
+impl From<ApiError> for String {
+    fn from(value: ApiError) -> Self {
+        return value.message();
+    }
 }
 
-async fn send_req(req: Request) -> Result<Vec<u8>, String> {
+async fn send_req(req: Request) -> Result<Vec<u8>, ReqError> {
     let resp = match req.send().await {
         Ok(r) => r,
         Err(e) => {
-            return Err(format!("Failed to send request: {}", e));
+            return Err(ReqError::Other(format!("Failed to send request: {}", e)));
         },
     };
     let status = resp.status();
     let body = match resp.binary().await {
         Err(e) => {
-            return Err(format!("Got error response, got additional error trying to read body [{}]: {}", status, e));
+            return Err(
+                ReqError::Other(
+                    format!("Got error response, got additional error trying to read body [{}]: {}", status, e),
+                ),
+            );
         },
         Ok(r) => r,
     };
     if status >= 400 {
-        return Err(format!("Got error response [{}]: [{}]", status, String::from_utf8_lossy(&body)));
+        let message = format!("Got error response [{}]: [{}]", status, String::from_utf8_lossy(&body));
+        if status == 401 {
+            return Err(ReqError::Unauthorized(message));
+        }
+        // Timeouts and rate limiting are worth retrying
+        if status < 500 && status != 408 && status != 429 {
+            return Err(ReqError::Rejected(message));
+        }
+        return Err(ReqError::Other(message));
     }
     return Ok(body);
 }
@@ -170,39 +109,108 @@ fn req_get_url(origin: &str, req: U2SGet) -> String {
     return format!("{}/api?q={}", origin, urlencoding::encode(&serde_json::to_string(&req).unwrap()));
 }
 
+fn post_req(origin: &str, body: &str) -> Request {
+    return Request::post(&format!("{}/api", origin)).header("Content-type", "application/json").body(body);
+}
+
+#[derive(Default)]
+struct AuthState {
+    on_need_auth: Option<Rc<dyn Fn()>>,
+    /// Requests rejected with 401, waiting to be retried after login.
+    waiting: Vec<oneshot::Sender<()>>,
+}
+
 #[derive(Clone)]
 pub struct World {
     pub origin: String,
+    auth: Rc<RefCell<AuthState>>,
 }
 
 impl World {
     pub fn new() -> World {
         let location = window().location();
         let origin = location.origin().unwrap();
-        return World { origin: origin };
+        return World {
+            origin: origin,
+            auth: Default::default(),
+        };
+    }
+
+    /// Called whenever a request fails due to a missing or expired session. The
+    /// failed requests are retried once `login` succeeds. If this isn't set, requests
+    /// fail with an error instead.
+    pub fn set_on_need_auth(&self, f: impl Fn() + 'static) {
+        self.auth.borrow_mut().on_need_auth = Some(Rc::new(f));
     }
 
-    pub async fn req_get<T: DeserializeOwned>(&self, req: U2SGet) -> Result<T, String> {
-        let res = send_req(Request::get(&req_get_url(&self.origin, req))).await?;
-        return Ok(serde_json::from_slice(&res).map_err(|e| e.to_string())?);
+    async fn send(&self, make_req: impl Fn() -> Request) -> Result<Vec<u8>, ApiError> {
+        loop {
+            match send_req(make_req()).await {
+                Ok(r) => return Ok(r),
+                Err(ReqError::Rejected(e)) => return Err(ApiError::Rejected(e)),
+                Err(ReqError::Other(e)) => return Err(ApiError::Transient(e)),
+                Err(ReqError::Unauthorized(e)) => {
+                    let (on_need_auth, wait) = {
+                        let mut auth = self.auth.borrow_mut();
+                        let Some(on_need_auth) = auth.on_need_auth.clone() else {
+                            return Err(ApiError::Rejected(e));
+                        };
+                        let (tx, rx) = oneshot::channel();
+                        auth.waiting.push(tx);
+                        (on_need_auth, rx)
+                    };
+                    on_need_auth();
+                    wait
+                        .await
+                        .map_err(
+                            |_| ApiError::Transient(format!("Gave up waiting for login after error: {}", e)),
+                        )?;
+                },
+            }
+        }
     }
 
-    pub async fn req_post_ret<T: DeserializeOwned>(&self, req: U2SPost) -> Result<T, String> {
-        let res =
-            send_req(
-                Request::post(&format!("{}/api", &self.origin))
-                    .header("Content-type", "application/json")
-                    .body(serde_json::to_string(&req).unwrap()),
-            ).await?;
-        return Ok(serde_json::from_slice(&res).map_err(|e| e.to_string())?);
+    /// Send a request, returning its response. A response that can't be parsed is
+    /// treated as rejected.
+    pub async fn call<R: U2SReq>(&self, req: R) -> Result<R::Resp, ApiError> {
+        let res = match req.into_any() {
+            U2SAny::Get(req) => {
+                let url = req_get_url(&self.origin, req);
+                self.send(|| Request::get(&url)).await?
+            },
+            U2SAny::Post(req) => {
+                let body = serde_json::to_string(&req).unwrap();
+                self.send(|| post_req(&self.origin, &body)).await?
+            },
+        };
+        return Ok(
+            serde_json::from_slice(
+                &res,
+            ).map_err(|e| ApiError::Rejected(format!("Error parsing response: {}", e)))?,
+        );
     }
 
-    pub async fn req_post(&self, req: U2SPost) -> Result<(), String> {
-        send_req(
-            Request::post(&format!("{}/api", &self.origin))
-                .header("Content-type", "application/json")
-                .body(serde_json::to_string(&req).unwrap()),
-        ).await?;
+    /// Start a session. On success, requests waiting for login are retried.
+    pub async fn login(&self, username: String, password: String) -> Result<(), String> {
+        let body = serde_json::to_string(&U2SPost::Auth(U2SAuth {
+            username: username,
+            password: password,
+        })).unwrap();
+        match send_req(post_req(&self.origin, &body)).await {
+            Ok(_) => { },
+            Err(ReqError::Unauthorized(e)) | Err(ReqError::Rejected(e)) | Err(ReqError::Other(e)) => return Err(e),
+        }
+        for tx in self.auth.borrow_mut().waiting.drain(..) {
+            _ = tx.send(());
+        }
         return Ok(());
     }
+
+    /// End the session. This doesn't wait for login if the session already expired.
+    pub async fn logout(&self) -> Result<(), String> {
+        match send_req(post_req(&self.origin, &serde_json::to_string(&U2SPost::Logout(U2SLogout)).unwrap())).await {
+            Ok(_) | Err(ReqError::Unauthorized(_)) => return Ok(()),
+            Err(ReqError::Rejected(e)) | Err(ReqError::Other(e)) => return Err(e),
+        }
+    }
 }
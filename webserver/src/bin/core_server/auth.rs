@@ -0,0 +1,108 @@
+use argon2::{
+    password_hash::{
+        rand_core::OsRng,
+        PasswordHash,
+        PasswordHasher,
+        PasswordVerifier,
+        SaltString,
+    },
+    Argon2,
+};
+use chrono::{
+    Duration,
+    Utc,
+};
+use loga::ResultContext;
+use poem::{
+    http::StatusCode,
+    web::cookie::{
+        Cookie,
+        CookieJar,
+        SameSite,
+    },
+};
+use rand::{
+    distributions::Alphanumeric,
+    Rng,
+};
+use super::{
+    err,
+    storage::Session,
+    CoreServer,
+};
+
+const SESSION_COOKIE: &str = "session";
+
+pub fn hash_password(password: &str) -> Result<String, loga::Error> {
+    return Ok(
+        Argon2::default()
+            .hash_password(password.as_bytes(), &SaltString::generate(&mut OsRng))
+            .context("Failed to hash password")?
+            .to_string(),
+    );
+}
+
+fn verify_password(password: &str, hash: &str) -> bool {
+    let Ok(hash) = PasswordHash::new(hash) else {
+        return false;
+    };
+    return Argon2::default().verify_password(password.as_bytes(), &hash).is_ok();
+}
+
+fn session_cookie(token: String, lifetime: Duration) -> Cookie {
+    let mut cookie = Cookie::new_with_str(SESSION_COOKIE, token);
+    cookie.set_http_only(true);
+    cookie.set_secure(true);
+    cookie.set_same_site(SameSite::Strict);
+    cookie.set_path("/");
+    cookie.set_max_age(lifetime.to_std().unwrap_or_default());
+    return cookie;
+}
+
+impl CoreServer {
+    /// Check the password and start a new session, storing the token in the jar.
+    pub(super) async fn login(
+        &self,
+        jar: &CookieJar,
+        username: String,
+        password: String,
+    ) -> Result<(), poem::Error> {
+        let hash = self.storage.get_user_password_hash(username.clone()).await.map_err(|e| self.internal(e))?;
+        let valid = match hash {
+            Some(hash) => tokio::task::spawn_blocking(
+                move || verify_password(&password, &hash),
+            ).await.context("Password verification task failed").map_err(|e| self.internal(e))?,
+            None => false,
+        };
+        if !valid {
+            return Err(err(StatusCode::UNAUTHORIZED, "Invalid username or password"));
+        }
+        let token = rand::thread_rng().sample_iter(&Alphanumeric).take(32).map(char::from).collect::<String>();
+        self.storage.create_session(token.clone(), Session {
+            user: username,
+            expires: Utc::now() + self.session_lifetime,
+        }).await.map_err(|e| self.internal(e))?;
+        jar.add(session_cookie(token, self.session_lifetime));
+        return Ok(());
+    }
+
+    /// End the current session, if any, and clear the cookie.
+    pub(super) async fn logout(&self, jar: &CookieJar) -> Result<(), poem::Error> {
+        if let Some(cookie) = jar.get(SESSION_COOKIE) {
+            self.storage.delete_session(cookie.value_str().to_string()).await.map_err(|e| self.internal(e))?;
+        }
+        let mut cookie = session_cookie(String::new(), Duration::zero());
+        cookie.make_removal();
+        jar.add(cookie);
+        return Ok(());
+    }
+
+    /// Returns the session token and session for the request, or a 401 error if
+    /// there's no valid session.
+    pub(super) async fn authenticate(&self, jar: &CookieJar) -> Result<(String, Session), poem::Error> {
+        let unauthorized = || err(StatusCode::UNAUTHORIZED, "Not logged in");
+        let token = jar.get(SESSION_COOKIE).ok_or_else(unauthorized)?.value_str().to_string();
+        let session = self.storage.get_session(token.clone(), Utc::now()).await.map_err(|e| self.internal(e))?;
+        return Ok((token, session.ok_or_else(unauthorized)?));
+    }
+}
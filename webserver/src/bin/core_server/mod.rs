@@ -1 +1,651 @@
- 
+use std::sync::Arc;
+use chrono::{
+    DateTime,
+    Duration,
+    SubsecRound,
+    Utc,
+};
+use loga::{
+    ea,
+    Log,
+};
+use poem::{
+    handler,
+    http::StatusCode,
+    web::{
+        cookie::CookieJar,
+        sse::{
+            Event,
+            SSE,
+        },
+        Data,
+        Query,
+        Json,
+    },
+    IntoResponse,
+    Response,
+};
+use serde::Deserialize;
+use tokio::sync::broadcast;
+use tokio_stream::{
+    wrappers::BroadcastStream,
+    StreamExt,
+};
+use webserver::interface::u2s::{
+    BrewId,
+    ChannelId,
+    DateMessageId,
+    IdentityId,
+    MessageId,
+    S2SWPush,
+    S2UEvent,
+    S2UEventKind,
+    S2UIdentity,
+    U2SAuth,
+    U2SBrewCreate,
+    U2SBrewDelete,
+    U2SBrewRename,
+    U2SBrewSetChannels,
+    U2SBrewUpdate,
+    U2SChannelCreate,
+    U2SChannelJoin,
+    U2SChannelLeave,
+    U2SChannelRename,
+    U2SDelete,
+    U2SEdit,
+    U2SEventsGetAfter,
+    U2SGet,
+    U2SGetBrew,
+    U2SGetBrews,
+    U2SGetBrewsById,
+    U2SGetChannel,
+    U2SGetChannelSummaries,
+    U2SGetChannels,
+    U2SGetChannelsById,
+    U2SGetIdentitiesById,
+    U2SGetIdentity,
+    U2SGetOwnIdentities,
+    U2SGetPushPubKey,
+    U2SGetReadPosition,
+    U2SIdentityCreate,
+    U2SIdentityRename,
+    U2SLogout,
+    U2SPost,
+    U2SReq,
+    U2SSend,
+    U2SSetReadPosition,
+    U2SSnapGetAfter,
+    U2SSnapGetAround,
+    U2SSnapGetBefore,
+    U2SSubscribePush,
+};
+use self::{
+    push::{
+        PushError,
+        Pusher,
+    },
+    storage::{
+        LocalSendId,
+        Sent,
+        Storage,
+    },
+};
+
+pub mod auth;
+pub mod push;
+pub mod storage;
+
+/// Upper limit for the number of messages returned by a single range request.
+const MAX_COUNT: u64 = 200;
+
+/// Upper limit for the number of ids in a single batch lookup.
+const MAX_BATCH: usize = 200;
+
+/// Message text is cut to this many characters in push notifications, to keep the
+/// payload within a single record.
+const PUSH_QUOTE_LENGTH: usize = 200;
+
+/// Notifications buffered per event stream client. Clients that fall further
+/// behind skip notifications, but catch up with the next one.
+const EVENT_BUFFER: usize = 100;
+
+/// Something to forward to event stream clients.
+#[derive(Clone)]
+enum Notification {
+    /// Only sent to the listed users' clients
+    Event {
+        event: S2UEvent,
+        users: Arc<Vec<String>>,
+    },
+    /// A `BrewChanged` or `BrewDeleted` event, only sent to the brew owner's clients
+    Brew {
+        user: String,
+        kind: S2UEventKind,
+    },
+    /// Only sent to the user's own clients
+    ReadPosition {
+        user: String,
+        id: MessageId,
+    },
+}
+
+pub struct CoreServer {
+    log: Log,
+    storage: Arc<dyn Storage>,
+    session_lifetime: Duration,
+    /// How long to remember sends' local ids, to recognize retries
+    send_dedup_window: Duration,
+    pusher: Pusher,
+    events: broadcast::Sender<Notification>,
+}
+
+impl CoreServer {
+    pub async fn new(
+        log: &Log,
+        storage: Arc<dyn Storage>,
+        session_lifetime: Duration,
+        send_dedup_window: Duration,
+        push_subject: Option<String>,
+    ) -> Result<Arc<CoreServer>, loga::Error> {
+        let pusher = Pusher::new(&storage.own_push_key(Pusher::generate_key()).await?, push_subject)?;
+        return Ok(Arc::new(CoreServer {
+            log: log.clone(),
+            storage: storage,
+            session_lifetime: session_lifetime,
+            send_dedup_window: send_dedup_window,
+            pusher: pusher,
+            events: broadcast::channel(EVENT_BUFFER).0,
+        }));
+    }
+
+    fn internal(&self, e: loga::Error) -> poem::Error {
+        self.log.warn_e(e, "Error processing request", ea!());
+        return err(StatusCode::INTERNAL_SERVER_ERROR, "Internal server error");
+    }
+
+    /// The user's identities, creating a first one named after the user if they have
+    /// none.
+    async fn user_identities(&self, user: &str) -> Result<Vec<S2UIdentity>, poem::Error> {
+        let identities = self.storage.list_identities(user.to_string()).await.map_err(|e| self.internal(e))?;
+        if !identities.is_empty() {
+            return Ok(identities);
+        }
+        let identity =
+            self
+                .storage
+                .create_identity(user.to_string(), user.to_string())
+                .await
+                .map_err(|e| self.internal(e))?;
+        self.log.debug("Created first identity", ea!(user = user, identity = identity.id.0));
+        return Ok(vec![identity]);
+    }
+
+    /// The identity to act as: `identity` if it's one of the user's, otherwise the
+    /// user's first identity.
+    async fn acting_identity(&self, user: &str, identity: Option<IdentityId>) -> Result<IdentityId, poem::Error> {
+        let owned = self.user_identities(user).await?;
+        match identity {
+            Some(identity) => {
+                if !owned.iter().any(|i| i.id == identity) {
+                    return Err(err(StatusCode::FORBIDDEN, "Not one of your identities"));
+                }
+                return Ok(identity);
+            },
+            None => return Ok(owned.into_iter().next().unwrap().id),
+        }
+    }
+
+    /// Fail unless one of the user's identities is a member of the channel.
+    async fn check_member(&self, user: &str, channel: &ChannelId) -> Result<(), poem::Error> {
+        let members = self.storage.get_channel_members(channel.clone()).await.map_err(|e| self.internal(e))?;
+        let members = not_found(members, "Unknown channel")?;
+        let identities = self.storage.list_identities(user.to_string()).await.map_err(|e| self.internal(e))?;
+        if !identities.iter().any(|i| members.contains(&i.id)) {
+            return Err(err(StatusCode::FORBIDDEN, "Not a member of the channel"));
+        }
+        return Ok(());
+    }
+
+    /// Fail unless the user is a member of every channel, for brews.
+    async fn check_member_all(&self, user: &str, channels: &[ChannelId]) -> Result<(), poem::Error> {
+        for channel in channels {
+            self.check_member(user, channel).await?;
+        }
+        return Ok(());
+    }
+
+    /// Fail unless the message was sent by one of the user's identities.
+    async fn check_author(&self, user: &str, id: &MessageId) -> Result<(), poem::Error> {
+        let author = self.storage.get_message_author(id.clone()).await.map_err(|e| self.internal(e))?;
+        let author = not_found(author, "Unknown message")?;
+        if !self.user_identities(user).await?.iter().any(|i| i.id == author) {
+            return Err(err(StatusCode::FORBIDDEN, "Not your message"));
+        }
+        return Ok(());
+    }
+
+    /// Forward an event to the connected clients of the channel's members.
+    async fn publish(&self, event: S2UEvent) -> Result<(), poem::Error> {
+        let users = self.storage.get_channel_users(event.id.0.clone()).await.map_err(|e| self.internal(e))?;
+        self.publish_to(event, users);
+        return Ok(());
+    }
+
+    /// Publish a brew's change logged in its channels, and the brew's current state, to
+    /// the owner's clients.
+    async fn publish_brew(&self, user: String, id: BrewId, events: Vec<S2UEvent>) -> Result<(), poem::Error> {
+        for event in events {
+            self.publish_to(event, vec![user.clone()]);
+        }
+        let kind = match self.storage.get_brew(user.clone(), id.clone()).await.map_err(|e| self.internal(e))? {
+            Some(brew) => S2UEventKind::BrewChanged(brew),
+            None => S2UEventKind::BrewDeleted(id),
+        };
+        _ = self.events.send(Notification::Brew {
+            user: user,
+            kind: kind,
+        });
+        return Ok(());
+    }
+
+    fn publish_to(&self, event: S2UEvent, users: Vec<String>) {
+        // Errors if there are no listeners
+        _ = self.events.send(Notification::Event {
+            event: event,
+            users: Arc::new(users),
+        });
+    }
+
+    /// Record that the user has read up to `id` and tell their other clients. Returns
+    /// false if the channel doesn't exist.
+    async fn mark_read(&self, user: String, id: MessageId) -> Result<bool, poem::Error> {
+        let Some(id) = self.storage.set_read_position(user.clone(), id).await.map_err(|e| self.internal(e))? else {
+            return Ok(false);
+        };
+        _ = self.events.send(Notification::ReadPosition {
+            user: user,
+            id: id,
+        });
+        return Ok(true);
+    }
+
+    /// Notify everyone but the sender of a new message. Subscriptions the push service
+    /// reports as gone are deleted.
+    async fn push_message(&self, sender: String, id: MessageId, time: DateTime<Utc>, text: String) {
+        match async {
+            let subscriptions = self.storage.list_push_subscriptions(id.0.clone(), sender, Utc::now()).await?;
+            if subscriptions.is_empty() {
+                return Ok(());
+            }
+            let title = match self.storage.get_channel(id.0.clone()).await? {
+                Some(c) => c.name,
+                None => return Ok(()),
+            };
+            let payload = serde_json::to_vec(&S2SWPush {
+                id: id,
+                time: time,
+                title: title,
+                quote: text.chars().take(PUSH_QUOTE_LENGTH).collect(),
+                icon_url: "logo.svg".to_string(),
+            }).unwrap();
+            for sub in subscriptions {
+                match self.pusher.push(&sub, &payload).await {
+                    Ok(_) => { },
+                    Err(PushError::Gone) => {
+                        self.log.debug("Push subscription gone, deleting", ea!());
+                        self.storage.delete_push_subscription(sub).await?;
+                    },
+                    Err(PushError::Other(e)) => {
+                        self.log.warn_e(e, "Failed to send push notification", ea!());
+                    },
+                }
+            }
+            return Ok(()) as Result<(), loga::Error>;
+        }.await {
+            Ok(_) => { },
+            Err(e) => {
+                self.log.warn_e(e, "Error sending push notifications", ea!());
+            },
+        }
+    }
+}
+
+fn err(status: StatusCode, message: &str) -> poem::Error {
+    return poem::Error::from_string(message, status);
+}
+
+fn not_found<T>(v: Option<T>, message: &str) -> Result<T, poem::Error> {
+    return v.ok_or_else(|| err(StatusCode::NOT_FOUND, message));
+}
+
+/// Successful response to a request of type `R`.
+fn respond<R: U2SReq>(resp: R::Resp) -> Result<Response, poem::Error> {
+    return Ok(Json(resp).into_response());
+}
+
+fn check_batch<T>(ids: &[T]) -> Result<(), poem::Error> {
+    if ids.len() > MAX_BATCH {
+        return Err(err(StatusCode::BAD_REQUEST, &format!("Too many ids, the limit is {}", MAX_BATCH)));
+    }
+    return Ok(());
+}
+
+#[derive(Deserialize)]
+struct GetParams {
+    q: String,
+}
+
+#[handler]
+pub async fn api_get(
+    Data(core): Data<&Arc<CoreServer>>,
+    jar: &CookieJar,
+    Query(params): Query<GetParams>,
+) -> Result<Response, poem::Error> {
+    let (_, session) = core.authenticate(jar).await?;
+    let req =
+        serde_json::from_str::<U2SGet>(
+            &params.q,
+        ).map_err(|e| err(StatusCode::BAD_REQUEST, &format!("Invalid request: {}", e)))?;
+    let storage = &core.storage;
+    match req {
+        U2SGet::GetPushPubKey(U2SGetPushPubKey) => {
+            return respond::<U2SGetPushPubKey>(core.pusher.public_key().to_vec());
+        },
+        U2SGet::GetBrew(U2SGetBrew(id)) => {
+            let brew = storage.get_brew(session.user, id).await.map_err(|e| core.internal(e))?;
+            return respond::<U2SGetBrew>(not_found(brew, "Unknown brew")?);
+        },
+        U2SGet::GetChannel(U2SGetChannel(id)) => {
+            core.check_member(&session.user, &id).await?;
+            let channel = storage.get_channel(id).await.map_err(|e| core.internal(e))?;
+            return respond::<U2SGetChannel>(not_found(channel, "Unknown channel")?);
+        },
+        U2SGet::GetIdentity(U2SGetIdentity(id)) => {
+            let identity = storage.get_identity(id).await.map_err(|e| core.internal(e))?;
+            return respond::<U2SGetIdentity>(not_found(identity, "Unknown identity")?);
+        },
+        U2SGet::GetBrewsById(U2SGetBrewsById(ids)) => {
+            check_batch(&ids)?;
+            let brews = storage.get_brews(session.user, ids).await.map_err(|e| core.internal(e))?;
+            return respond::<U2SGetBrewsById>(brews);
+        },
+        U2SGet::GetChannelsById(U2SGetChannelsById(ids)) => {
+            check_batch(&ids)?;
+            let mut channels = storage.get_channels(ids.clone()).await.map_err(|e| core.internal(e))?;
+
+            // Channels the user isn't in are reported as unknown
+            for (id, channel) in ids.into_iter().zip(channels.iter_mut()) {
+                if channel.is_none() {
+                    continue;
+                }
+                if !storage.get_channel_users(id).await.map_err(|e| core.internal(e))?.contains(&session.user) {
+                    *channel = None;
+                }
+            }
+            return respond::<U2SGetChannelsById>(channels);
+        },
+        U2SGet::GetIdentitiesById(U2SGetIdentitiesById(ids)) => {
+            check_batch(&ids)?;
+            return respond::<U2SGetIdentitiesById>(storage.get_identities(ids).await.map_err(|e| core.internal(e))?);
+        },
+        U2SGet::GetChannels(U2SGetChannels) => {
+            return respond::<U2SGetChannels>(storage.list_channels(session.user).await.map_err(|e| core.internal(e))?);
+        },
+        U2SGet::GetChannelSummaries(U2SGetChannelSummaries) => {
+            let mut summaries = storage.list_channel_summaries(session.user).await.map_err(|e| core.internal(e))?;
+
+            // Stable, so inactive channels stay in id order at the end
+            summaries.sort_by(|a, b| b.last_message.as_ref().map(|m| m.time).cmp(&a.last_message.as_ref().map(|m| m.time)));
+            return respond::<U2SGetChannelSummaries>(summaries);
+        },
+        U2SGet::GetReadPosition(U2SGetReadPosition(id)) => {
+            let position = storage.get_read_position(session.user, id).await.map_err(|e| core.internal(e))?;
+            return respond::<U2SGetReadPosition>(position);
+        },
+        U2SGet::GetBrews(U2SGetBrews) => {
+            return respond::<U2SGetBrews>(storage.list_brews(session.user).await.map_err(|e| core.internal(e))?);
+        },
+        U2SGet::GetOwnIdentities(U2SGetOwnIdentities) => {
+            return respond::<U2SGetOwnIdentities>(core.user_identities(&session.user).await?);
+        },
+        U2SGet::EventsGetAfter(U2SEventsGetAfter { id, count }) => {
+            core.check_member(&session.user, &id.0).await?;
+            let resp = storage.events_after(id, count.min(MAX_COUNT)).await.map_err(|e| core.internal(e))?;
+            return respond::<U2SEventsGetAfter>(not_found(resp, "Unknown channel")?);
+        },
+        U2SGet::SnapGetAround(U2SSnapGetAround { channel, time, count }) => {
+            core.check_member(&session.user, &channel).await?;
+            let resp =
+                storage.snap_around(channel, time, count.min(MAX_COUNT)).await.map_err(|e| core.internal(e))?;
+            return respond::<U2SSnapGetAround>(not_found(resp, "Unknown channel")?);
+        },
+        U2SGet::SnapGetBefore(U2SSnapGetBefore { id, count }) => {
+            core.check_member(&session.user, &id.0).await?;
+            let resp = storage.snap_before(id, count.min(MAX_COUNT)).await.map_err(|e| core.internal(e))?;
+            return respond::<U2SSnapGetBefore>(not_found(resp, "Unknown channel")?);
+        },
+        U2SGet::SnapGetAfter(U2SSnapGetAfter { id, count }) => {
+            core.check_member(&session.user, &id.0).await?;
+            let resp = storage.snap_after(id, count.min(MAX_COUNT)).await.map_err(|e| core.internal(e))?;
+            return respond::<U2SSnapGetAfter>(not_found(resp, "Unknown channel")?);
+        },
+    }
+}
+
+#[handler]
+pub async fn api_post(
+    Data(core): Data<&Arc<CoreServer>>,
+    jar: &CookieJar,
+    Json(req): Json<U2SPost>,
+) -> Result<Response, poem::Error> {
+    let storage = &core.storage;
+    if let U2SPost::Auth(U2SAuth { username, password }) = req {
+        core.login(jar, username, password).await?;
+        return respond::<U2SAuth>(());
+    }
+    let (token, session) = core.authenticate(jar).await?;
+    match req {
+        U2SPost::SubscribePush(U2SSubscribePush(sub)) => {
+            storage.add_push_subscription(token, sub).await.map_err(|e| core.internal(e))?;
+            return respond::<U2SSubscribePush>(());
+        },
+        U2SPost::Auth(U2SAuth { .. }) => unreachable!(),
+        U2SPost::Logout(U2SLogout) => {
+            core.logout(jar).await?;
+            return respond::<U2SLogout>(());
+        },
+        U2SPost::IdentityCreate(U2SIdentityCreate { name }) => {
+            let identity = storage.create_identity(session.user, name).await.map_err(|e| core.internal(e))?;
+            core.log.debug("Created identity", ea!(identity = identity.id.0));
+            return respond::<U2SIdentityCreate>(identity);
+        },
+        U2SPost::IdentityRename(U2SIdentityRename { id, name }) => {
+            if !storage.rename_identity(session.user, id, name).await.map_err(|e| core.internal(e))? {
+                return Err(err(StatusCode::NOT_FOUND, "Unknown identity"));
+            }
+            return respond::<U2SIdentityRename>(());
+        },
+        U2SPost::ChannelCreate(U2SChannelCreate { identity, name }) => {
+            let identity = core.acting_identity(&session.user, identity).await?;
+            let id = storage.create_channel(identity, name, Utc::now()).await.map_err(|e| core.internal(e))?;
+            let id = id.ok_or_else(|| err(StatusCode::CONFLICT, "Channel limit reached"))?;
+            core.log.debug("Created channel", ea!(channel = id.1));
+            return respond::<U2SChannelCreate>(id);
+        },
+        U2SPost::ChannelJoin(U2SChannelJoin { identity, id }) => {
+            let identity = core.acting_identity(&session.user, identity).await?;
+            let channel = storage.get_channel(id.clone()).await.map_err(|e| core.internal(e))?;
+            not_found(channel, "Unknown channel")?;
+
+            // None if already a member
+            let event = storage.join_channel(id.clone(), identity, Utc::now()).await.map_err(|e| core.internal(e))?;
+            if let Some(event) = event {
+                core.publish(event).await?;
+            }
+            return respond::<U2SChannelJoin>(id);
+        },
+        U2SPost::ChannelLeave(U2SChannelLeave(id)) => {
+            let mut left = false;
+            for identity in core.user_identities(&session.user).await? {
+                // None if not a member
+                let event =
+                    storage.leave_channel(id.clone(), identity.id, Utc::now()).await.map_err(|e| core.internal(e))?;
+                if let Some(event) = event {
+                    // No longer a member, but the user's other clients still need to know
+                    let mut users =
+                        storage.get_channel_users(id.clone()).await.map_err(|e| core.internal(e))?;
+                    users.push(session.user.clone());
+                    core.publish_to(event, users);
+                    left = true;
+                }
+            }
+            if !left {
+                return Err(err(StatusCode::NOT_FOUND, "Unknown channel or not a member"));
+            }
+            return respond::<U2SChannelLeave>(());
+        },
+        U2SPost::ChannelRename(U2SChannelRename { id, name }) => {
+            core.check_member(&session.user, &id).await?;
+            let event = storage.rename_channel(id, Utc::now(), name).await.map_err(|e| core.internal(e))?;
+            core.publish(not_found(event, "Unknown channel")?).await?;
+            return respond::<U2SChannelRename>(());
+        },
+        U2SPost::BrewCreate(U2SBrewCreate { name, channels }) => {
+            core.check_member_all(&session.user, &channels).await?;
+            let (id, events) =
+                storage
+                    .create_brew(session.user.clone(), name, channels, Utc::now())
+                    .await
+                    .map_err(|e| core.internal(e))?;
+            core.publish_brew(session.user, id.clone(), events).await?;
+            return respond::<U2SBrewCreate>(id);
+        },
+        U2SPost::BrewRename(U2SBrewRename { id, name }) => {
+            let events =
+                storage
+                    .update_brew(session.user.clone(), id.clone(), Some(name), None, Utc::now())
+                    .await
+                    .map_err(|e| core.internal(e))?;
+            core.publish_brew(session.user, id, not_found(events, "Unknown brew")?).await?;
+            return respond::<U2SBrewRename>(());
+        },
+        U2SPost::BrewSetChannels(U2SBrewSetChannels { id, channels }) => {
+            core.check_member_all(&session.user, &channels).await?;
+            let events =
+                storage
+                    .update_brew(session.user.clone(), id.clone(), None, Some(channels), Utc::now())
+                    .await
+                    .map_err(|e| core.internal(e))?;
+            core.publish_brew(session.user, id, not_found(events, "Unknown brew")?).await?;
+            return respond::<U2SBrewSetChannels>(());
+        },
+        U2SPost::BrewUpdate(U2SBrewUpdate { id, name, channels }) => {
+            core.check_member_all(&session.user, &channels).await?;
+            let events =
+                storage
+                    .update_brew(session.user.clone(), id.clone(), Some(name), Some(channels), Utc::now())
+                    .await
+                    .map_err(|e| core.internal(e))?;
+            core.publish_brew(session.user, id, not_found(events, "Unknown brew")?).await?;
+            return respond::<U2SBrewUpdate>(());
+        },
+        U2SPost::BrewDelete(U2SBrewDelete(id)) => {
+            let events =
+                storage
+                    .delete_brew(session.user.clone(), id.clone(), Utc::now())
+                    .await
+                    .map_err(|e| core.internal(e))?;
+            core.publish_brew(session.user, id, not_found(events, "Unknown brew")?).await?;
+            return respond::<U2SBrewDelete>(());
+        },
+        U2SPost::Send(U2SSend { identity, channel, reply, local_id, body }) => {
+            let identity = core.acting_identity(&session.user, identity).await?;
+            let members = storage.get_channel_members(channel.clone()).await.map_err(|e| core.internal(e))?;
+            if !not_found(members, "Unknown channel")?.contains(&identity) {
+                return Err(err(StatusCode::FORBIDDEN, "Not a member of the channel"));
+            }
+            // Match storage precision, so pushed times are equal to later fetched times
+            let time = Utc::now().trunc_subsecs(6);
+            let sent = storage.send(channel, identity, reply, time, body.clone(), Some(LocalSendId {
+                user: session.user.clone(),
+                local_id: local_id,
+                expire_before: time - core.send_dedup_window,
+            })).await.map_err(|e| core.internal(e))?;
+            let event = match not_found(sent, "Unknown channel")? {
+                Sent::New(event) => event,
+                // A retry after the response was lost, already published
+                Sent::Existing(id) => return respond::<U2SSend>(id),
+            };
+            let id = event.id.clone();
+            core.publish(event).await?;
+
+            // Your own messages don't count as unread
+            core.mark_read(session.user.clone(), id.clone()).await?;
+            tokio::spawn({
+                let core = core.clone();
+                let id = id.clone();
+                async move {
+                    core.push_message(session.user, id, time, body).await;
+                }
+            });
+            return respond::<U2SSend>(id);
+        },
+        U2SPost::SetReadPosition(U2SSetReadPosition(id)) => {
+            core.check_member(&session.user, &id.0).await?;
+            if !core.mark_read(session.user, id).await? {
+                return Err(err(StatusCode::NOT_FOUND, "Unknown channel"));
+            }
+            return respond::<U2SSetReadPosition>(());
+        },
+        U2SPost::Edit(U2SEdit { id, body }) => {
+            core.check_author(&session.user, &id).await?;
+            let event = storage.edit_message(id, Utc::now(), body).await.map_err(|e| core.internal(e))?;
+            core.publish(not_found(event, "Unknown message")?).await?;
+            return respond::<U2SEdit>(());
+        },
+        U2SPost::Delete(U2SDelete(id)) => {
+            core.check_author(&session.user, &id).await?;
+            let event = storage.delete_message(id, Utc::now()).await.map_err(|e| core.internal(e))?;
+            core.publish(not_found(event, "Unknown message")?).await?;
+            return respond::<U2SDelete>(());
+        },
+    }
+}
+
+/// Stream channel activity as server-sent events. New messages are sent as
+/// default (`message`) events with a `DateMessageId`, all other events as `event`
+/// events with just the event's `MessageId`. Changes to the user's read positions
+/// are sent as `read` events with the new position's `MessageId`. Only events in
+/// channels the user has a member identity in are sent. Changes to the user's brews
+/// are sent as `brew` events with the `BrewChanged` or `BrewDeleted` event kind.
+#[handler]
+pub async fn api_events(Data(core): Data<&Arc<CoreServer>>, jar: &CookieJar) -> Result<SSE, poem::Error> {
+    let (_, session) = core.authenticate(jar).await?;
+    let events = BroadcastStream::new(core.events.subscribe()).filter_map(move |e| match e {
+        Ok(Notification::Event { event: e, users }) => {
+            if !users.contains(&session.user) {
+                return None;
+            }
+            match &e.kind {
+                S2UEventKind::MessageCreated(_) => return Some(
+                    Event::message(serde_json::to_string(&DateMessageId(e.time, e.id)).unwrap()),
+                ),
+                _ => return Some(Event::message(serde_json::to_string(&e.id).unwrap()).event_type("event")),
+            }
+        },
+        Ok(Notification::Brew { user, kind }) => {
+            if user != session.user {
+                return None;
+            }
+            return Some(Event::message(serde_json::to_string(&kind).unwrap()).event_type("brew"));
+        },
+        Ok(Notification::ReadPosition { user, id }) => {
+            if user != session.user {
+                return None;
+            }
+            return Some(Event::message(serde_json::to_string(&id).unwrap()).event_type("read"));
+        },
+        Err(_) => None,
+    });
+    return Ok(SSE::new(events).keep_alive(std::time::Duration::from_secs(30)));
+}
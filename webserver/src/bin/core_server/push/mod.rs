@@ -0,0 +1,279 @@
+//! Web Push delivery: VAPID (RFC 8292) authentication and `aes128gcm` payload
+//! encryption (RFC 8291).
+use std::time::Duration;
+use aes_gcm::{
+    aead::{
+        Aead,
+        KeyInit,
+    },
+    Aes128Gcm,
+};
+use base64::{
+    alphabet,
+    engine::{
+        general_purpose::{
+            GeneralPurpose,
+            GeneralPurposeConfig,
+            URL_SAFE_NO_PAD,
+        },
+        DecodePaddingMode,
+    },
+    Engine,
+};
+use chrono::Utc;
+use hkdf::Hkdf;
+use loga::{
+    ea,
+    ResultContext,
+};
+use p256::{
+    ecdh::{
+        diffie_hellman,
+        EphemeralSecret,
+    },
+    ecdsa::{
+        signature::Signer,
+        Signature,
+        SigningKey,
+    },
+    elliptic_curve::sec1::ToEncodedPoint,
+    PublicKey,
+    SecretKey,
+};
+use rand::{
+    rngs::OsRng,
+    RngCore,
+};
+use reqwest::{
+    StatusCode,
+    Url,
+};
+use serde::{
+    Deserialize,
+    Serialize,
+};
+use sha2::Sha256;
+
+pub mod stub;
+#[cfg(test)]
+mod tests;
+
+/// Record size advertised in the payload header. Payloads are always sent as a
+/// single record so they must be smaller than this.
+const RECORD_SIZE: u32 = 4096;
+
+/// How long push services should hold on to undelivered messages.
+const TTL: Duration = Duration::from_secs(60 * 60 * 24);
+
+/// Subscriptions may be padded or not depending on the browser.
+pub const BASE64_URL: GeneralPurpose =
+    GeneralPurpose::new(
+        &alphabet::URL_SAFE,
+        GeneralPurposeConfig::new().with_decode_padding_mode(DecodePaddingMode::Indifferent),
+    );
+
+/// The JSON form of a browser `PushSubscription`.
+#[derive(Serialize, Deserialize)]
+pub struct Subscription {
+    pub endpoint: String,
+    pub keys: SubscriptionKeys,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SubscriptionKeys {
+    pub p256dh: String,
+    pub auth: String,
+}
+
+pub enum PushError {
+    /// The subscription no longer exists and should be forgotten.
+    Gone,
+    Other(loga::Error),
+}
+
+impl From<loga::Error> for PushError {
+    fn from(value: loga::Error) -> Self {
+        return PushError::Other(value);
+    }
+}
+
+pub struct Pusher {
+    key: SigningKey,
+    public_key: Vec<u8>,
+    subject: Option<String>,
+    client: reqwest::Client,
+}
+
+impl Pusher {
+    /// Generate a new VAPID private key, for use with `new`.
+    pub fn generate_key() -> Vec<u8> {
+        return SecretKey::random(&mut OsRng).to_bytes().to_vec();
+    }
+
+    /// `subject` is a `mailto:` or `https:` contact URL for the push service operator.
+    pub fn new(key: &[u8], subject: Option<String>) -> Result<Pusher, loga::Error> {
+        let key = SigningKey::from_slice(key).map_err(loga::Error::from).context("Invalid VAPID private key")?;
+        let public_key = key.verifying_key().to_encoded_point(false).as_bytes().to_vec();
+        return Ok(Pusher {
+            key: key,
+            public_key: public_key,
+            subject: subject,
+            client: reqwest::Client::builder()
+                .timeout(Duration::from_secs(30))
+                .build()
+                .context("Failed to build push http client")?,
+        });
+    }
+
+    /// The uncompressed public key, used as the `applicationServerKey` when
+    /// subscribing.
+    pub fn public_key(&self) -> &[u8] {
+        return &self.public_key;
+    }
+
+    fn authorization(&self, endpoint: &Url) -> String {
+        #[derive(Serialize)]
+        struct Claims<'a> {
+            aud: String,
+            exp: i64,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            sub: Option<&'a str>,
+        }
+
+        let header = URL_SAFE_NO_PAD.encode(r#"{"typ":"JWT","alg":"ES256"}"#);
+        let claims = URL_SAFE_NO_PAD.encode(serde_json::to_vec(&Claims {
+            aud: endpoint.origin().ascii_serialization(),
+            exp: (Utc::now() + chrono::Duration::hours(12)).timestamp(),
+            sub: self.subject.as_deref(),
+        }).unwrap());
+        let signed = format!("{}.{}", header, claims);
+        let signature: Signature = self.key.sign(signed.as_bytes());
+        return format!(
+            "vapid t={}.{}, k={}",
+            signed,
+            URL_SAFE_NO_PAD.encode(signature.to_bytes()),
+            URL_SAFE_NO_PAD.encode(&self.public_key)
+        );
+    }
+
+    /// Encrypt and send `payload` to the subscription (JSON).
+    pub async fn push(&self, subscription: &str, payload: &[u8]) -> Result<(), PushError> {
+        let subscription =
+            serde_json::from_str::<Subscription>(subscription).context("Invalid push subscription")?;
+        let endpoint = Url::parse(&subscription.endpoint).context("Invalid push subscription endpoint")?;
+        let body =
+            encrypt(
+                &BASE64_URL.decode(&subscription.keys.p256dh).context("Invalid subscription p256dh key")?,
+                &BASE64_URL.decode(&subscription.keys.auth).context("Invalid subscription auth secret")?,
+                payload,
+            )?;
+        let resp =
+            self
+                .client
+                .post(endpoint.clone())
+                .header("TTL", TTL.as_secs().to_string())
+                .header("Content-Encoding", "aes128gcm")
+                .header("Content-Type", "application/octet-stream")
+                .header("Authorization", self.authorization(&endpoint))
+                .body(body)
+                .send()
+                .await
+                .context("Error sending push request")?;
+        match resp.status() {
+            StatusCode::NOT_FOUND | StatusCode::GONE => {
+                return Err(PushError::Gone);
+            },
+            s if s.is_success() => {
+                return Ok(());
+            },
+            s => {
+                return Err(PushError::Other(loga::err_with("Push service rejected message", ea!(status = s))));
+            },
+        }
+    }
+}
+
+/// Derive the content encryption key and nonce (RFC 8291 section 3.4).
+fn derive_key(
+    ecdh_secret: &[u8],
+    auth_secret: &[u8],
+    ua_public: &[u8],
+    as_public: &[u8],
+    salt: &[u8],
+) -> Result<([u8; 16], [u8; 12]), loga::Error> {
+    let mut key_info = b"WebPush: info\0".to_vec();
+    key_info.extend_from_slice(ua_public);
+    key_info.extend_from_slice(as_public);
+    let mut ikm = [0u8; 32];
+    Hkdf::<Sha256>::new(Some(auth_secret), ecdh_secret)
+        .expand(&key_info, &mut ikm)
+        .map_err(loga::Error::from)
+        .context("Failed to derive input keying material")?;
+    let prk = Hkdf::<Sha256>::new(Some(salt), &ikm);
+    let mut cek = [0u8; 16];
+    prk
+        .expand(b"Content-Encoding: aes128gcm\0", &mut cek)
+        .map_err(loga::Error::from)
+        .context("Failed to derive content encryption key")?;
+    let mut nonce = [0u8; 12];
+    prk.expand(b"Content-Encoding: nonce\0", &mut nonce).map_err(loga::Error::from).context("Failed to derive nonce")?;
+    return Ok((cek, nonce));
+}
+
+/// Encrypt a payload for a subscriber, producing a single-record `aes128gcm` body.
+pub fn encrypt(ua_public: &[u8], auth_secret: &[u8], payload: &[u8]) -> Result<Vec<u8>, loga::Error> {
+    if payload.len() + 1 + 16 > RECORD_SIZE as usize {
+        return Err(loga::err("Push payload too large"));
+    }
+    let ua_public_key =
+        PublicKey::from_sec1_bytes(ua_public).map_err(loga::Error::from).context("Invalid subscriber public key")?;
+    let as_secret = EphemeralSecret::random(&mut OsRng);
+    let as_public = as_secret.public_key().to_encoded_point(false).as_bytes().to_vec();
+    let ecdh_secret = as_secret.diffie_hellman(&ua_public_key);
+    let mut salt = [0u8; 16];
+    OsRng.fill_bytes(&mut salt);
+    let (cek, nonce) = derive_key(ecdh_secret.raw_secret_bytes(), auth_secret, ua_public, &as_public, &salt)?;
+    let mut plaintext = payload.to_vec();
+
+    // Last record delimiter, no padding
+    plaintext.push(2);
+    let ciphertext =
+        Aes128Gcm::new(&cek.into())
+            .encrypt(&nonce.into(), plaintext.as_slice())
+            .map_err(loga::Error::from)
+            .context("Failed to encrypt push payload")?;
+    let mut out = salt.to_vec();
+    out.extend_from_slice(&RECORD_SIZE.to_be_bytes());
+    out.push(as_public.len() as u8);
+    out.extend_from_slice(&as_public);
+    out.extend_from_slice(&ciphertext);
+    return Ok(out);
+}
+
+/// The inverse of `encrypt`, for the subscriber side.
+pub fn decrypt(ua_secret: &SecretKey, auth_secret: &[u8], body: &[u8]) -> Result<Vec<u8>, loga::Error> {
+    let header = body.get(.. 21).context("Push body is missing header")?;
+    let salt = &header[.. 16];
+    let id_len = header[20] as usize;
+    let as_public = body.get(21 .. 21 + id_len).context("Push body is missing key id")?;
+    let ciphertext = &body[21 + id_len ..];
+    let as_public_key =
+        PublicKey::from_sec1_bytes(as_public).map_err(loga::Error::from).context("Invalid sender public key")?;
+    let ecdh_secret = diffie_hellman(ua_secret.to_nonzero_scalar(), as_public_key.as_affine());
+    let ua_public = ua_secret.public_key().to_encoded_point(false).as_bytes().to_vec();
+    let (cek, nonce) = derive_key(ecdh_secret.raw_secret_bytes(), auth_secret, &ua_public, as_public, salt)?;
+    let mut plaintext =
+        Aes128Gcm::new(&cek.into())
+            .decrypt(&nonce.into(), ciphertext)
+            .map_err(loga::Error::from)
+            .context("Failed to decrypt push payload")?;
+
+    // Strip padding and the record delimiter
+    while plaintext.last() == Some(&0) {
+        plaintext.pop();
+    }
+    if plaintext.pop() != Some(2) {
+        return Err(loga::err("Push payload is missing last record delimiter"));
+    }
+    return Ok(plaintext);
+}
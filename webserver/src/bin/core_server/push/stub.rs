@@ -0,0 +1,202 @@
+//! A stand-in push service for testing delivery without a browser or a real push
+//! service. It acts as both the push service and the subscriber: it hands out
+//! subscriptions, checks the VAPID authorization on pushes it receives, decrypts
+//! them and records the payloads for inspection.
+//!
+//! * `POST /subscribe` - returns a new subscription, to be posted with
+//!   `U2SPost::SubscribePush`
+//!
+//! * `POST /endpoint/:id` - the push endpoint. Unknown subscriptions get 410.
+//!
+//! * `GET /received/:id` - the decrypted payloads received so far
+//!
+//! * `POST /unsubscribe/:id` - forget a subscription, as if the browser had
+//!   unsubscribed
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc,
+        Mutex,
+    },
+};
+use base64::{
+    engine::general_purpose::URL_SAFE_NO_PAD,
+    Engine,
+};
+use p256::{
+    ecdsa::{
+        signature::Verifier,
+        Signature,
+        VerifyingKey,
+    },
+    elliptic_curve::sec1::ToEncodedPoint,
+    SecretKey,
+};
+use poem::{
+    handler,
+    http::{
+        HeaderMap,
+        StatusCode,
+    },
+    post,
+    get,
+    web::{
+        Data,
+        Json,
+        Path,
+    },
+    Body,
+    EndpointExt,
+    IntoEndpoint,
+    IntoResponse,
+    Response,
+    Route,
+};
+use rand::{
+    rngs::OsRng,
+    RngCore,
+};
+use crate::core_server::storage::random_id;
+use super::{
+    decrypt,
+    Subscription,
+    SubscriptionKeys,
+    BASE64_URL,
+};
+
+struct Subscriber {
+    secret: SecretKey,
+    auth: [u8; 16],
+    received: Vec<serde_json::Value>,
+}
+
+pub struct PushStub {
+    /// Externally reachable url of the stub's root, used to build endpoints
+    base_url: String,
+    subscribers: Mutex<HashMap<String, Subscriber>>,
+}
+
+impl PushStub {
+    pub fn new(base_url: String) -> Arc<PushStub> {
+        return Arc::new(PushStub {
+            base_url: base_url,
+            subscribers: Default::default(),
+        });
+    }
+
+    pub fn route(self: Arc<PushStub>) -> impl IntoEndpoint {
+        return Route::new()
+            .at("/subscribe", post(subscribe))
+            .at("/endpoint/:id", post(endpoint))
+            .at("/received/:id", get(received))
+            .at("/unsubscribe/:id", post(unsubscribe))
+            .with(poem::middleware::AddData::new(self));
+    }
+}
+
+fn err(status: StatusCode, message: &str) -> poem::Error {
+    return poem::Error::from_string(message, status);
+}
+
+/// Check the `vapid t=JWT, k=KEY` authorization header signature.
+fn verify_vapid(headers: &HeaderMap) -> Result<(), poem::Error> {
+    let unauthorized = |m: &str| err(StatusCode::UNAUTHORIZED, m);
+    let auth =
+        headers
+            .get("Authorization")
+            .and_then(|h| h.to_str().ok())
+            .and_then(|h| h.strip_prefix("vapid "))
+            .ok_or_else(|| unauthorized("Missing vapid authorization"))?;
+    let mut token = None;
+    let mut key = None;
+    for part in auth.split(',') {
+        match part.trim().split_once('=') {
+            Some(("t", v)) => token = Some(v),
+            Some(("k", v)) => key = Some(v),
+            _ => { },
+        }
+    }
+    let token = token.ok_or_else(|| unauthorized("Missing vapid token"))?;
+    let key = key.ok_or_else(|| unauthorized("Missing vapid key"))?;
+    let (signed, signature) = token.rsplit_once('.').ok_or_else(|| unauthorized("Malformed vapid token"))?;
+    let key =
+        BASE64_URL
+            .decode(key)
+            .ok()
+            .and_then(|k| VerifyingKey::from_sec1_bytes(&k).ok())
+            .ok_or_else(|| unauthorized("Invalid vapid key"))?;
+    let signature =
+        URL_SAFE_NO_PAD
+            .decode(signature)
+            .ok()
+            .and_then(|s| Signature::from_slice(&s).ok())
+            .ok_or_else(|| unauthorized("Invalid vapid signature"))?;
+    key.verify(signed.as_bytes(), &signature).map_err(|_| unauthorized("Vapid signature doesn't match"))?;
+    return Ok(());
+}
+
+#[handler]
+async fn subscribe(Data(stub): Data<&Arc<PushStub>>) -> Json<Subscription> {
+    let id = random_id();
+    let secret = SecretKey::random(&mut OsRng);
+    let mut auth = [0u8; 16];
+    OsRng.fill_bytes(&mut auth);
+    let sub = Subscription {
+        endpoint: format!("{}/endpoint/{}", stub.base_url, id),
+        keys: SubscriptionKeys {
+            p256dh: URL_SAFE_NO_PAD.encode(secret.public_key().to_encoded_point(false).as_bytes()),
+            auth: URL_SAFE_NO_PAD.encode(auth),
+        },
+    };
+    stub.subscribers.lock().unwrap().insert(id, Subscriber {
+        secret: secret,
+        auth: auth,
+        received: vec![],
+    });
+    return Json(sub);
+}
+
+#[handler]
+async fn endpoint(
+    Data(stub): Data<&Arc<PushStub>>,
+    Path(id): Path<String>,
+    headers: &HeaderMap,
+    body: Body,
+) -> Result<Response, poem::Error> {
+    verify_vapid(headers)?;
+    if headers.get("Content-Encoding").and_then(|h| h.to_str().ok()) != Some("aes128gcm") {
+        return Err(err(StatusCode::BAD_REQUEST, "Unsupported content encoding"));
+    }
+    let body = body.into_vec().await?;
+    let mut subscribers = stub.subscribers.lock().unwrap();
+    let Some(subscriber) = subscribers.get_mut(&id) else {
+        return Err(err(StatusCode::GONE, "Unknown subscription"));
+    };
+    let payload =
+        decrypt(
+            &subscriber.secret,
+            &subscriber.auth,
+            &body,
+        ).map_err(|e| err(StatusCode::BAD_REQUEST, &format!("Failed to decrypt payload: {}", e)))?;
+    let payload =
+        serde_json::from_slice(
+            &payload,
+        ).map_err(|e| err(StatusCode::BAD_REQUEST, &format!("Payload isn't json: {}", e)))?;
+    subscriber.received.push(payload);
+    return Ok(StatusCode::CREATED.into_response());
+}
+
+#[handler]
+async fn received(
+    Data(stub): Data<&Arc<PushStub>>,
+    Path(id): Path<String>,
+) -> Result<Json<Vec<serde_json::Value>>, poem::Error> {
+    let subscribers = stub.subscribers.lock().unwrap();
+    let subscriber = subscribers.get(&id).ok_or_else(|| err(StatusCode::NOT_FOUND, "Unknown subscription"))?;
+    return Ok(Json(subscriber.received.clone()));
+}
+
+#[handler]
+async fn unsubscribe(Data(stub): Data<&Arc<PushStub>>, Path(id): Path<String>) {
+    stub.subscribers.lock().unwrap().remove(&id);
+}
@@ -0,0 +1,110 @@
+//! Delivery through the push stub, end to end over http.
+use std::sync::Arc;
+use chrono::{
+    Duration,
+    Utc,
+};
+use poem::{
+    listener::{
+        Acceptor,
+        Listener,
+        TcpListener,
+    },
+    Server,
+};
+use webserver::interface::u2s::{
+    MessageId,
+    S2SWPush,
+};
+use crate::core_server::{
+    storage::{
+        memory::MemoryStorage,
+        Session,
+        Storage,
+    },
+    CoreServer,
+};
+use super::{
+    stub::PushStub,
+    Subscription,
+};
+
+/// A stub listening on a free local port, returning its base url.
+async fn start_stub() -> String {
+    let acceptor = TcpListener::bind("127.0.0.1:0").into_acceptor().await.unwrap();
+    let base_url = format!("http://{}", acceptor.local_addr()[0].as_socket_addr().unwrap());
+    let stub = PushStub::new(base_url.clone());
+    tokio::spawn(Server::new_with_acceptor(acceptor).run(stub.route()));
+    return base_url;
+}
+
+async fn post(client: &reqwest::Client, url: String) -> Vec<u8> {
+    let resp = client.post(url).send().await.unwrap();
+    assert!(resp.status().is_success());
+    return resp.bytes().await.unwrap().to_vec();
+}
+
+/// Log the user in and subscribe their session through the stub, returning the
+/// stub's id for the subscription.
+async fn subscribe(client: &reqwest::Client, base_url: &str, storage: &dyn Storage, user: &str) -> String {
+    let sub = post(client, format!("{}/subscribe", base_url)).await;
+    let id = serde_json::from_slice::<Subscription>(&sub).unwrap().endpoint.rsplit('/').next().unwrap().to_string();
+    let token = format!("token-{}", user);
+    storage.create_session(token.clone(), Session {
+        user: user.to_string(),
+        expires: Utc::now() + Duration::days(1),
+    }).await.unwrap();
+    storage.add_push_subscription(token, String::from_utf8(sub).unwrap()).await.unwrap();
+    return id;
+}
+
+async fn received(client: &reqwest::Client, base_url: &str, id: &str) -> Vec<serde_json::Value> {
+    let resp = client.get(format!("{}/received/{}", base_url, id)).send().await.unwrap();
+    return serde_json::from_slice(&resp.bytes().await.unwrap()).unwrap();
+}
+
+#[tokio::test]
+async fn push_delivery() {
+    let base_url = start_stub().await;
+    let client = reqwest::Client::new();
+    let storage = Arc::new(MemoryStorage::new());
+    let core =
+        CoreServer::new(
+            &loga::new(loga::Level::Info),
+            storage.clone(),
+            Duration::days(1),
+            Duration::days(1),
+            None,
+        ).await.unwrap();
+    let alice = storage.create_identity("alice".to_string(), "Alice".to_string()).await.unwrap().id;
+    let bob = storage.create_identity("bob".to_string(), "Bob".to_string()).await.unwrap().id;
+    storage.create_identity("carol".to_string(), "Carol".to_string()).await.unwrap();
+    let channel = storage.create_channel(alice, "general".to_string(), Utc::now()).await.unwrap().unwrap();
+    storage.join_channel(channel.clone(), bob, Utc::now()).await.unwrap().unwrap();
+    let alice_sub = subscribe(&client, &base_url, storage.as_ref(), "alice").await;
+    let bob_sub = subscribe(&client, &base_url, storage.as_ref(), "bob").await;
+    let carol_sub = subscribe(&client, &base_url, storage.as_ref(), "carol").await;
+
+    // Only other members are notified
+    let id = MessageId(channel.clone(), 1);
+    let time = Utc::now();
+    core.push_message("alice".to_string(), id.clone(), time, "hello".to_string()).await;
+    let bob_received = received(&client, &base_url, &bob_sub).await;
+    assert_eq!(bob_received.len(), 1);
+    let push = serde_json::from_value::<S2SWPush>(bob_received[0].clone()).unwrap();
+    assert_eq!(push.id, id);
+    assert_eq!(push.time, time);
+    assert_eq!(push.title, "general");
+    assert_eq!(push.quote, "hello");
+    assert!(received(&client, &base_url, &alice_sub).await.is_empty());
+    assert!(received(&client, &base_url, &carol_sub).await.is_empty());
+
+    // Subscriptions the stub has forgotten get 410 and are deleted
+    post(&client, format!("{}/unsubscribe/{}", base_url, bob_sub)).await;
+    assert_eq!(
+        storage.list_push_subscriptions(channel.clone(), "alice".to_string(), Utc::now()).await.unwrap().len(),
+        1
+    );
+    core.push_message("alice".to_string(), MessageId(channel.clone(), 2), Utc::now(), "again".to_string()).await;
+    assert!(storage.list_push_subscriptions(channel, "alice".to_string(), Utc::now()).await.unwrap().is_empty());
+}
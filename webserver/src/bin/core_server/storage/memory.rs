@@ -0,0 +1,665 @@
+use std::{
+    collections::{
+        BTreeMap,
+        BTreeSet,
+        HashMap,
+        HashSet,
+    },
+    sync::Mutex,
+};
+use async_trait::async_trait;
+use chrono::{
+    DateTime,
+    SubsecRound,
+    Utc,
+};
+use webserver::interface::u2s::{
+    IdentityId,
+    ChannelId,
+    MessageId,
+    BrewId,
+    S2UIdentity,
+    S2UChannel,
+    S2UChannelSummary,
+    S2UBrew,
+    S2UMessage,
+    S2UEvent,
+    S2UEventKind,
+    S2UEventsGetAfterResp,
+    S2USnapGetAroundResp,
+    S2UGetBeforeResp,
+    S2UGetAfterResp,
+};
+use super::{
+    random_id,
+    LocalSendId,
+    Sent,
+    Session,
+    Storage,
+};
+
+struct Message {
+    seq: u64,
+    author: IdentityId,
+    time: DateTime<Utc>,
+    _reply: Option<MessageId>,
+    text: String,
+}
+
+impl Message {
+    fn resp(&self, channel: &ChannelId) -> S2UMessage {
+        return S2UMessage {
+            id: MessageId(channel.clone(), self.seq),
+            author: self.author.clone(),
+            time: self.time,
+            text: self.text.clone(),
+        };
+    }
+}
+
+struct Channel {
+    name: String,
+    members: BTreeSet<IdentityId>,
+    messages: Vec<Message>,
+    events: Vec<S2UEvent>,
+}
+
+impl Channel {
+    fn server_time(&self, id: &ChannelId) -> MessageId {
+        return MessageId(id.clone(), self.events.last().map(|e| e.id.1).unwrap_or(0));
+    }
+
+    /// Append an event to the log, assigning it the next id. `kind` gets the new
+    /// event's id.
+    fn append_event(
+        &mut self,
+        id: &ChannelId,
+        time: DateTime<Utc>,
+        kind: impl FnOnce(&MessageId) -> S2UEventKind,
+    ) -> S2UEvent {
+        let id = MessageId(id.clone(), self.server_time(id).1 + 1);
+        let kind = kind(&id);
+        let event = S2UEvent {
+            id: id,
+            // Match the precision of persistent storage
+            time: time.trunc_subsecs(6),
+            kind: kind,
+        };
+        self.events.push(event.clone());
+        return event;
+    }
+
+    fn find_message(&self, id: &MessageId) -> Option<usize> {
+        return self.messages.binary_search_by_key(&id.1, |m| m.seq).ok();
+    }
+
+    fn resp(&self, id: &ChannelId, start: usize, end: usize) -> Vec<S2UMessage> {
+        return self.messages[start .. end].iter().map(|m| m.resp(id)).collect();
+    }
+}
+
+struct Identity {
+    owner: String,
+    identity: S2UIdentity,
+}
+
+struct Brew {
+    owner: String,
+    brew: S2UBrew,
+}
+
+#[derive(Default)]
+struct State {
+    identities: Vec<Identity>,
+    channels: BTreeMap<ChannelId, Channel>,
+    brews: BTreeMap<BrewId, Brew>,
+    users: HashMap<String, String>,
+    sessions: HashMap<String, Session>,
+    push_key: Option<Vec<u8>>,
+    /// Subscription to session token
+    push_subscriptions: BTreeMap<String, String>,
+    /// User and channel to last read seq
+    read_positions: HashMap<(String, ChannelId), u64>,
+    /// User and local id to the time it was sent and the resulting message
+    sent_local_ids: HashMap<(String, String), (DateTime<Utc>, MessageId)>,
+}
+
+impl State {
+    /// True if the user owns one of the channel's member identities.
+    fn is_member(&self, user: &str, channel: &Channel) -> bool {
+        return self.identities.iter().any(|i| i.owner == user && channel.members.contains(&i.identity.id));
+    }
+
+    /// Append a brew event to the log of each of the channels, skipping duplicates and
+    /// channels that don't exist.
+    fn append_brew_events<'a>(
+        &mut self,
+        channels: impl IntoIterator<Item = &'a ChannelId>,
+        time: DateTime<Utc>,
+        kind: S2UEventKind,
+    ) -> Vec<S2UEvent> {
+        let mut out = vec![];
+        let mut seen = HashSet::new();
+        for id in channels {
+            if !seen.insert(id) {
+                continue;
+            }
+            let Some(c) = self.channels.get_mut(id) else {
+                continue;
+            };
+            out.push(c.append_event(id, time, |_| kind.clone()));
+        }
+        return out;
+    }
+}
+
+/// Non-persistent storage, for tests.
+#[derive(Default)]
+pub struct MemoryStorage(Mutex<State>);
+
+impl MemoryStorage {
+    pub fn new() -> MemoryStorage {
+        return MemoryStorage::default();
+    }
+}
+
+#[async_trait]
+impl Storage for MemoryStorage {
+    async fn get_identity(&self, id: IdentityId) -> Result<Option<S2UIdentity>, loga::Error> {
+        let state = self.0.lock().unwrap();
+        return Ok(state.identities.iter().find(|i| i.identity.id == id).map(|i| i.identity.clone()));
+    }
+
+    async fn get_identities(&self, ids: Vec<IdentityId>) -> Result<Vec<Option<S2UIdentity>>, loga::Error> {
+        let state = self.0.lock().unwrap();
+        return Ok(
+            ids
+                .into_iter()
+                .map(|id| state.identities.iter().find(|i| i.identity.id == id).map(|i| i.identity.clone()))
+                .collect(),
+        );
+    }
+
+    async fn create_identity(&self, user: String, name: String) -> Result<S2UIdentity, loga::Error> {
+        let identity = S2UIdentity {
+            id: IdentityId(random_id()),
+            name: name,
+            avatar: None,
+        };
+        self.0.lock().unwrap().identities.push(Identity {
+            owner: user,
+            identity: identity.clone(),
+        });
+        return Ok(identity);
+    }
+
+    async fn list_identities(&self, user: String) -> Result<Vec<S2UIdentity>, loga::Error> {
+        let state = self.0.lock().unwrap();
+        return Ok(state.identities.iter().filter(|i| i.owner == user).map(|i| i.identity.clone()).collect());
+    }
+
+    async fn rename_identity(&self, user: String, id: IdentityId, name: String) -> Result<bool, loga::Error> {
+        let mut state = self.0.lock().unwrap();
+        let Some(i) = state.identities.iter_mut().find(|i| i.identity.id == id && i.owner == user) else {
+            return Ok(false);
+        };
+        i.identity.name = name;
+        return Ok(true);
+    }
+
+    async fn create_channel(
+        &self,
+        owner: IdentityId,
+        name: String,
+        time: DateTime<Utc>,
+    ) -> Result<Option<ChannelId>, loga::Error> {
+        let mut state = self.0.lock().unwrap();
+        let idx = match state.channels.keys().filter(|c| c.0 == owner).map(|c| c.1).max() {
+            Some(i) => match i.checked_add(1) {
+                Some(i) => i,
+                None => return Ok(None),
+            },
+            None => 0,
+        };
+        let id = ChannelId(owner.clone(), idx);
+        let mut channel = Channel {
+            name: name,
+            members: [owner.clone()].into_iter().collect(),
+            messages: vec![],
+            events: vec![],
+        };
+        channel.append_event(&id, time, |_| S2UEventKind::MemberJoined {
+            channel: id.clone(),
+            identity: owner,
+        });
+        state.channels.insert(id.clone(), channel);
+        return Ok(Some(id));
+    }
+
+    async fn get_channel(&self, id: ChannelId) -> Result<Option<S2UChannel>, loga::Error> {
+        let state = self.0.lock().unwrap();
+        return Ok(state.channels.get(&id).map(|c| S2UChannel {
+            id: id.clone(),
+            name: c.name.clone(),
+        }));
+    }
+
+    async fn get_channel_members(&self, id: ChannelId) -> Result<Option<Vec<IdentityId>>, loga::Error> {
+        let state = self.0.lock().unwrap();
+        return Ok(state.channels.get(&id).map(|c| c.members.iter().cloned().collect()));
+    }
+
+    async fn get_channel_users(&self, id: ChannelId) -> Result<Vec<String>, loga::Error> {
+        let state = self.0.lock().unwrap();
+        let Some(c) = state.channels.get(&id) else {
+            return Ok(vec![]);
+        };
+        return Ok(
+            state
+                .identities
+                .iter()
+                .filter(|i| c.members.contains(&i.identity.id))
+                .map(|i| i.owner.clone())
+                .collect::<BTreeSet<_>>()
+                .into_iter()
+                .collect(),
+        );
+    }
+
+    async fn get_channels(&self, ids: Vec<ChannelId>) -> Result<Vec<Option<S2UChannel>>, loga::Error> {
+        let state = self.0.lock().unwrap();
+        return Ok(ids.into_iter().map(|id| state.channels.get(&id).map(|c| S2UChannel {
+            name: c.name.clone(),
+            id: id,
+        })).collect());
+    }
+
+    async fn list_channels(&self, user: String) -> Result<Vec<S2UChannel>, loga::Error> {
+        let state = self.0.lock().unwrap();
+        return Ok(state.channels.iter().filter(|(_, c)| state.is_member(&user, c)).map(|(id, c)| S2UChannel {
+            id: id.clone(),
+            name: c.name.clone(),
+        }).collect());
+    }
+
+    async fn list_channel_summaries(&self, user: String) -> Result<Vec<S2UChannelSummary>, loga::Error> {
+        let state = self.0.lock().unwrap();
+        return Ok(state.channels.iter().filter(|(_, c)| state.is_member(&user, c)).map(|(id, c)| {
+            let read = state.read_positions.get(&(user.clone(), id.clone())).cloned().unwrap_or(0);
+            return S2UChannelSummary {
+                channel: S2UChannel {
+                    id: id.clone(),
+                    name: c.name.clone(),
+                },
+                last_message: c.resp(id, c.messages.len().saturating_sub(1), c.messages.len()).pop(),
+                unread: c.messages.iter().filter(|m| m.seq > read).count() as u64,
+            };
+        }).collect());
+    }
+
+    async fn get_read_position(&self, user: String, channel: ChannelId) -> Result<Option<MessageId>, loga::Error> {
+        let state = self.0.lock().unwrap();
+        return Ok(state.read_positions.get(&(user, channel.clone())).map(|seq| MessageId(channel, *seq)));
+    }
+
+    async fn set_read_position(&self, user: String, id: MessageId) -> Result<Option<MessageId>, loga::Error> {
+        let mut state = self.0.lock().unwrap();
+        if !state.channels.contains_key(&id.0) {
+            return Ok(None);
+        }
+        let seq = state.read_positions.entry((user, id.0.clone())).or_default();
+        *seq = (*seq).max(id.1);
+        return Ok(Some(MessageId(id.0, *seq)));
+    }
+
+    async fn rename_channel(
+        &self,
+        id: ChannelId,
+        time: DateTime<Utc>,
+        name: String,
+    ) -> Result<Option<S2UEvent>, loga::Error> {
+        let mut state = self.0.lock().unwrap();
+        let Some(c) = state.channels.get_mut(&id) else {
+            return Ok(None);
+        };
+        c.name = name.clone();
+        return Ok(Some(c.append_event(&id, time, |_| S2UEventKind::ChannelRenamed {
+            id: id.clone(),
+            name: name,
+        })));
+    }
+
+    async fn join_channel(
+        &self,
+        id: ChannelId,
+        identity: IdentityId,
+        time: DateTime<Utc>,
+    ) -> Result<Option<S2UEvent>, loga::Error> {
+        let mut state = self.0.lock().unwrap();
+        let Some(c) = state.channels.get_mut(&id) else {
+            return Ok(None);
+        };
+        if !c.members.insert(identity.clone()) {
+            return Ok(None);
+        }
+        return Ok(Some(c.append_event(&id, time, |_| S2UEventKind::MemberJoined {
+            channel: id.clone(),
+            identity: identity,
+        })));
+    }
+
+    async fn leave_channel(
+        &self,
+        id: ChannelId,
+        identity: IdentityId,
+        time: DateTime<Utc>,
+    ) -> Result<Option<S2UEvent>, loga::Error> {
+        let mut state = self.0.lock().unwrap();
+        let Some(c) = state.channels.get_mut(&id) else {
+            return Ok(None);
+        };
+        if !c.members.remove(&identity) {
+            return Ok(None);
+        }
+        return Ok(Some(c.append_event(&id, time, |_| S2UEventKind::MemberLeft {
+            channel: id.clone(),
+            identity: identity,
+        })));
+    }
+
+    async fn get_brew(&self, owner: String, id: BrewId) -> Result<Option<S2UBrew>, loga::Error> {
+        let state = self.0.lock().unwrap();
+        return Ok(state.brews.get(&id).filter(|b| b.owner == owner).map(|b| b.brew.clone()));
+    }
+
+    async fn get_brews(&self, owner: String, ids: Vec<BrewId>) -> Result<Vec<Option<S2UBrew>>, loga::Error> {
+        let state = self.0.lock().unwrap();
+        return Ok(
+            ids
+                .into_iter()
+                .map(|id| state.brews.get(&id).filter(|b| b.owner == owner).map(|b| b.brew.clone()))
+                .collect(),
+        );
+    }
+
+    async fn list_brews(&self, owner: String) -> Result<Vec<S2UBrew>, loga::Error> {
+        let state = self.0.lock().unwrap();
+        return Ok(state.brews.values().filter(|b| b.owner == owner).map(|b| b.brew.clone()).collect());
+    }
+
+    async fn create_brew(
+        &self,
+        owner: String,
+        name: String,
+        channels: Vec<ChannelId>,
+        time: DateTime<Utc>,
+    ) -> Result<(BrewId, Vec<S2UEvent>), loga::Error> {
+        let mut state = self.0.lock().unwrap();
+        let id = BrewId(state.brews.keys().last().map(|i| i.0 + 1).unwrap_or(1));
+        let brew = S2UBrew {
+            id: id.clone(),
+            name: name,
+            channels: channels,
+        };
+        state.brews.insert(id.clone(), Brew {
+            owner: owner,
+            brew: brew.clone(),
+        });
+        let events = state.append_brew_events(&brew.channels, time, S2UEventKind::BrewChanged(brew.clone()));
+        return Ok((id, events));
+    }
+
+    async fn update_brew(
+        &self,
+        owner: String,
+        id: BrewId,
+        name: Option<String>,
+        channels: Option<Vec<ChannelId>>,
+        time: DateTime<Utc>,
+    ) -> Result<Option<Vec<S2UEvent>>, loga::Error> {
+        let mut state = self.0.lock().unwrap();
+        let Some(b) = state.brews.get_mut(&id).filter(|b| b.owner == owner) else {
+            return Ok(None);
+        };
+        let old_channels = b.brew.channels.clone();
+        if let Some(name) = name {
+            b.brew.name = name;
+        }
+        if let Some(channels) = channels {
+            b.brew.channels = channels;
+        }
+        let brew = b.brew.clone();
+        return Ok(
+            Some(
+                state.append_brew_events(
+                    old_channels.iter().chain(brew.channels.iter()),
+                    time,
+                    S2UEventKind::BrewChanged(brew.clone()),
+                ),
+            ),
+        );
+    }
+
+    async fn delete_brew(
+        &self,
+        owner: String,
+        id: BrewId,
+        time: DateTime<Utc>,
+    ) -> Result<Option<Vec<S2UEvent>>, loga::Error> {
+        let mut state = self.0.lock().unwrap();
+        if !state.brews.get(&id).map(|b| b.owner == owner).unwrap_or(false) {
+            return Ok(None);
+        }
+        let brew = state.brews.remove(&id).unwrap().brew;
+        return Ok(Some(state.append_brew_events(&brew.channels, time, S2UEventKind::BrewDeleted(id))));
+    }
+
+    async fn send(
+        &self,
+        channel: ChannelId,
+        author: IdentityId,
+        reply: Option<MessageId>,
+        time: DateTime<Utc>,
+        text: String,
+        local_id: Option<LocalSendId>,
+    ) -> Result<Option<Sent>, loga::Error> {
+        let mut state = self.0.lock().unwrap();
+        let state = &mut *state;
+        let Some(c) = state.channels.get_mut(&channel) else {
+            return Ok(None);
+        };
+        if let Some(local_id) = &local_id {
+            state.sent_local_ids.retain(|_, (sent, _)| *sent >= local_id.expire_before);
+            if let Some((_, existing)) =
+                state.sent_local_ids.get(&(local_id.user.clone(), local_id.local_id.clone())) {
+                return Ok(Some(Sent::Existing(existing.clone())));
+            }
+        }
+        let event = c.append_event(&channel, time, |id| S2UEventKind::MessageCreated(S2UMessage {
+            id: id.clone(),
+            author: author.clone(),
+            time: time.trunc_subsecs(6),
+            text: text.clone(),
+        }));
+        c.messages.push(Message {
+            seq: event.id.1,
+            author: author,
+            time: event.time,
+            _reply: reply,
+            text: text,
+        });
+        if let Some(local_id) = local_id {
+            state.sent_local_ids.insert((local_id.user, local_id.local_id), (time, event.id.clone()));
+        }
+        return Ok(Some(Sent::New(event)));
+    }
+
+    async fn get_message_author(&self, id: MessageId) -> Result<Option<IdentityId>, loga::Error> {
+        let state = self.0.lock().unwrap();
+        let Some(c) = state.channels.get(&id.0) else {
+            return Ok(None);
+        };
+        return Ok(c.find_message(&id).map(|i| c.messages[i].author.clone()));
+    }
+
+    async fn edit_message(
+        &self,
+        id: MessageId,
+        time: DateTime<Utc>,
+        text: String,
+    ) -> Result<Option<S2UEvent>, loga::Error> {
+        let mut state = self.0.lock().unwrap();
+        let Some(c) = state.channels.get_mut(&id.0) else {
+            return Ok(None);
+        };
+        let Some(i) = c.find_message(&id) else {
+            return Ok(None);
+        };
+        c.messages[i].text = text.clone();
+        return Ok(Some(c.append_event(&id.0, time, |_| S2UEventKind::MessageEdited {
+            id: id.clone(),
+            text: text,
+        })));
+    }
+
+    async fn delete_message(&self, id: MessageId, time: DateTime<Utc>) -> Result<Option<S2UEvent>, loga::Error> {
+        let mut state = self.0.lock().unwrap();
+        let Some(c) = state.channels.get_mut(&id.0) else {
+            return Ok(None);
+        };
+        let Some(i) = c.find_message(&id) else {
+            return Ok(None);
+        };
+        c.messages.remove(i);
+        return Ok(Some(c.append_event(&id.0, time, |_| S2UEventKind::MessageDeleted(id.clone()))));
+    }
+
+    async fn events_after(&self, id: MessageId, count: u64) -> Result<Option<S2UEventsGetAfterResp>, loga::Error> {
+        let state = self.0.lock().unwrap();
+        let Some(c) = state.channels.get(&id.0) else {
+            return Ok(None);
+        };
+        let start = c.events.partition_point(|e| e.id.1 <= id.1);
+        let end = (start + count as usize).min(c.events.len());
+        return Ok(Some(S2UEventsGetAfterResp {
+            server_time: c.server_time(&id.0),
+            entries: c.events[start .. end].to_vec(),
+        }));
+    }
+
+    async fn snap_around(
+        &self,
+        channel: ChannelId,
+        time: DateTime<Utc>,
+        count: u64,
+    ) -> Result<Option<S2USnapGetAroundResp>, loga::Error> {
+        let state = self.0.lock().unwrap();
+        let Some(c) = state.channels.get(&channel) else {
+            return Ok(None);
+        };
+        // Times needn't be in id order, so split by time rather than position
+        let before = c.messages.iter().filter(|m| m.time < time).collect::<Vec<_>>();
+        let after = c.messages.iter().filter(|m| m.time >= time).collect::<Vec<_>>();
+        let start = before.len().saturating_sub(count as usize);
+        let end = after.len().min(count as usize);
+        return Ok(Some(S2USnapGetAroundResp {
+            server_time: c.server_time(&channel),
+            entries: before[start ..].iter().chain(&after[.. end]).map(|m| m.resp(&channel)).collect(),
+            early_stop: start == 0,
+            late_stop: end == after.len(),
+        }));
+    }
+
+    async fn snap_before(&self, id: MessageId, count: u64) -> Result<Option<S2UGetBeforeResp>, loga::Error> {
+        let state = self.0.lock().unwrap();
+        let Some(c) = state.channels.get(&id.0) else {
+            return Ok(None);
+        };
+        let end = c.messages.partition_point(|m| m.seq < id.1);
+        let start = end.saturating_sub(count as usize);
+        return Ok(Some(S2UGetBeforeResp {
+            server_time: c.server_time(&id.0),
+            entries: c.resp(&id.0, start, end),
+            early_stop: start == 0,
+        }));
+    }
+
+    async fn snap_after(&self, id: MessageId, count: u64) -> Result<Option<S2UGetAfterResp>, loga::Error> {
+        let state = self.0.lock().unwrap();
+        let Some(c) = state.channels.get(&id.0) else {
+            return Ok(None);
+        };
+        let start = c.messages.partition_point(|m| m.seq <= id.1);
+        let end = (start + count as usize).min(c.messages.len());
+        return Ok(Some(S2UGetAfterResp {
+            server_time: c.server_time(&id.0),
+            entries: c.resp(&id.0, start, end),
+            late_stop: end == c.messages.len(),
+        }));
+    }
+
+    async fn set_user(&self, username: String, password_hash: String) -> Result<(), loga::Error> {
+        self.0.lock().unwrap().users.insert(username, password_hash);
+        return Ok(());
+    }
+
+    async fn get_user_password_hash(&self, username: String) -> Result<Option<String>, loga::Error> {
+        return Ok(self.0.lock().unwrap().users.get(&username).cloned());
+    }
+
+    async fn create_session(&self, token: String, session: Session) -> Result<(), loga::Error> {
+        self.0.lock().unwrap().sessions.insert(token, session);
+        return Ok(());
+    }
+
+    async fn get_session(&self, token: String, now: DateTime<Utc>) -> Result<Option<Session>, loga::Error> {
+        let mut state = self.0.lock().unwrap();
+        let Some(found) = state.sessions.get(&token) else {
+            return Ok(None);
+        };
+        if found.expires > now {
+            return Ok(Some(found.clone()));
+        }
+        state.sessions.retain(|_, s| s.expires > now);
+        let state = &mut *state;
+        state.push_subscriptions.retain(|_, session| state.sessions.contains_key(session));
+        return Ok(None);
+    }
+
+    async fn delete_session(&self, token: String) -> Result<(), loga::Error> {
+        let mut state = self.0.lock().unwrap();
+        state.sessions.remove(&token);
+        state.push_subscriptions.retain(|_, session| *session != token);
+        return Ok(());
+    }
+
+    async fn own_push_key(&self, new: Vec<u8>) -> Result<Vec<u8>, loga::Error> {
+        return Ok(self.0.lock().unwrap().push_key.get_or_insert(new).clone());
+    }
+
+    async fn add_push_subscription(&self, session: String, subscription: String) -> Result<(), loga::Error> {
+        self.0.lock().unwrap().push_subscriptions.insert(subscription, session);
+        return Ok(());
+    }
+
+    async fn list_push_subscriptions(
+        &self,
+        channel: ChannelId,
+        exclude_user: String,
+        now: DateTime<Utc>,
+    ) -> Result<Vec<String>, loga::Error> {
+        let state = self.0.lock().unwrap();
+        let Some(c) = state.channels.get(&channel) else {
+            return Ok(vec![]);
+        };
+        return Ok(state.push_subscriptions.iter().filter(|(_, token)| match state.sessions.get(*token) {
+            Some(s) => s.user != exclude_user && s.expires > now && state.is_member(&s.user, c),
+            None => false,
+        }).map(|(sub, _)| sub.clone()).collect());
+    }
+
+    async fn delete_push_subscription(&self, subscription: String) -> Result<(), loga::Error> {
+        self.0.lock().unwrap().push_subscriptions.remove(&subscription);
+        return Ok(());
+    }
+}
@@ -0,0 +1,254 @@
+use async_trait::async_trait;
+use chrono::{
+    DateTime,
+    Utc,
+};
+use rand::{
+    distributions::Alphanumeric,
+    Rng,
+};
+use webserver::interface::u2s::{
+    IdentityId,
+    ChannelId,
+    MessageId,
+    BrewId,
+    S2UEvent,
+    S2UIdentity,
+    S2UChannel,
+    S2UChannelSummary,
+    S2UBrew,
+    S2UEventsGetAfterResp,
+    S2USnapGetAroundResp,
+    S2UGetBeforeResp,
+    S2UGetAfterResp,
+};
+
+pub mod sqlite;
+pub mod memory;
+#[cfg(test)]
+mod tests;
+
+pub fn random_id() -> String {
+    return rand::thread_rng().sample_iter(&Alphanumeric).take(16).map(char::from).collect();
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Session {
+    pub user: String,
+    pub expires: DateTime<Utc>,
+}
+
+/// Identifies a send by the id the client gave it, so a retried send isn't stored
+/// twice.
+pub struct LocalSendId {
+    pub user: String,
+    pub local_id: String,
+    /// Local ids recorded before this are forgotten.
+    pub expire_before: DateTime<Utc>,
+}
+
+pub enum Sent {
+    New(S2UEvent),
+    /// The local id was already used, this is the message stored then.
+    Existing(MessageId),
+}
+
+/// Persistence for the core server. Range queries return `None` if the channel
+/// doesn't exist. All implementations must behave identically - in particular:
+///
+/// * Each channel has an event log. Event ids are assigned per channel, starting at
+///   1 and increasing by 1 with each event. A message's id is the id of the event
+///   that created it. A channel's server time is the id of its latest event (or 0 if
+///   it has no events).
+///
+/// * Deleted messages are excluded from snapshots.
+///
+/// * Range entries are returned in ascending order. `early_stop`/`late_stop` are
+///   true only if there are no more messages in that direction. Message times needn't
+///   be in id order: `snap_around` splits by time, returning the messages before
+///   `time` followed by those at or after it, each in ascending order.
+///
+/// * Times are stored with microsecond precision, and returned as stored.
+///
+/// * Expired sessions are never returned. Looking up an expired session deletes all
+///   expired sessions, along with their push subscriptions.
+#[async_trait]
+pub trait Storage: Send + Sync {
+    async fn get_identity(&self, id: IdentityId) -> Result<Option<S2UIdentity>, loga::Error>;
+
+    /// Look up several identities at once. Results are in `ids` order, `None` for unknown
+    /// ids.
+    async fn get_identities(&self, ids: Vec<IdentityId>) -> Result<Vec<Option<S2UIdentity>>, loga::Error>;
+    async fn create_identity(&self, user: String, name: String) -> Result<S2UIdentity, loga::Error>;
+
+    /// Identities the user can act as, in creation order.
+    async fn list_identities(&self, user: String) -> Result<Vec<S2UIdentity>, loga::Error>;
+
+    /// Returns false if the identity doesn't exist or isn't owned by the user.
+    async fn rename_identity(&self, user: String, id: IdentityId, name: String) -> Result<bool, loga::Error>;
+
+    /// Returns `None` if the owner has no free channel ids. The owner is added as a
+    /// member.
+    async fn create_channel(
+        &self,
+        owner: IdentityId,
+        name: String,
+        time: DateTime<Utc>,
+    ) -> Result<Option<ChannelId>, loga::Error>;
+    async fn get_channel(&self, id: ChannelId) -> Result<Option<S2UChannel>, loga::Error>;
+
+    /// The channel's member identities, or `None` if the channel doesn't exist.
+    async fn get_channel_members(&self, id: ChannelId) -> Result<Option<Vec<IdentityId>>, loga::Error>;
+
+    /// Users owning a member identity of the channel, in name order. Empty if the
+    /// channel doesn't exist.
+    async fn get_channel_users(&self, id: ChannelId) -> Result<Vec<String>, loga::Error>;
+
+    /// Look up several channels at once. Results are in `ids` order, `None` for unknown
+    /// ids.
+    async fn get_channels(&self, ids: Vec<ChannelId>) -> Result<Vec<Option<S2UChannel>>, loga::Error>;
+
+    /// Channels where the user owns a member identity, in channel id order.
+    async fn list_channels(&self, user: String) -> Result<Vec<S2UChannel>, loga::Error>;
+
+    /// Channels where the user owns a member identity, with their latest message and the number of messages after the
+    /// user's read position, in channel id order.
+    async fn list_channel_summaries(&self, user: String) -> Result<Vec<S2UChannelSummary>, loga::Error>;
+
+    async fn get_read_position(&self, user: String, channel: ChannelId) -> Result<Option<MessageId>, loga::Error>;
+
+    /// Move the user's read position in the channel forward to `id`. Positions never
+    /// move backwards. Returns the resulting position, or `None` if the channel
+    /// doesn't exist.
+    async fn set_read_position(&self, user: String, id: MessageId) -> Result<Option<MessageId>, loga::Error>;
+
+    /// Returns `None` if the channel doesn't exist.
+    async fn rename_channel(
+        &self,
+        id: ChannelId,
+        time: DateTime<Utc>,
+        name: String,
+    ) -> Result<Option<S2UEvent>, loga::Error>;
+
+    /// Returns `None` if the channel doesn't exist or the identity is already a
+    /// member.
+    async fn join_channel(
+        &self,
+        id: ChannelId,
+        identity: IdentityId,
+        time: DateTime<Utc>,
+    ) -> Result<Option<S2UEvent>, loga::Error>;
+
+    /// Returns `None` if the channel doesn't exist or the identity isn't a member.
+    async fn leave_channel(
+        &self,
+        id: ChannelId,
+        identity: IdentityId,
+        time: DateTime<Utc>,
+    ) -> Result<Option<S2UEvent>, loga::Error>;
+    /// Brews belong to the user that created them. Other users' brews are treated as
+    /// unknown by the brew methods.
+    async fn get_brew(&self, owner: String, id: BrewId) -> Result<Option<S2UBrew>, loga::Error>;
+
+    /// Look up several brews at once. Results are in `ids` order, `None` for unknown
+    /// ids.
+    async fn get_brews(&self, owner: String, ids: Vec<BrewId>) -> Result<Vec<Option<S2UBrew>>, loga::Error>;
+    async fn list_brews(&self, owner: String) -> Result<Vec<S2UBrew>, loga::Error>;
+
+    /// Brew changes are logged as `BrewChanged` or `BrewDeleted` events in each of the
+    /// brew's channels, before and after the change. The appended events are
+    /// returned.
+    async fn create_brew(
+        &self,
+        owner: String,
+        name: String,
+        channels: Vec<ChannelId>,
+        time: DateTime<Utc>,
+    ) -> Result<(BrewId, Vec<S2UEvent>), loga::Error>;
+
+    /// Change the brew's name and/or channels together. Channels are in display order.
+    /// Returns `None` if the brew doesn't exist.
+    async fn update_brew(
+        &self,
+        owner: String,
+        id: BrewId,
+        name: Option<String>,
+        channels: Option<Vec<ChannelId>>,
+        time: DateTime<Utc>,
+    ) -> Result<Option<Vec<S2UEvent>>, loga::Error>;
+
+    /// Returns `None` if the brew doesn't exist.
+    async fn delete_brew(
+        &self,
+        owner: String,
+        id: BrewId,
+        time: DateTime<Utc>,
+    ) -> Result<Option<Vec<S2UEvent>>, loga::Error>;
+
+    /// Store a new message, assigning it the next id in the channel. If `local_id` was
+    /// already used and hasn't expired, nothing is stored and the earlier message is
+    /// returned. Returns `None` if the channel doesn't exist.
+    async fn send(
+        &self,
+        channel: ChannelId,
+        author: IdentityId,
+        reply: Option<MessageId>,
+        time: DateTime<Utc>,
+        text: String,
+        local_id: Option<LocalSendId>,
+    ) -> Result<Option<Sent>, loga::Error>;
+
+    /// Returns `None` if the message doesn't exist.
+    async fn get_message_author(&self, id: MessageId) -> Result<Option<IdentityId>, loga::Error>;
+
+    /// Returns `None` if the message doesn't exist.
+    async fn edit_message(
+        &self,
+        id: MessageId,
+        time: DateTime<Utc>,
+        text: String,
+    ) -> Result<Option<S2UEvent>, loga::Error>;
+
+    /// Returns `None` if the message doesn't exist.
+    async fn delete_message(&self, id: MessageId, time: DateTime<Utc>) -> Result<Option<S2UEvent>, loga::Error>;
+
+    /// Events with ids after `id`, in the same channel.
+    async fn events_after(&self, id: MessageId, count: u64) -> Result<Option<S2UEventsGetAfterResp>, loga::Error>;
+
+    /// Up to `count` messages before `time` and up to `count` messages at or after
+    /// `time`.
+    async fn snap_around(
+        &self,
+        channel: ChannelId,
+        time: DateTime<Utc>,
+        count: u64,
+    ) -> Result<Option<S2USnapGetAroundResp>, loga::Error>;
+    async fn snap_before(&self, id: MessageId, count: u64) -> Result<Option<S2UGetBeforeResp>, loga::Error>;
+    async fn snap_after(&self, id: MessageId, count: u64) -> Result<Option<S2UGetAfterResp>, loga::Error>;
+
+    /// Create a user, or replace the password hash if the user already exists.
+    async fn set_user(&self, username: String, password_hash: String) -> Result<(), loga::Error>;
+    async fn get_user_password_hash(&self, username: String) -> Result<Option<String>, loga::Error>;
+    async fn create_session(&self, token: String, session: Session) -> Result<(), loga::Error>;
+    async fn get_session(&self, token: String, now: DateTime<Utc>) -> Result<Option<Session>, loga::Error>;
+
+    /// Also deletes the session's push subscriptions.
+    async fn delete_session(&self, token: String) -> Result<(), loga::Error>;
+
+    /// Return the server's VAPID private key, storing `new` first if there isn't one
+    /// yet.
+    async fn own_push_key(&self, new: Vec<u8>) -> Result<Vec<u8>, loga::Error>;
+
+    /// Associate a subscription with a session, replacing any previous association.
+    async fn add_push_subscription(&self, session: String, subscription: String) -> Result<(), loga::Error>;
+
+    /// Subscriptions for the unexpired sessions of users with an identity in the
+    /// channel, except the sessions of `exclude_user`.
+    async fn list_push_subscriptions(
+        &self,
+        channel: ChannelId,
+        exclude_user: String,
+        now: DateTime<Utc>,
+    ) -> Result<Vec<String>, loga::Error>;
+    async fn delete_push_subscription(&self, subscription: String) -> Result<(), loga::Error>;
+}
@@ -0,0 +1,1097 @@
+use std::{
+    collections::HashSet,
+    path::Path,
+};
+use chrono::{
+    DateTime,
+    NaiveDateTime,
+    SubsecRound,
+    TimeZone,
+    Utc,
+};
+use deadpool_sqlite::{
+    Pool,
+    Runtime,
+};
+use loga::ResultContext;
+use async_trait::async_trait;
+use rusqlite::{
+    params,
+    types::Type,
+    Connection,
+    OptionalExtension,
+    TransactionBehavior,
+};
+use webserver::interface::u2s::{
+    IdentityId,
+    ChannelId,
+    MessageId,
+    BrewId,
+    S2UEvent,
+    S2UEventKind,
+    S2UIdentity,
+    S2UChannel,
+    S2UChannelSummary,
+    S2UBrew,
+    S2UMessage,
+    S2UEventsGetAfterResp,
+    S2USnapGetAroundResp,
+    S2UGetBeforeResp,
+    S2UGetAfterResp,
+};
+use super::{
+    random_id,
+    LocalSendId,
+    Sent,
+    Session,
+    Storage,
+};
+
+#[cfg(test)]
+mod tests;
+
+/// Schema migrations, applied in order. The index of the last applied migration + 1
+/// is stored in `PRAGMA user_version`. Never modify a migration once released, add
+/// a new one instead.
+const MIGRATIONS: &[&str] =
+    &[
+        r#"
+        create table identities (
+            id text not null primary key,
+            name text not null
+        );
+        create table channels (
+            owner text not null,
+            idx integer not null,
+            name text not null,
+            primary key (owner, idx)
+        );
+        create table messages (
+            channel_owner text not null,
+            channel_idx integer not null,
+            seq integer not null,
+            time integer not null,
+            reply_owner text,
+            reply_idx integer,
+            reply_seq integer,
+            text text not null,
+            primary key (channel_owner, channel_idx, seq)
+        );
+        create index messages_time on messages (channel_owner, channel_idx, time);
+        create table brews (
+            id integer not null primary key,
+            name text not null,
+            channels text not null
+        );
+        create table push_subscriptions (
+            subscription text not null primary key
+        );
+        "#,
+        r#"
+        create table sessions (
+            token text not null primary key,
+            user text not null,
+            expires integer not null
+        );
+        "#,
+        r#"
+        create table users (
+            username text not null primary key,
+            password_hash text not null
+        );
+        "#,
+        r#"
+        drop table push_subscriptions;
+        create table push_subscriptions (
+            subscription text not null primary key,
+            session text not null
+        );
+        create index push_subscriptions_session on push_subscriptions (session);
+        create table push_key (
+            key blob not null
+        );
+        "#,
+        r#"
+        create table events (
+            channel_owner text not null,
+            channel_idx integer not null,
+            seq integer not null,
+            time integer not null,
+            kind text not null,
+            primary key (channel_owner, channel_idx, seq)
+        );
+        insert into events (channel_owner, channel_idx, seq, time, kind)
+        select
+            channel_owner,
+            channel_idx,
+            seq,
+            time,
+            json_object(
+                'MessageCreated',
+                json_object(
+                    'id',
+                    json_array(json_array(channel_owner, channel_idx), seq),
+                    'time',
+                    strftime('%Y-%m-%dT%H:%M:%S', time / 1000000, 'unixepoch') || '.' || printf('%06d', time % 1000000) || 'Z',
+                    'text',
+                    text
+                )
+            )
+        from messages;
+        create table channel_members (
+            channel_owner text not null,
+            channel_idx integer not null,
+            identity text not null,
+            primary key (channel_owner, channel_idx, identity)
+        );
+        insert into channel_members (channel_owner, channel_idx, identity) select owner, idx, owner from channels;
+        "#,
+        r#"
+        alter table identities add column avatar text;
+        alter table messages add column author text not null default '';
+        update messages set author = coalesce((select id from identities order by rowid limit 1), '');
+        update events
+        set kind = json_set(kind, '$.MessageCreated.author', coalesce((select id from identities order by rowid limit 1), ''))
+        where json_extract(kind, '$.MessageCreated') is not null;
+        "#,
+        r#"
+        alter table identities add column owner text;
+        update identities set owner = (select username from users order by rowid limit 1);
+        create index identities_owner on identities (owner);
+        "#,
+        r#"
+        create table read_positions (
+            user text not null,
+            channel_owner text not null,
+            channel_idx integer not null,
+            seq integer not null,
+            primary key (user, channel_owner, channel_idx)
+        );
+        "#,
+        r#"
+        create table sent_local_ids (
+            user text not null,
+            local_id text not null,
+            time integer not null,
+            channel_owner text not null,
+            channel_idx integer not null,
+            seq integer not null,
+            primary key (user, local_id)
+        );
+        create index sent_local_ids_time on sent_local_ids (time);
+        "#,
+        r#"
+        alter table brews add column owner text;
+        update brews set owner = (select username from users order by rowid limit 1);
+        create index brews_owner on brews (owner);
+        "#,
+    ];
+
+fn migrate(conn: &mut Connection) -> Result<(), rusqlite::Error> {
+    let version: usize = conn.query_row("pragma user_version", [], |r| r.get(0))?;
+    for (i, m) in MIGRATIONS.iter().enumerate().skip(version) {
+        let txn = conn.transaction_with_behavior(TransactionBehavior::Exclusive)?;
+        txn.execute_batch(m)?;
+        txn.pragma_update(None, "user_version", i + 1)?;
+        txn.commit()?;
+    }
+    return Ok(());
+}
+
+fn to_time(micros: i64) -> DateTime<Utc> {
+    return Utc.from_utc_datetime(&NaiveDateTime::from_timestamp_micros(micros).unwrap_or_default());
+}
+
+fn channel_exists(conn: &Connection, id: &ChannelId) -> Result<bool, rusqlite::Error> {
+    return Ok(
+        conn
+            .query_row("select 1 from channels where owner = ? and idx = ?", params![id.0.0, id.1], |_| Ok(()))
+            .optional()?
+            .is_some(),
+    );
+}
+
+fn channel_server_time(conn: &Connection, id: &ChannelId) -> Result<MessageId, rusqlite::Error> {
+    let seq: u64 =
+        conn.query_row(
+            "select coalesce(max(seq), 0) from events where channel_owner = ? and channel_idx = ?",
+            params![id.0.0, id.1],
+            |r| r.get(0),
+        )?;
+    return Ok(MessageId(id.clone(), seq));
+}
+
+/// Append an event to the channel's log, assigning it the next id. `kind` gets the
+/// new event's id.
+fn append_event(
+    conn: &Connection,
+    channel: &ChannelId,
+    time: DateTime<Utc>,
+    kind: impl FnOnce(&MessageId) -> S2UEventKind,
+) -> Result<S2UEvent, rusqlite::Error> {
+    // Times are stored in microseconds, return the time as it'll be read back
+    let time = time.trunc_subsecs(6);
+    let id = MessageId(channel.clone(), channel_server_time(conn, channel)?.1 + 1);
+    let kind = kind(&id);
+    conn.execute(
+        "insert into events (channel_owner, channel_idx, seq, time, kind) values (?, ?, ?, ?, ?)",
+        params![channel.0.0, channel.1, id.1, time.timestamp_micros(), serde_json::to_string(&kind).unwrap()],
+    )?;
+    return Ok(S2UEvent {
+        id: id,
+        time: time,
+        kind: kind,
+    });
+}
+
+/// Append a brew event to the log of each of the channels, skipping duplicates and
+/// channels that don't exist.
+fn append_brew_events<'a>(
+    conn: &Connection,
+    channels: impl IntoIterator<Item = &'a ChannelId>,
+    time: DateTime<Utc>,
+    kind: S2UEventKind,
+) -> Result<Vec<S2UEvent>, rusqlite::Error> {
+    let mut out = vec![];
+    let mut seen = HashSet::new();
+    for channel in channels {
+        if !seen.insert(channel) || !channel_exists(conn, channel)? {
+            continue;
+        }
+        out.push(append_event(conn, channel, time, |_| kind.clone())?);
+    }
+    return Ok(out);
+}
+
+/// Returns `None` if the brew doesn't exist or has a different owner.
+fn get_brew(conn: &Connection, owner: &str, id: &BrewId) -> Result<Option<S2UBrew>, rusqlite::Error> {
+    return conn.query_row(
+        "select name, channels from brews where id = ? and owner = ?",
+        params![id.0 as i64, owner],
+        |r| Ok(S2UBrew {
+            id: id.clone(),
+            name: r.get(0)?,
+            channels: serde_json::from_str(&r.get::<_, String>(1)?).unwrap_or_default(),
+        }),
+    ).optional();
+}
+
+/// Runs a message query, returning up to `count` messages and whether more matched.
+/// The query must select `seq, author, time, text` and take the channel owner, channel
+/// index, pivot and limit as parameters, in that order.
+fn query_messages(
+    conn: &Connection,
+    sql: &str,
+    channel: &ChannelId,
+    pivot: i64,
+    count: u64,
+) -> Result<(Vec<S2UMessage>, bool), rusqlite::Error> {
+    let mut stmt = conn.prepare_cached(sql)?;
+    let mut out = stmt.query_map(params![channel.0.0, channel.1, pivot, count + 1], |r| Ok(S2UMessage {
+        id: MessageId(channel.clone(), r.get(0)?),
+        author: IdentityId(r.get(1)?),
+        time: to_time(r.get(2)?),
+        text: r.get(3)?,
+    }))?.collect::<Result<Vec<_>, _>>()?;
+    let more = out.len() as u64 > count;
+    out.truncate(count as usize);
+    return Ok((out, more));
+}
+
+const SQL_BEFORE_TIME: &str =
+    "select seq, author, time, text from messages where channel_owner = ? and channel_idx = ? and time < ? order by seq desc limit ?";
+const SQL_FROM_TIME: &str =
+    "select seq, author, time, text from messages where channel_owner = ? and channel_idx = ? and time >= ? order by seq asc limit ?";
+const SQL_BEFORE_SEQ: &str =
+    "select seq, author, time, text from messages where channel_owner = ? and channel_idx = ? and seq < ? order by seq desc limit ?";
+const SQL_AFTER_SEQ: &str =
+    "select seq, author, time, text from messages where channel_owner = ? and channel_idx = ? and seq > ? order by seq asc limit ?";
+
+#[derive(Clone)]
+pub struct SqliteStorage {
+    pool: Pool,
+}
+
+impl SqliteStorage {
+    pub async fn new(data_dir: &Path) -> Result<SqliteStorage, loga::Error> {
+        std::fs::create_dir_all(data_dir).context("Error creating data directory")?;
+        let pool =
+            deadpool_sqlite::Config::new(data_dir.join("core.sqlite3"))
+                .create_pool(Runtime::Tokio1)
+                .context("Error creating database pool")?;
+        let out = SqliteStorage { pool: pool };
+        out.run(|conn| {
+            conn.pragma_update(None, "journal_mode", "wal")?;
+            migrate(conn)?;
+            return Ok(());
+        }).await.context("Error migrating database")?;
+        return Ok(out);
+    }
+
+    async fn run<
+        T: 'static + Send,
+    >(
+        &self,
+        f: impl 'static + Send + FnOnce(&mut Connection) -> Result<T, rusqlite::Error>,
+    ) -> Result<T, loga::Error> {
+        let conn = self.pool.get().await.context("Error getting database connection")?;
+        return conn.interact(move |conn| {
+            conn.busy_timeout(std::time::Duration::from_secs(10))?;
+            return f(conn);
+        }).await.context("Error running database interaction")?.context("Error executing query");
+    }
+}
+
+#[async_trait]
+impl Storage for SqliteStorage {
+    async fn get_identity(&self, id: IdentityId) -> Result<Option<S2UIdentity>, loga::Error> {
+        return self.run(move |conn| {
+            return conn.query_row(
+                "select name, avatar from identities where id = ?",
+                params![id.0],
+                |r| Ok(S2UIdentity {
+                    id: id.clone(),
+                    name: r.get(0)?,
+                    avatar: r.get(1)?,
+                }),
+            ).optional();
+        }).await;
+    }
+
+    async fn get_identities(&self, ids: Vec<IdentityId>) -> Result<Vec<Option<S2UIdentity>>, loga::Error> {
+        return self.run(move |conn| {
+            let mut stmt = conn.prepare_cached("select name, avatar from identities where id = ?")?;
+            let mut out = vec![];
+            for id in ids {
+                out.push(stmt.query_row(params![id.0], |r| Ok(S2UIdentity {
+                    id: id.clone(),
+                    name: r.get(0)?,
+                    avatar: r.get(1)?,
+                })).optional()?);
+            }
+            return Ok(out);
+        }).await;
+    }
+
+    async fn create_identity(&self, user: String, name: String) -> Result<S2UIdentity, loga::Error> {
+        return self.run(move |conn| {
+            let identity = S2UIdentity {
+                id: IdentityId(random_id()),
+                name: name,
+                avatar: None,
+            };
+            conn.execute(
+                "insert into identities (id, name, owner) values (?, ?, ?)",
+                params![identity.id.0, identity.name, user],
+            )?;
+            return Ok(identity);
+        }).await;
+    }
+
+    async fn list_identities(&self, user: String) -> Result<Vec<S2UIdentity>, loga::Error> {
+        return self.run(move |conn| {
+            let mut stmt =
+                conn.prepare_cached(
+                    "select id, name, avatar from identities where owner = ? order by rowid",
+                )?;
+            let out = stmt.query_map(params![user], |r| Ok(S2UIdentity {
+                id: IdentityId(r.get(0)?),
+                name: r.get(1)?,
+                avatar: r.get(2)?,
+            }))?.collect::<Result<Vec<_>, _>>();
+            return out;
+        }).await;
+    }
+
+    async fn rename_identity(&self, user: String, id: IdentityId, name: String) -> Result<bool, loga::Error> {
+        return self.run(move |conn| {
+            return Ok(
+                conn.execute(
+                    "update identities set name = ? where id = ? and owner = ?",
+                    params![name, id.0, user],
+                )? >
+                    0,
+            );
+        }).await;
+    }
+
+    async fn create_channel(
+        &self,
+        owner: IdentityId,
+        name: String,
+        time: DateTime<Utc>,
+    ) -> Result<Option<ChannelId>, loga::Error> {
+        return self.run(move |conn| {
+            let txn = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            let idx: u32 =
+                txn.query_row(
+                    "select coalesce(max(idx) + 1, 0) from channels where owner = ?",
+                    params![owner.0],
+                    |r| r.get(0),
+                )?;
+            let Ok(idx) = u16::try_from(idx) else {
+                return Ok(None);
+            };
+            txn.execute("insert into channels (owner, idx, name) values (?, ?, ?)", params![owner.0, idx, name])?;
+            let id = ChannelId(owner.clone(), idx);
+            txn.execute(
+                "insert into channel_members (channel_owner, channel_idx, identity) values (?, ?, ?)",
+                params![id.0.0, id.1, owner.0],
+            )?;
+            append_event(&txn, &id, time, |_| S2UEventKind::MemberJoined {
+                channel: id.clone(),
+                identity: owner,
+            })?;
+            txn.commit()?;
+            return Ok(Some(id));
+        }).await;
+    }
+
+    async fn get_channel(&self, id: ChannelId) -> Result<Option<S2UChannel>, loga::Error> {
+        return self.run(move |conn| {
+            return conn.query_row(
+                "select name from channels where owner = ? and idx = ?",
+                params![id.0.0, id.1],
+                |r| Ok(S2UChannel {
+                    id: id.clone(),
+                    name: r.get(0)?,
+                }),
+            ).optional();
+        }).await;
+    }
+
+    async fn get_channel_members(&self, id: ChannelId) -> Result<Option<Vec<IdentityId>>, loga::Error> {
+        return self.run(move |conn| {
+            let conn = conn.transaction()?;
+            if !channel_exists(&conn, &id)? {
+                return Ok(None);
+            }
+            let mut stmt =
+                conn.prepare_cached(
+                    "select identity from channel_members where channel_owner = ? and channel_idx = ? order by identity",
+                )?;
+            let out =
+                stmt
+                    .query_map(params![id.0.0, id.1], |r| Ok(IdentityId(r.get(0)?)))?
+                    .collect::<Result<Vec<_>, _>>()?;
+            return Ok(Some(out));
+        }).await;
+    }
+
+    async fn get_channel_users(&self, id: ChannelId) -> Result<Vec<String>, loga::Error> {
+        return self.run(move |conn| {
+            let mut stmt =
+                conn.prepare_cached(
+                    "select distinct identities.owner from channel_members join identities on channel_members.identity = identities.id where channel_members.channel_owner = ? and channel_members.channel_idx = ? order by identities.owner",
+                )?;
+            let out = stmt.query_map(params![id.0.0, id.1], |r| r.get(0))?.collect::<Result<Vec<_>, _>>();
+            return out;
+        }).await;
+    }
+
+    async fn get_channels(&self, ids: Vec<ChannelId>) -> Result<Vec<Option<S2UChannel>>, loga::Error> {
+        return self.run(move |conn| {
+            let mut stmt = conn.prepare_cached("select name from channels where owner = ? and idx = ?")?;
+            let mut out = vec![];
+            for id in ids {
+                out.push(stmt.query_row(params![id.0.0, id.1], |r| Ok(S2UChannel {
+                    id: id.clone(),
+                    name: r.get(0)?,
+                })).optional()?);
+            }
+            return Ok(out);
+        }).await;
+    }
+
+    async fn list_channels(&self, user: String) -> Result<Vec<S2UChannel>, loga::Error> {
+        return self.run(move |conn| {
+            let mut stmt =
+                conn.prepare_cached(
+                    "select owner, idx, name from channels where exists (select 1 from channel_members join identities on channel_members.identity = identities.id where channel_members.channel_owner = channels.owner and channel_members.channel_idx = channels.idx and identities.owner = ?) order by owner, idx",
+                )?;
+            let out = stmt.query_map(params![user], |r| Ok(S2UChannel {
+                id: ChannelId(IdentityId(r.get(0)?), r.get(1)?),
+                name: r.get(2)?,
+            }))?.collect::<Result<Vec<_>, _>>();
+            return out;
+        }).await;
+    }
+
+    async fn list_channel_summaries(&self, user: String) -> Result<Vec<S2UChannelSummary>, loga::Error> {
+        return self.run(move |conn| {
+            let mut stmt =
+                conn.prepare_cached(
+                    r#"
+                    select
+                        c.owner,
+                        c.idx,
+                        c.name,
+                        m.seq,
+                        m.author,
+                        m.time,
+                        m.text,
+                        (
+                            select count(*)
+                            from messages u
+                            where u.channel_owner = c.owner and u.channel_idx = c.idx and u.seq > coalesce(r.seq, 0)
+                        )
+                    from channels c
+                    left join read_positions r on r.user = ? and r.channel_owner = c.owner and r.channel_idx = c.idx
+                    left join messages m on m.channel_owner = c.owner and m.channel_idx = c.idx and m.seq = (
+                        select max(seq) from messages where channel_owner = c.owner and channel_idx = c.idx
+                    )
+                    where exists (
+                        select 1
+                        from channel_members cm
+                        join identities i on cm.identity = i.id
+                        where cm.channel_owner = c.owner and cm.channel_idx = c.idx and i.owner = ?
+                    )
+                    order by c.owner, c.idx
+                    "#,
+                )?;
+            let out = stmt.query_map(params![user, user], |r| {
+                let id = ChannelId(IdentityId(r.get(0)?), r.get(1)?);
+                let last_message = match r.get::<_, Option<u64>>(3)? {
+                    Some(seq) => Some(S2UMessage {
+                        id: MessageId(id.clone(), seq),
+                        author: IdentityId(r.get(4)?),
+                        time: to_time(r.get(5)?),
+                        text: r.get(6)?,
+                    }),
+                    None => None,
+                };
+                return Ok(S2UChannelSummary {
+                    channel: S2UChannel {
+                        id: id,
+                        name: r.get(2)?,
+                    },
+                    last_message: last_message,
+                    unread: r.get(7)?,
+                });
+            })?.collect::<Result<Vec<_>, _>>();
+            return out;
+        }).await;
+    }
+
+    async fn get_read_position(&self, user: String, channel: ChannelId) -> Result<Option<MessageId>, loga::Error> {
+        return self.run(move |conn| {
+            return conn.query_row(
+                "select seq from read_positions where user = ? and channel_owner = ? and channel_idx = ?",
+                params![user, channel.0.0, channel.1],
+                |r| Ok(MessageId(channel.clone(), r.get(0)?)),
+            ).optional();
+        }).await;
+    }
+
+    async fn set_read_position(&self, user: String, id: MessageId) -> Result<Option<MessageId>, loga::Error> {
+        return self.run(move |conn| {
+            let txn = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            if !channel_exists(&txn, &id.0)? {
+                return Ok(None);
+            }
+            let seq: u64 =
+                txn.query_row(
+                    "insert into read_positions (user, channel_owner, channel_idx, seq) values (?, ?, ?, ?) on conflict do update set seq = max(seq, excluded.seq) returning seq",
+                    params![user, id.0.0.0, id.0.1, id.1],
+                    |r| r.get(0),
+                )?;
+            txn.commit()?;
+            return Ok(Some(MessageId(id.0, seq)));
+        }).await;
+    }
+
+    async fn rename_channel(
+        &self,
+        id: ChannelId,
+        time: DateTime<Utc>,
+        name: String,
+    ) -> Result<Option<S2UEvent>, loga::Error> {
+        return self.run(move |conn| {
+            let txn = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            if txn.execute("update channels set name = ? where owner = ? and idx = ?", params![name, id.0.0, id.1])? ==
+                0 {
+                return Ok(None);
+            }
+            let event = append_event(&txn, &id, time, |_| S2UEventKind::ChannelRenamed {
+                id: id.clone(),
+                name: name,
+            })?;
+            txn.commit()?;
+            return Ok(Some(event));
+        }).await;
+    }
+
+    async fn join_channel(
+        &self,
+        id: ChannelId,
+        identity: IdentityId,
+        time: DateTime<Utc>,
+    ) -> Result<Option<S2UEvent>, loga::Error> {
+        return self.run(move |conn| {
+            let txn = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            if !channel_exists(&txn, &id)? {
+                return Ok(None);
+            }
+            if txn.execute(
+                "insert into channel_members (channel_owner, channel_idx, identity) values (?, ?, ?) on conflict do nothing",
+                params![id.0.0, id.1, identity.0],
+            )? ==
+                0 {
+                return Ok(None);
+            }
+            let event = append_event(&txn, &id, time, |_| S2UEventKind::MemberJoined {
+                channel: id.clone(),
+                identity: identity,
+            })?;
+            txn.commit()?;
+            return Ok(Some(event));
+        }).await;
+    }
+
+    async fn leave_channel(
+        &self,
+        id: ChannelId,
+        identity: IdentityId,
+        time: DateTime<Utc>,
+    ) -> Result<Option<S2UEvent>, loga::Error> {
+        return self.run(move |conn| {
+            let txn = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            if txn.execute(
+                "delete from channel_members where channel_owner = ? and channel_idx = ? and identity = ?",
+                params![id.0.0, id.1, identity.0],
+            )? ==
+                0 {
+                return Ok(None);
+            }
+            let event = append_event(&txn, &id, time, |_| S2UEventKind::MemberLeft {
+                channel: id.clone(),
+                identity: identity,
+            })?;
+            txn.commit()?;
+            return Ok(Some(event));
+        }).await;
+    }
+
+    async fn get_brew(&self, owner: String, id: BrewId) -> Result<Option<S2UBrew>, loga::Error> {
+        return self.run(move |conn| {
+            return get_brew(conn, &owner, &id);
+        }).await;
+    }
+
+    async fn get_brews(&self, owner: String, ids: Vec<BrewId>) -> Result<Vec<Option<S2UBrew>>, loga::Error> {
+        return self.run(move |conn| {
+            let mut out = vec![];
+            for id in ids {
+                out.push(get_brew(conn, &owner, &id)?);
+            }
+            return Ok(out);
+        }).await;
+    }
+
+    async fn list_brews(&self, owner: String) -> Result<Vec<S2UBrew>, loga::Error> {
+        return self.run(move |conn| {
+            let mut stmt = conn.prepare_cached("select id, name, channels from brews where owner = ? order by id")?;
+            let out = stmt.query_map(params![owner], |r| Ok(S2UBrew {
+                id: BrewId(r.get::<_, i64>(0)? as usize),
+                name: r.get(1)?,
+                channels: serde_json::from_str(&r.get::<_, String>(2)?).unwrap_or_default(),
+            }))?.collect::<Result<Vec<_>, _>>();
+            return out;
+        }).await;
+    }
+
+    async fn create_brew(
+        &self,
+        owner: String,
+        name: String,
+        channels: Vec<ChannelId>,
+        time: DateTime<Utc>,
+    ) -> Result<(BrewId, Vec<S2UEvent>), loga::Error> {
+        return self.run(move |conn| {
+            let txn = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            txn.execute(
+                "insert into brews (owner, name, channels) values (?, ?, ?)",
+                params![owner, name, serde_json::to_string(&channels).unwrap()],
+            )?;
+            let id = BrewId(txn.last_insert_rowid() as usize);
+            let events = append_brew_events(&txn, &channels, time, S2UEventKind::BrewChanged(S2UBrew {
+                id: id.clone(),
+                name: name,
+                channels: channels.clone(),
+            }))?;
+            txn.commit()?;
+            return Ok((id, events));
+        }).await;
+    }
+
+    async fn update_brew(
+        &self,
+        owner: String,
+        id: BrewId,
+        name: Option<String>,
+        channels: Option<Vec<ChannelId>>,
+        time: DateTime<Utc>,
+    ) -> Result<Option<Vec<S2UEvent>>, loga::Error> {
+        return self.run(move |conn| {
+            let txn = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            let Some(mut brew) = get_brew(&txn, &owner, &id)? else {
+                return Ok(None);
+            };
+            let old_channels = brew.channels.clone();
+            if let Some(name) = name {
+                brew.name = name;
+            }
+            if let Some(channels) = channels {
+                brew.channels = channels;
+            }
+            txn.execute(
+                "update brews set name = ?, channels = ? where id = ?",
+                params![brew.name, serde_json::to_string(&brew.channels).unwrap(), id.0 as i64],
+            )?;
+            let events =
+                append_brew_events(
+                    &txn,
+                    old_channels.iter().chain(brew.channels.iter()),
+                    time,
+                    S2UEventKind::BrewChanged(brew.clone()),
+                )?;
+            txn.commit()?;
+            return Ok(Some(events));
+        }).await;
+    }
+
+    async fn delete_brew(
+        &self,
+        owner: String,
+        id: BrewId,
+        time: DateTime<Utc>,
+    ) -> Result<Option<Vec<S2UEvent>>, loga::Error> {
+        return self.run(move |conn| {
+            let txn = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            let Some(brew) = get_brew(&txn, &owner, &id)? else {
+                return Ok(None);
+            };
+            txn.execute("delete from brews where id = ?", params![id.0 as i64])?;
+            let events = append_brew_events(&txn, &brew.channels, time, S2UEventKind::BrewDeleted(id))?;
+            txn.commit()?;
+            return Ok(Some(events));
+        }).await;
+    }
+
+    async fn own_push_key(&self, new: Vec<u8>) -> Result<Vec<u8>, loga::Error> {
+        return self.run(move |conn| {
+            let txn = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            let existing = txn.query_row("select key from push_key limit 1", [], |r| r.get(0)).optional()?;
+            let out = match existing {
+                Some(k) => k,
+                None => {
+                    txn.execute("insert into push_key (key) values (?)", params![new])?;
+                    new
+                },
+            };
+            txn.commit()?;
+            return Ok(out);
+        }).await;
+    }
+
+    async fn add_push_subscription(&self, session: String, subscription: String) -> Result<(), loga::Error> {
+        return self.run(move |conn| {
+            conn.execute(
+                "insert into push_subscriptions (subscription, session) values (?, ?) on conflict (subscription) do update set session = excluded.session",
+                params![subscription, session],
+            )?;
+            return Ok(());
+        }).await;
+    }
+
+    async fn send(
+        &self,
+        channel: ChannelId,
+        author: IdentityId,
+        reply: Option<MessageId>,
+        time: DateTime<Utc>,
+        text: String,
+        local_id: Option<LocalSendId>,
+    ) -> Result<Option<Sent>, loga::Error> {
+        let time = time.trunc_subsecs(6);
+        return self.run(move |conn| {
+            let txn = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            if !channel_exists(&txn, &channel)? {
+                return Ok(None);
+            }
+            if let Some(local_id) = &local_id {
+                txn.execute(
+                    "delete from sent_local_ids where time < ?",
+                    params![local_id.expire_before.timestamp_micros()],
+                )?;
+                let existing =
+                    txn
+                        .query_row(
+                            "select channel_owner, channel_idx, seq from sent_local_ids where user = ? and local_id = ?",
+                            params![local_id.user, local_id.local_id],
+                            |r| Ok(MessageId(ChannelId(IdentityId(r.get(0)?), r.get(1)?), r.get(2)?)),
+                        )
+                        .optional()?;
+                if let Some(existing) = existing {
+                    return Ok(Some(Sent::Existing(existing)));
+                }
+            }
+            let event = append_event(&txn, &channel, time, |id| S2UEventKind::MessageCreated(S2UMessage {
+                id: id.clone(),
+                author: author.clone(),
+                time: time,
+                text: text.clone(),
+            }))?;
+            txn.execute(
+                "insert into messages (channel_owner, channel_idx, seq, author, time, reply_owner, reply_idx, reply_seq, text) values (?, ?, ?, ?, ?, ?, ?, ?, ?)",
+                params![
+                    channel.0.0,
+                    channel.1,
+                    event.id.1,
+                    author.0,
+                    time.timestamp_micros(),
+                    reply.as_ref().map(|r| r.0.0.0.clone()),
+                    reply.as_ref().map(|r| r.0.1),
+                    reply.as_ref().map(|r| r.1),
+                    text
+                ],
+            )?;
+            if let Some(local_id) = &local_id {
+                txn.execute(
+                    "insert into sent_local_ids (user, local_id, time, channel_owner, channel_idx, seq) values (?, ?, ?, ?, ?, ?)",
+                    params![local_id.user, local_id.local_id, time.timestamp_micros(), channel.0.0, channel.1, event.id.1],
+                )?;
+            }
+            txn.commit()?;
+            return Ok(Some(Sent::New(event)));
+        }).await;
+    }
+
+    async fn get_message_author(&self, id: MessageId) -> Result<Option<IdentityId>, loga::Error> {
+        return self.run(move |conn| {
+            return conn.query_row(
+                "select author from messages where channel_owner = ? and channel_idx = ? and seq = ?",
+                params![id.0.0.0, id.0.1, id.1],
+                |r| Ok(IdentityId(r.get(0)?)),
+            ).optional();
+        }).await;
+    }
+
+    async fn edit_message(
+        &self,
+        id: MessageId,
+        time: DateTime<Utc>,
+        text: String,
+    ) -> Result<Option<S2UEvent>, loga::Error> {
+        return self.run(move |conn| {
+            let txn = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            if txn.execute(
+                "update messages set text = ? where channel_owner = ? and channel_idx = ? and seq = ?",
+                params![text, id.0.0.0, id.0.1, id.1],
+            )? ==
+                0 {
+                return Ok(None);
+            }
+            let event = append_event(&txn, &id.0, time, |_| S2UEventKind::MessageEdited {
+                id: id.clone(),
+                text: text,
+            })?;
+            txn.commit()?;
+            return Ok(Some(event));
+        }).await;
+    }
+
+    async fn delete_message(&self, id: MessageId, time: DateTime<Utc>) -> Result<Option<S2UEvent>, loga::Error> {
+        return self.run(move |conn| {
+            let txn = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            if txn.execute(
+                "delete from messages where channel_owner = ? and channel_idx = ? and seq = ?",
+                params![id.0.0.0, id.0.1, id.1],
+            )? ==
+                0 {
+                return Ok(None);
+            }
+            let event = append_event(&txn, &id.0, time, |_| S2UEventKind::MessageDeleted(id.clone()))?;
+            txn.commit()?;
+            return Ok(Some(event));
+        }).await;
+    }
+
+    async fn events_after(&self, id: MessageId, count: u64) -> Result<Option<S2UEventsGetAfterResp>, loga::Error> {
+        return self.run(move |conn| {
+            let conn = conn.transaction()?;
+            if !channel_exists(&conn, &id.0)? {
+                return Ok(None);
+            }
+            let mut stmt =
+                conn.prepare_cached(
+                    "select seq, time, kind from events where channel_owner = ? and channel_idx = ? and seq > ? order by seq asc limit ?",
+                )?;
+            let entries = stmt.query_map(params![id.0.0.0, id.0.1, id.1, count], |r| Ok(S2UEvent {
+                id: MessageId(id.0.clone(), r.get(0)?),
+                time: to_time(r.get(1)?),
+                kind: serde_json::from_str(
+                    &r.get::<_, String>(2)?,
+                ).map_err(|e| rusqlite::Error::FromSqlConversionFailure(2, Type::Text, Box::new(e)))?,
+            }))?.collect::<Result<Vec<_>, _>>()?;
+            drop(stmt);
+            return Ok(Some(S2UEventsGetAfterResp {
+                server_time: channel_server_time(&conn, &id.0)?,
+                entries: entries,
+            }));
+        }).await;
+    }
+
+    async fn snap_around(
+        &self,
+        channel: ChannelId,
+        time: DateTime<Utc>,
+        count: u64,
+    ) -> Result<Option<S2USnapGetAroundResp>, loga::Error> {
+        return self.run(move |conn| {
+            let conn = conn.transaction()?;
+            if !channel_exists(&conn, &channel)? {
+                return Ok(None);
+            }
+            let (mut entries, early_more) =
+                query_messages(&conn, SQL_BEFORE_TIME, &channel, time.timestamp_micros(), count)?;
+            entries.reverse();
+            let (after, late_more) = query_messages(&conn, SQL_FROM_TIME, &channel, time.timestamp_micros(), count)?;
+            entries.extend(after);
+            return Ok(Some(S2USnapGetAroundResp {
+                server_time: channel_server_time(&conn, &channel)?,
+                entries: entries,
+                early_stop: !early_more,
+                late_stop: !late_more,
+            }));
+        }).await;
+    }
+
+    async fn snap_before(&self, id: MessageId, count: u64) -> Result<Option<S2UGetBeforeResp>, loga::Error> {
+        return self.run(move |conn| {
+            let conn = conn.transaction()?;
+            if !channel_exists(&conn, &id.0)? {
+                return Ok(None);
+            }
+            let (mut entries, more) = query_messages(&conn, SQL_BEFORE_SEQ, &id.0, id.1 as i64, count)?;
+            entries.reverse();
+            return Ok(Some(S2UGetBeforeResp {
+                server_time: channel_server_time(&conn, &id.0)?,
+                entries: entries,
+                early_stop: !more,
+            }));
+        }).await;
+    }
+
+    async fn snap_after(&self, id: MessageId, count: u64) -> Result<Option<S2UGetAfterResp>, loga::Error> {
+        return self.run(move |conn| {
+            let conn = conn.transaction()?;
+            if !channel_exists(&conn, &id.0)? {
+                return Ok(None);
+            }
+            let (entries, more) = query_messages(&conn, SQL_AFTER_SEQ, &id.0, id.1 as i64, count)?;
+            return Ok(Some(S2UGetAfterResp {
+                server_time: channel_server_time(&conn, &id.0)?,
+                entries: entries,
+                late_stop: !more,
+            }));
+        }).await;
+    }
+
+    async fn set_user(&self, username: String, password_hash: String) -> Result<(), loga::Error> {
+        return self.run(move |conn| {
+            conn.execute(
+                "insert into users (username, password_hash) values (?, ?) on conflict (username) do update set password_hash = excluded.password_hash",
+                params![username, password_hash],
+            )?;
+            return Ok(());
+        }).await;
+    }
+
+    async fn get_user_password_hash(&self, username: String) -> Result<Option<String>, loga::Error> {
+        return self.run(move |conn| {
+            return conn
+                .query_row("select password_hash from users where username = ?", params![username], |r| r.get(0))
+                .optional();
+        }).await;
+    }
+
+    async fn create_session(&self, token: String, session: Session) -> Result<(), loga::Error> {
+        return self.run(move |conn| {
+            conn.execute(
+                "insert into sessions (token, user, expires) values (?, ?, ?)",
+                params![token, session.user, session.expires.timestamp_micros()],
+            )?;
+            return Ok(());
+        }).await;
+    }
+
+    async fn get_session(&self, token: String, now: DateTime<Utc>) -> Result<Option<Session>, loga::Error> {
+        return self.run(move |conn| {
+            let found =
+                conn
+                    .query_row(
+                        "select user, expires from sessions where token = ?",
+                        params![token],
+                        |r| Ok(Session {
+                            user: r.get(0)?,
+                            expires: to_time(r.get(1)?),
+                        }),
+                    )
+                    .optional()?;
+            let Some(found) = found else {
+                return Ok(None);
+            };
+            if found.expires > now {
+                return Ok(Some(found));
+            }
+            let txn = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            txn.execute(
+                "delete from push_subscriptions where session in (select token from sessions where expires <= ?)",
+                params![now.timestamp_micros()],
+            )?;
+            txn.execute("delete from sessions where expires <= ?", params![now.timestamp_micros()])?;
+            txn.commit()?;
+            return Ok(None);
+        }).await;
+    }
+
+    async fn delete_session(&self, token: String) -> Result<(), loga::Error> {
+        return self.run(move |conn| {
+            let txn = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            txn.execute("delete from sessions where token = ?", params![token])?;
+            txn.execute("delete from push_subscriptions where session = ?", params![token])?;
+            txn.commit()?;
+            return Ok(());
+        }).await;
+    }
+
+    async fn list_push_subscriptions(
+        &self,
+        channel: ChannelId,
+        exclude_user: String,
+        now: DateTime<Utc>,
+    ) -> Result<Vec<String>, loga::Error> {
+        return self.run(move |conn| {
+            let mut stmt =
+                conn.prepare_cached(
+                    "select push_subscriptions.subscription from push_subscriptions join sessions on push_subscriptions.session = sessions.token where sessions.user != ? and sessions.expires > ? and exists (select 1 from channel_members join identities on channel_members.identity = identities.id where channel_members.channel_owner = ? and channel_members.channel_idx = ? and identities.owner = sessions.user)",
+                )?;
+            let out =
+                stmt
+                    .query_map(
+                        params![exclude_user, now.timestamp_micros(), channel.0.0, channel.1],
+                        |r| r.get(0),
+                    )?
+                    .collect::<Result<Vec<_>, _>>();
+            return out;
+        }).await;
+    }
+
+    async fn delete_push_subscription(&self, subscription: String) -> Result<(), loga::Error> {
+        return self.run(move |conn| {
+            conn.execute("delete from push_subscriptions where subscription = ?", params![subscription])?;
+            return Ok(());
+        }).await;
+    }
+}
@@ -0,0 +1,57 @@
+//! Upgrades from older schema versions.
+use rusqlite::{
+    params,
+    Connection,
+};
+use webserver::interface::u2s::{
+    ChannelId,
+    IdentityId,
+    MessageId,
+};
+use super::{
+    super::{
+        tests::TempDir,
+        Storage,
+    },
+    SqliteStorage,
+    MIGRATIONS,
+};
+
+/// Create a database at an old `version`, then run `setup` on it.
+fn old_db(dir: &TempDir, version: usize, setup: &str) {
+    std::fs::create_dir_all(&dir.0).unwrap();
+    let conn = Connection::open(dir.0.join("core.sqlite3")).unwrap();
+    for m in &MIGRATIONS[..version] {
+        conn.execute_batch(m).unwrap();
+    }
+    conn.pragma_update(None, "user_version", version).unwrap();
+    conn.execute_batch(setup).unwrap();
+}
+
+#[tokio::test]
+async fn upgrade_keeps_identity_ownership() {
+    // Before version 7 identities had no owner, and there was only one user
+    let dir = TempDir::new();
+    old_db(&dir, 6, r#"
+        insert into users (username, password_hash) values ('alice', 'hash');
+        insert into identities (id, name) values ('a1', 'Alice'), ('a2', 'Alice 2');
+        insert into channels (owner, idx, name) values ('a1', 1, 'general');
+        insert into channel_members (channel_owner, channel_idx, identity) values ('a1', 1, 'a1');
+        insert into messages (channel_owner, channel_idx, seq, time, text, author) values ('a1', 1, 1, 0, 'hi', 'a1');
+        "#);
+    let s = SqliteStorage::new(&dir.0).await.unwrap();
+    let identities = s.list_identities("alice".to_string()).await.unwrap();
+    assert_eq!(
+        identities.into_iter().map(|i| i.id).collect::<Vec<_>>(),
+        vec![IdentityId("a1".to_string()), IdentityId("a2".to_string())]
+    );
+    let channel = ChannelId(IdentityId("a1".to_string()), 1);
+    assert_eq!(s.get_channel_users(channel.clone()).await.unwrap(), vec!["alice".to_string()]);
+    assert_eq!(
+        s.get_message_author(MessageId(channel, 1)).await.unwrap(),
+        Some(IdentityId("a1".to_string()))
+    );
+    assert!(s.list_identities("bob".to_string()).await.unwrap().is_empty());
+    let version: usize = s.run(|conn| conn.query_row("pragma user_version", params![], |r| r.get(0))).await.unwrap();
+    assert_eq!(version, MIGRATIONS.len());
+}
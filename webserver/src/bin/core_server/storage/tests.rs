@@ -0,0 +1,574 @@
+//! Conformance tests, run against every `Storage` implementation.
+use std::path::PathBuf;
+use chrono::{
+    DateTime,
+    Duration,
+    TimeZone,
+    Utc,
+};
+use serde::Serialize;
+use webserver::interface::u2s::{
+    ChannelId,
+    IdentityId,
+    MessageId,
+    S2UEvent,
+    S2UEventKind,
+    S2UMessage,
+    BrewId,
+};
+use super::{
+    random_id,
+    LocalSendId,
+    Sent,
+    Session,
+    Storage,
+};
+
+/// A directory for a test database, deleted afterwards.
+pub(super) struct TempDir(pub(super) PathBuf);
+
+impl TempDir {
+    pub(super) fn new() -> TempDir {
+        return TempDir(std::env::temp_dir().join(format!("narrow-storage-test-{}", random_id())));
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+macro_rules! conformance {
+    ($($name: ident,) *) => {
+        mod memory {
+            $(
+                #[tokio::test]
+                async fn $name() {
+                    super::$name(&super::super::memory::MemoryStorage::new()).await;
+                }
+            ) *
+        }
+
+        mod sqlite {
+            $(
+                #[tokio::test]
+                async fn $name() {
+                    let dir = super::TempDir::new();
+                    super::$name(&super::super::sqlite::SqliteStorage::new(&dir.0).await.unwrap()).await;
+                }
+            ) *
+        }
+    };
+}
+
+conformance!(
+    ids_are_assigned_per_channel,
+    unknown_channels,
+    snap_around,
+    snap_around_splits_by_time,
+    snap_before,
+    snap_after,
+    edit_and_delete,
+    times_are_stored_to_microseconds,
+    read_positions,
+    channel_lists_are_per_user,
+    identities_and_members,
+    brew_events,
+    brews_are_per_user,
+    retried_sends_are_deduplicated,
+    dedup_is_per_user,
+    dedup_expires,
+    expired_sessions_are_deleted,
+);
+
+/// Seconds after an arbitrary start time.
+fn t(seconds: i64) -> DateTime<Utc> {
+    return Utc.timestamp_opt(1_700_000_000, 0).unwrap() + Duration::seconds(seconds);
+}
+
+/// Compare results as the client sees them.
+fn json<T: Serialize>(v: &T) -> serde_json::Value {
+    return serde_json::to_value(v).unwrap();
+}
+
+fn seqs(entries: &[S2UMessage]) -> Vec<u64> {
+    return entries.iter().map(|m| m.id.1).collect();
+}
+
+/// The user owning the identities `channel` creates.
+fn alice() -> String {
+    return "alice".to_string();
+}
+
+async fn channel(s: &dyn Storage, name: &str) -> (IdentityId, ChannelId) {
+    let owner = s.create_identity("alice".to_string(), "Alice".to_string()).await.unwrap().id;
+    let channel = s.create_channel(owner.clone(), name.to_string(), t(0)).await.unwrap().unwrap();
+    return (owner, channel);
+}
+
+async fn send(s: &dyn Storage, channel: &ChannelId, author: &IdentityId, time: DateTime<Utc>, text: &str) -> S2UEvent {
+    match s.send(channel.clone(), author.clone(), None, time, text.to_string(), None).await.unwrap().unwrap() {
+        Sent::New(e) => return e,
+        Sent::Existing(_) => panic!("Send without a local id was deduplicated"),
+    }
+}
+
+/// Send messages at the given times (seconds), returning their seqs.
+async fn send_at(s: &dyn Storage, channel: &ChannelId, author: &IdentityId, times: &[i64]) -> Vec<u64> {
+    let mut out = vec![];
+    for time in times {
+        out.push(send(s, channel, author, t(*time), &format!("at {}", time)).await.id.1);
+    }
+    return out;
+}
+
+async fn events(s: &dyn Storage, channel: &ChannelId) -> Vec<S2UEvent> {
+    return s.events_after(MessageId(channel.clone(), 0), 1000).await.unwrap().unwrap().entries;
+}
+
+async fn ids_are_assigned_per_channel(s: &dyn Storage) {
+    let (owner, a) = channel(s, "a").await;
+    let (_, b) = channel(s, "b").await;
+    assert_ne!(a, b);
+
+    // Creating the channel is the first event
+    let a_events = events(s, &a).await;
+    assert_eq!(a_events.len(), 1);
+    assert_eq!(a_events[0].id, MessageId(a.clone(), 1));
+    assert!(matches!(&a_events[0].kind, S2UEventKind::MemberJoined { identity, .. } if *identity == owner));
+    assert_eq!(send_at(s, &a, &owner, &[1, 2, 3]).await, vec![2, 3, 4]);
+    assert_eq!(send_at(s, &b, &owner, &[4]).await, vec![2]);
+
+    // Other events take ids from the same sequence
+    let renamed = s.rename_channel(a.clone(), t(5), "a2".to_string()).await.unwrap().unwrap();
+    assert_eq!(renamed.id, MessageId(a.clone(), 5));
+    assert_eq!(send_at(s, &a, &owner, &[6]).await, vec![6]);
+    let resp = s.events_after(MessageId(a.clone(), 0), 1000).await.unwrap().unwrap();
+    assert_eq!(resp.server_time, MessageId(a.clone(), 6));
+    assert_eq!(resp.entries.iter().map(|e| e.id.1).collect::<Vec<_>>(), vec![1, 2, 3, 4, 5, 6]);
+
+    // Limited and offset
+    let resp = s.events_after(MessageId(a.clone(), 2), 2).await.unwrap().unwrap();
+    assert_eq!(resp.entries.iter().map(|e| e.id.1).collect::<Vec<_>>(), vec![3, 4]);
+    assert_eq!(resp.server_time, MessageId(a.clone(), 6));
+}
+
+async fn unknown_channels(s: &dyn Storage) {
+    let (owner, _) = channel(s, "a").await;
+    let missing = ChannelId(owner.clone(), 100);
+    assert!(s.get_channel(missing.clone()).await.unwrap().is_none());
+    assert!(s.send(missing.clone(), owner.clone(), None, t(1), "x".to_string(), None).await.unwrap().is_none());
+    assert!(s.events_after(MessageId(missing.clone(), 0), 10).await.unwrap().is_none());
+    assert!(s.snap_around(missing.clone(), t(1), 10).await.unwrap().is_none());
+    assert!(s.snap_before(MessageId(missing.clone(), 10), 10).await.unwrap().is_none());
+    assert!(s.snap_after(MessageId(missing.clone(), 0), 10).await.unwrap().is_none());
+    assert!(s.rename_channel(missing.clone(), t(1), "x".to_string()).await.unwrap().is_none());
+    assert!(s.set_read_position("alice".to_string(), MessageId(missing, 1)).await.unwrap().is_none());
+}
+
+async fn snap_around(s: &dyn Storage) {
+    let (owner, c) = channel(s, "a").await;
+    let empty = s.snap_around(c.clone(), t(10), 10).await.unwrap().unwrap();
+    assert!(empty.entries.is_empty());
+    assert!(empty.early_stop);
+    assert!(empty.late_stop);
+    assert_eq!(empty.server_time, MessageId(c.clone(), 1));
+    let ids = send_at(s, &c, &owner, &[10, 20, 30, 40, 50]).await;
+    let resp = s.snap_around(c.clone(), t(30), 2).await.unwrap().unwrap();
+    assert_eq!(seqs(&resp.entries), ids[0 .. 4].to_vec());
+    assert!(resp.early_stop);
+    assert!(!resp.late_stop);
+    assert_eq!(resp.server_time, MessageId(c.clone(), 6));
+    let resp = s.snap_around(c.clone(), t(30), 1).await.unwrap().unwrap();
+    assert_eq!(seqs(&resp.entries), ids[1 .. 3].to_vec());
+    assert!(!resp.early_stop);
+    assert!(!resp.late_stop);
+
+    // Between messages
+    let resp = s.snap_around(c.clone(), t(35), 2).await.unwrap().unwrap();
+    assert_eq!(seqs(&resp.entries), ids[1 .. 5].to_vec());
+    assert!(!resp.early_stop);
+    assert!(resp.late_stop);
+
+    // Past either end
+    let resp = s.snap_around(c.clone(), t(100), 10).await.unwrap().unwrap();
+    assert_eq!(seqs(&resp.entries), ids);
+    assert!(resp.early_stop);
+    assert!(resp.late_stop);
+    let resp = s.snap_around(c.clone(), t(0), 2).await.unwrap().unwrap();
+    assert_eq!(seqs(&resp.entries), ids[0 .. 2].to_vec());
+    assert!(resp.early_stop);
+    assert!(!resp.late_stop);
+}
+
+async fn snap_around_splits_by_time(s: &dyn Storage) {
+    let (owner, c) = channel(s, "a").await;
+
+    // Ex: concurrent sends, stamped before being assigned ids
+    let ids = send_at(s, &c, &owner, &[10, 30, 20, 40]).await;
+    let resp = s.snap_around(c.clone(), t(25), 10).await.unwrap().unwrap();
+    assert_eq!(seqs(&resp.entries), vec![ids[0], ids[2], ids[1], ids[3]]);
+    assert!(resp.early_stop);
+    assert!(resp.late_stop);
+    let resp = s.snap_around(c.clone(), t(25), 1).await.unwrap().unwrap();
+    assert_eq!(seqs(&resp.entries), vec![ids[2], ids[1]]);
+    assert!(!resp.early_stop);
+    assert!(!resp.late_stop);
+}
+
+async fn snap_before(s: &dyn Storage) {
+    let (owner, c) = channel(s, "a").await;
+    let ids = send_at(s, &c, &owner, &[10, 20, 30, 40, 50]).await;
+    let resp = s.snap_before(MessageId(c.clone(), ids[3]), 2).await.unwrap().unwrap();
+    assert_eq!(seqs(&resp.entries), ids[1 .. 3].to_vec());
+    assert!(!resp.early_stop);
+    assert_eq!(resp.server_time, MessageId(c.clone(), 6));
+    let resp = s.snap_before(MessageId(c.clone(), ids[2]), 2).await.unwrap().unwrap();
+    assert_eq!(seqs(&resp.entries), ids[0 .. 2].to_vec());
+    assert!(resp.early_stop);
+    let resp = s.snap_before(MessageId(c.clone(), ids[0]), 2).await.unwrap().unwrap();
+    assert!(resp.entries.is_empty());
+    assert!(resp.early_stop);
+
+    // The pivot needn't exist
+    let resp = s.snap_before(MessageId(c.clone(), 100), 10).await.unwrap().unwrap();
+    assert_eq!(seqs(&resp.entries), ids);
+    assert!(resp.early_stop);
+}
+
+async fn snap_after(s: &dyn Storage) {
+    let (owner, c) = channel(s, "a").await;
+    let ids = send_at(s, &c, &owner, &[10, 20, 30, 40, 50]).await;
+    let resp = s.snap_after(MessageId(c.clone(), ids[1]), 2).await.unwrap().unwrap();
+    assert_eq!(seqs(&resp.entries), ids[2 .. 4].to_vec());
+    assert!(!resp.late_stop);
+    assert_eq!(resp.server_time, MessageId(c.clone(), 6));
+    let resp = s.snap_after(MessageId(c.clone(), ids[2]), 2).await.unwrap().unwrap();
+    assert_eq!(seqs(&resp.entries), ids[3 .. 5].to_vec());
+    assert!(resp.late_stop);
+    let resp = s.snap_after(MessageId(c.clone(), ids[4]), 2).await.unwrap().unwrap();
+    assert!(resp.entries.is_empty());
+    assert!(resp.late_stop);
+
+    // The pivot needn't exist, ex: the channel creation event
+    let resp = s.snap_after(MessageId(c.clone(), 0), 10).await.unwrap().unwrap();
+    assert_eq!(seqs(&resp.entries), ids);
+    assert!(resp.late_stop);
+}
+
+async fn edit_and_delete(s: &dyn Storage) {
+    let (owner, c) = channel(s, "a").await;
+    let ids = send_at(s, &c, &owner, &[10, 20, 30]).await;
+    let edited = s.edit_message(MessageId(c.clone(), ids[1]), t(40), "edited".to_string()).await.unwrap().unwrap();
+    assert_eq!(edited.id, MessageId(c.clone(), 5));
+    assert!(
+        matches!(&edited.kind, S2UEventKind::MessageEdited { id, text } if id.1 == ids[1] && text == "edited")
+    );
+    let resp = s.snap_around(c.clone(), t(0), 10).await.unwrap().unwrap();
+    assert_eq!(resp.entries.iter().map(|m| m.text.as_str()).collect::<Vec<_>>(), vec!["at 10", "edited", "at 30"]);
+
+    // Edits don't move the message
+    assert_eq!(resp.entries[1].time, t(20));
+    let deleted = s.delete_message(MessageId(c.clone(), ids[1]), t(50)).await.unwrap().unwrap();
+    assert_eq!(deleted.id, MessageId(c.clone(), 6));
+    assert!(matches!(&deleted.kind, S2UEventKind::MessageDeleted(id) if id.1 == ids[1]));
+    let resp = s.snap_around(c.clone(), t(0), 10).await.unwrap().unwrap();
+    assert_eq!(seqs(&resp.entries), vec![ids[0], ids[2]]);
+    assert!(resp.early_stop);
+    assert!(resp.late_stop);
+    assert_eq!(seqs(&s.snap_before(MessageId(c.clone(), ids[2]), 10).await.unwrap().unwrap().entries), vec![ids[0]]);
+    assert_eq!(seqs(&s.snap_after(MessageId(c.clone(), ids[0]), 10).await.unwrap().unwrap().entries), vec![ids[2]]);
+
+    // Gone
+    assert!(s.edit_message(MessageId(c.clone(), ids[1]), t(60), "x".to_string()).await.unwrap().is_none());
+    assert!(s.delete_message(MessageId(c.clone(), ids[1]), t(60)).await.unwrap().is_none());
+
+    // Events that aren't messages can't be edited
+    assert!(s.edit_message(MessageId(c.clone(), 1), t(60), "x".to_string()).await.unwrap().is_none());
+    assert!(s.delete_message(MessageId(c.clone(), edited.id.1), t(60)).await.unwrap().is_none());
+
+    // The log has everything, as returned when appended
+    let log = events(s, &c).await;
+    assert_eq!(log.len(), 6);
+    assert_eq!(json(&log[4]), json(&edited));
+    assert_eq!(json(&log[5]), json(&deleted));
+}
+
+async fn times_are_stored_to_microseconds(s: &dyn Storage) {
+    let (owner, c) = channel(s, "a").await;
+    let time = t(10) + Duration::nanoseconds(123_456_789);
+    let sent = send(s, &c, &owner, time, "x").await;
+    assert_eq!(sent.time, t(10) + Duration::microseconds(123_456));
+    let renamed = s.rename_channel(c.clone(), time, "b".to_string()).await.unwrap().unwrap();
+    assert_eq!(renamed.time, sent.time);
+    let log = events(s, &c).await;
+    assert_eq!(json(&log[1]), json(&sent));
+    assert_eq!(json(&log[2]), json(&renamed));
+    let resp = s.snap_around(c.clone(), t(0), 10).await.unwrap().unwrap();
+    assert_eq!(resp.entries[0].time, sent.time);
+    let S2UEventKind::MessageCreated(m) = &sent.kind else {
+        panic!("Not a message");
+    };
+    assert_eq!(json(&resp.entries[0]), json(m));
+}
+
+async fn read_positions(s: &dyn Storage) {
+    let (owner, c) = channel(s, "a").await;
+    let alice = "alice".to_string();
+    let bob = "bob".to_string();
+    let bob_identity = s.create_identity(bob.clone(), "Bob".to_string()).await.unwrap().id;
+    s.join_channel(c.clone(), bob_identity, t(1)).await.unwrap().unwrap();
+    let ids = send_at(s, &c, &owner, &[10, 20, 30]).await;
+    assert!(s.get_read_position(alice.clone(), c.clone()).await.unwrap().is_none());
+    let unread = |summaries: Vec<webserver::interface::u2s::S2UChannelSummary>| {
+        return summaries.into_iter().find(|s| s.channel.id == c).unwrap().unread;
+    };
+    assert_eq!(unread(s.list_channel_summaries(alice.clone()).await.unwrap()), 3);
+    assert_eq!(
+        s.set_read_position(alice.clone(), MessageId(c.clone(), ids[1])).await.unwrap(),
+        Some(MessageId(c.clone(), ids[1]))
+    );
+    assert_eq!(unread(s.list_channel_summaries(alice.clone()).await.unwrap()), 1);
+
+    // Never moves backwards
+    assert_eq!(
+        s.set_read_position(alice.clone(), MessageId(c.clone(), ids[0])).await.unwrap(),
+        Some(MessageId(c.clone(), ids[1]))
+    );
+    assert_eq!(s.get_read_position(alice.clone(), c.clone()).await.unwrap(), Some(MessageId(c.clone(), ids[1])));
+
+    // Per user
+    assert!(s.get_read_position(bob.clone(), c.clone()).await.unwrap().is_none());
+    let summaries = s.list_channel_summaries(bob.clone()).await.unwrap();
+    assert_eq!(summaries[0].last_message.as_ref().map(|m| m.id.1), Some(ids[2]));
+    assert_eq!(unread(summaries), 3);
+
+    // Deleted messages aren't unread
+    s.delete_message(MessageId(c.clone(), ids[2]), t(40)).await.unwrap().unwrap();
+    assert_eq!(unread(s.list_channel_summaries(alice.clone()).await.unwrap()), 0);
+    let summaries = s.list_channel_summaries(bob).await.unwrap();
+    assert_eq!(summaries[0].last_message.as_ref().map(|m| m.id.1), Some(ids[1]));
+    assert_eq!(unread(summaries), 2);
+}
+
+async fn channel_lists_are_per_user(s: &dyn Storage) {
+    let (owner, c) = channel(s, "a").await;
+    send_at(s, &c, &owner, &[10]).await;
+    let bob = "bob".to_string();
+    let bob_identity = s.create_identity(bob.clone(), "Bob".to_string()).await.unwrap().id;
+    assert_eq!(s.list_channels(alice()).await.unwrap().len(), 1);
+    assert_eq!(s.list_channel_summaries(alice()).await.unwrap().len(), 1);
+    assert!(s.list_channels(bob.clone()).await.unwrap().is_empty());
+    assert!(s.list_channel_summaries(bob.clone()).await.unwrap().is_empty());
+
+    // Visible once a member, gone again after leaving
+    s.join_channel(c.clone(), bob_identity.clone(), t(20)).await.unwrap().unwrap();
+    assert_eq!(s.list_channels(bob.clone()).await.unwrap().into_iter().map(|c| c.id).collect::<Vec<_>>(), vec![
+        c.clone()
+    ]);
+    assert_eq!(s.list_channel_summaries(bob.clone()).await.unwrap().len(), 1);
+    s.leave_channel(c.clone(), bob_identity, t(30)).await.unwrap().unwrap();
+    assert!(s.list_channels(bob.clone()).await.unwrap().is_empty());
+    assert!(s.list_channel_summaries(bob).await.unwrap().is_empty());
+}
+
+async fn identities_and_members(s: &dyn Storage) {
+    let (alice, c) = channel(s, "a").await;
+    let alice2 = s.create_identity("alice".to_string(), "Alice 2".to_string()).await.unwrap().id;
+    let bob = s.create_identity("bob".to_string(), "Bob".to_string()).await.unwrap().id;
+    let ids = |identities: Vec<webserver::interface::u2s::S2UIdentity>| {
+        return identities.into_iter().map(|i| i.id).collect::<Vec<_>>();
+    };
+    assert_eq!(ids(s.list_identities("alice".to_string()).await.unwrap()), vec![alice.clone(), alice2.clone()]);
+    assert_eq!(ids(s.list_identities("bob".to_string()).await.unwrap()), vec![bob.clone()]);
+    assert!(s.list_identities("carol".to_string()).await.unwrap().is_empty());
+    assert_eq!(s.get_channel_members(c.clone()).await.unwrap(), Some(vec![alice.clone()]));
+    s.join_channel(c.clone(), bob.clone(), t(10)).await.unwrap().unwrap();
+    assert!(s.join_channel(c.clone(), bob.clone(), t(11)).await.unwrap().is_none());
+    let mut members = s.get_channel_members(c.clone()).await.unwrap().unwrap();
+    members.sort();
+    let mut want = vec![alice.clone(), bob.clone()];
+    want.sort();
+    assert_eq!(members, want);
+    s.leave_channel(c.clone(), alice.clone(), t(12)).await.unwrap().unwrap();
+    assert!(s.leave_channel(c.clone(), alice2.clone(), t(13)).await.unwrap().is_none());
+    assert_eq!(s.get_channel_members(c.clone()).await.unwrap(), Some(vec![bob.clone()]));
+    assert_eq!(s.get_channel_users(c.clone()).await.unwrap(), vec!["bob".to_string()]);
+    s.join_channel(c.clone(), alice2, t(14)).await.unwrap().unwrap();
+    assert_eq!(s.get_channel_users(c.clone()).await.unwrap(), vec!["alice".to_string(), "bob".to_string()]);
+    assert!(s.get_channel_members(ChannelId(alice.clone(), 999)).await.unwrap().is_none());
+    assert!(s.get_channel_users(ChannelId(alice, 999)).await.unwrap().is_empty());
+}
+
+/// The channel and kind of each event.
+fn brew_event_kinds(events: &[S2UEvent]) -> Vec<(ChannelId, serde_json::Value)> {
+    return events.iter().map(|e| (e.id.0.clone(), json(&e.kind))).collect();
+}
+
+async fn brew_events(s: &dyn Storage) {
+    let (owner, a) = channel(s, "a").await;
+    let (_, b) = channel(s, "b").await;
+    let changed = |id: &BrewId, name: &str, channels: Vec<ChannelId>| json(
+        &S2UEventKind::BrewChanged(webserver::interface::u2s::S2UBrew {
+            id: id.clone(),
+            name: name.to_string(),
+            channels: channels,
+        }),
+    );
+
+    // Logged once per existing channel
+    let channels = vec![a.clone(), a.clone(), ChannelId(owner, 999)];
+    let (id, logged) = s.create_brew(alice(), "x".to_string(), channels.clone(), t(10)).await.unwrap();
+    assert_eq!(brew_event_kinds(&logged), vec![(a.clone(), changed(&id, "x", channels.clone()))]);
+    assert_eq!(json(events(s, &a).await.last().unwrap()), json(&logged[0]));
+    let logged = s.update_brew(alice(), id.clone(), None, Some(vec![a.clone()]), t(11)).await.unwrap().unwrap();
+    assert_eq!(brew_event_kinds(&logged), vec![(a.clone(), changed(&id, "x", vec![a.clone()]))]);
+    let logged = s.update_brew(alice(), id.clone(), Some("y".to_string()), None, t(12)).await.unwrap().unwrap();
+    assert_eq!(brew_event_kinds(&logged), vec![(a.clone(), changed(&id, "y", vec![a.clone()]))]);
+
+    // Both the channels it left and joined see the change, with the name changed at
+    // the same time
+    let logged =
+        s.update_brew(alice(), id.clone(), Some("w".to_string()), Some(vec![b.clone()]), t(13)).await.unwrap().unwrap();
+    assert_eq!(
+        brew_event_kinds(&logged),
+        vec![(a.clone(), changed(&id, "w", vec![b.clone()])), (b.clone(), changed(&id, "w", vec![b.clone()]))]
+    );
+    assert_eq!(json(&s.get_brew(alice(), id.clone()).await.unwrap()), json(&logged[0].kind)["BrewChanged"]);
+    let logged = s.delete_brew(alice(), id.clone(), t(14)).await.unwrap().unwrap();
+    assert_eq!(brew_event_kinds(&logged), vec![(b.clone(), json(&S2UEventKind::BrewDeleted(id.clone())))]);
+    assert_eq!(json(events(s, &b).await.last().unwrap()), json(&logged[0]));
+    assert!(s.get_brew(alice(), id.clone()).await.unwrap().is_none());
+    assert!(s.update_brew(alice(), id.clone(), Some("z".to_string()), Some(vec![]), t(15)).await.unwrap().is_none());
+    assert!(s.delete_brew(alice(), id, t(15)).await.unwrap().is_none());
+}
+
+async fn brews_are_per_user(s: &dyn Storage) {
+    let (_, a) = channel(s, "a").await;
+    let (id, _) = s.create_brew(alice(), "x".to_string(), vec![a.clone()], t(10)).await.unwrap();
+    let (bob_id, _) = s.create_brew("bob".to_string(), "y".to_string(), vec![], t(10)).await.unwrap();
+    assert_eq!(s.list_brews(alice()).await.unwrap().into_iter().map(|b| b.id).collect::<Vec<_>>(), vec![id.clone()]);
+    assert_eq!(
+        s.list_brews("bob".to_string()).await.unwrap().into_iter().map(|b| b.id).collect::<Vec<_>>(),
+        vec![bob_id.clone()]
+    );
+    assert_eq!(s.get_brew(alice(), id.clone()).await.unwrap().map(|b| b.name), Some("x".to_string()));
+    assert!(s.get_brew("bob".to_string(), id.clone()).await.unwrap().is_none());
+    assert_eq!(
+        s
+            .get_brews(alice(), vec![id.clone(), bob_id.clone()])
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|b| b.map(|b| b.id))
+            .collect::<Vec<_>>(),
+        vec![Some(id.clone()), None]
+    );
+
+    // Other users can't change it
+    assert!(
+        s.update_brew("bob".to_string(), id.clone(), Some("z".to_string()), Some(vec![]), t(11)).await.unwrap().is_none()
+    );
+    assert!(s.delete_brew("bob".to_string(), id.clone(), t(11)).await.unwrap().is_none());
+    assert_eq!(json(&s.get_brew(alice(), id).await.unwrap().unwrap().channels), json(&vec![a]));
+}
+
+/// Send with a local id remembered since `expire_before`, as the core server does.
+async fn send_local(
+    s: &dyn Storage,
+    channel: &ChannelId,
+    author: &IdentityId,
+    user: &str,
+    local_id: &str,
+    time: DateTime<Utc>,
+    expire_before: DateTime<Utc>,
+) -> Sent {
+    return s.send(channel.clone(), author.clone(), None, time, "x".to_string(), Some(LocalSendId {
+        user: user.to_string(),
+        local_id: local_id.to_string(),
+        expire_before: expire_before,
+    })).await.unwrap().unwrap();
+}
+
+async fn message_count(s: &dyn Storage, channel: &ChannelId) -> usize {
+    return s.snap_around(channel.clone(), t(0), 100).await.unwrap().unwrap().entries.len();
+}
+
+async fn retried_sends_are_deduplicated(s: &dyn Storage) {
+    let (owner, c) = channel(s, "a").await;
+    let Sent::New(first) = send_local(s, &c, &owner, "alice", "l1", t(10), t(0)).await else {
+        panic!("First send was deduplicated");
+    };
+
+    // The response was lost, the client retries
+    let Sent::Existing(id) = send_local(s, &c, &owner, "alice", "l1", t(11), t(1)).await else {
+        panic!("Retry stored a new message");
+    };
+    assert_eq!(id, first.id);
+    assert_eq!(message_count(s, &c).await, 1);
+    assert_eq!(events(s, &c).await.len(), 2);
+
+    // Other local ids are unaffected
+    let Sent::New(second) = send_local(s, &c, &owner, "alice", "l2", t(12), t(2)).await else {
+        panic!("Send with a new local id was deduplicated");
+    };
+    assert_ne!(second.id, first.id);
+    assert_eq!(message_count(s, &c).await, 2);
+}
+
+async fn dedup_is_per_user(s: &dyn Storage) {
+    let (owner, c) = channel(s, "a").await;
+    let Sent::New(first) = send_local(s, &c, &owner, "alice", "l1", t(10), t(0)).await else {
+        panic!("First send was deduplicated");
+    };
+    let Sent::New(other) = send_local(s, &c, &owner, "bob", "l1", t(11), t(1)).await else {
+        panic!("Another user's send was deduplicated");
+    };
+    assert_ne!(other.id, first.id);
+    assert_eq!(message_count(s, &c).await, 2);
+}
+
+async fn dedup_expires(s: &dyn Storage) {
+    let (owner, c) = channel(s, "a").await;
+    let Sent::New(first) = send_local(s, &c, &owner, "alice", "l1", t(10), t(0)).await else {
+        panic!("First send was deduplicated");
+    };
+
+    // Still within the window
+    let Sent::Existing(id) = send_local(s, &c, &owner, "alice", "l1", t(50), t(10)).await else {
+        panic!("Retry within the window stored a new message");
+    };
+    assert_eq!(id, first.id);
+
+    // Past the window the local id is forgotten
+    let Sent::New(late) = send_local(s, &c, &owner, "alice", "l1", t(100), t(11)).await else {
+        panic!("Retry after the window was deduplicated");
+    };
+    assert_ne!(late.id, first.id);
+    assert_eq!(message_count(s, &c).await, 2);
+
+    // And the new send is remembered in its place
+    let Sent::Existing(id) = send_local(s, &c, &owner, "alice", "l1", t(101), t(11)).await else {
+        panic!("Retry of the new send stored a new message");
+    };
+    assert_eq!(id, late.id);
+}
+
+async fn expired_sessions_are_deleted(s: &dyn Storage) {
+    for (token, expires) in [("a", 10), ("b", 20), ("c", 40)] {
+        s.create_session(token.to_string(), Session {
+            user: "alice".to_string(),
+            expires: t(expires),
+        }).await.unwrap();
+    }
+    s.add_push_subscription("b".to_string(), "sub b".to_string()).await.unwrap();
+    s.add_push_subscription("c".to_string(), "sub c".to_string()).await.unwrap();
+    let (_, c) = channel(s, "a").await;
+    assert!(s.get_session("a".to_string(), t(30)).await.unwrap().is_none());
+
+    // Every expired session is gone, even looked up before it expired
+    assert!(s.get_session("b".to_string(), t(0)).await.unwrap().is_none());
+    assert_eq!(s.get_session("c".to_string(), t(0)).await.unwrap().map(|s| s.expires), Some(t(40)));
+    assert_eq!(s.list_push_subscriptions(c, "bob".to_string(), t(0)).await.unwrap(), vec!["sub c".to_string()]);
+}
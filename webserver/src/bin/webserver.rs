@@ -11,11 +11,13 @@ use loga::{
     ResultContext,
 };
 use poem::{
+    get,
     Route,
     Server,
     listener::TcpListener,
     middleware::{
         AddData,
+        CookieJarManager,
         SetHeader,
     },
     EndpointExt,
@@ -41,12 +43,36 @@ mod args {
         #[serde(default)]
         pub debug: bool,
         pub static_dir: PathBuf,
+        /// Directory for persistent server data (database, etc).
+        pub data_dir: PathBuf,
+        /// Keep all data in memory, discarding it on exit (for testing).
+        #[serde(default)]
+        pub ephemeral: bool,
         pub web_bind_addr: SocketAddr,
+        /// How long logins last before the user must log in again, in days. Defaults to
+        /// 30.
+        #[serde(default)]
+        pub session_lifetime_days: Option<u32>,
+        /// How long the server remembers sent messages by their client-assigned id, in
+        /// hours, so a client retrying a send whose response was lost doesn't create a
+        /// duplicate. Defaults to 168 (a week).
+        #[serde(default)]
+        pub send_dedup_hours: Option<u32>,
+        /// Contact URL (`mailto:` or `https:`) sent to push services with
+        /// notifications. Some push services reject notifications without this.
+        #[serde(default)]
+        pub push_subject: Option<String>,
+        /// Serve a stand-in push service at `/push_stub` (for testing).
+        #[serde(default)]
+        pub push_stub: bool,
     }
 
     #[derive(Aargvark)]
     pub struct Args {
         pub config: aargvark::AargvarkJson<Config>,
+        /// Create a user (or reset an existing user's password) with a password read
+        /// from stdin, then exit.
+        pub add_user: Option<String>,
     }
 }
 
@@ -57,13 +83,38 @@ struct HttpInner {
 #[tokio::main]
 async fn main() {
     async fn inner() -> Result<(), loga::Error> {
-        let config = vark::<args::Args>().config.value;
+        let args = vark::<args::Args>();
+        let config = args.config.value;
         let log = &loga::new(if config.debug {
             loga::Level::Debug
         } else {
             loga::Level::Info
         });
         let tm = taskmanager::TaskManager::new();
+        let storage: Arc<dyn core_server::storage::Storage> = if config.ephemeral {
+            Arc::new(core_server::storage::memory::MemoryStorage::new())
+        } else {
+            Arc::new(core_server::storage::sqlite::SqliteStorage::new(&config.data_dir).await?)
+        };
+        if let Some(username) = args.add_user {
+            let mut password = String::new();
+            std::io::stdin().read_line(&mut password).context("Failed to read password from stdin")?;
+            let password = password.trim_end_matches(['\r', '\n']);
+            if password.is_empty() {
+                return Err(loga::err("Password is empty"));
+            }
+            storage.set_user(username.clone(), core_server::auth::hash_password(password)?).await?;
+            log.info("Set user password", ea!(user = username));
+            return Ok(());
+        }
+        let core =
+            core_server::CoreServer::new(
+                &log.fork(ea!(sys = "core")),
+                storage,
+                chrono::Duration::days(config.session_lifetime_days.unwrap_or(30) as i64),
+                chrono::Duration::hours(config.send_dedup_hours.unwrap_or(24 * 7) as i64),
+                config.push_subject,
+            ).await?;
 
         // UI server
         tm.critical_task({
@@ -71,13 +122,28 @@ async fn main() {
             let tm = tm.clone();
             let inner = Arc::new(HttpInner { _log: log.clone() });
             async move {
+                let mut route =
+                    Route::new()
+                        .at("/api", get(core_server::api_get).post(core_server::api_post))
+                        .at("/api/events", get(core_server::api_events));
+                if config.push_stub {
+                    route =
+                        route.nest(
+                            "/push_stub",
+                            core_server::push::stub::PushStub::new(
+                                format!("http://{}/push_stub", config.web_bind_addr),
+                            ).route(),
+                        );
+                }
                 let server =
                     Server::new(
                         TcpListener::bind(config.web_bind_addr),
                     ).run(
-                        Route::new()
+                        route
                             .nest("/", StaticFilesEndpoint::new(&config.static_dir))
                             .with(AddData::new(inner))
+                            .with(AddData::new(core))
+                            .with(CookieJarManager::new())
                             .with(
                                 SetHeader::new()
                                     .appending("Cross-Origin-Embedder-Policy", "require-corp")
@@ -1 +1,2 @@
- 
+#[path = "../../web/src/interface/mod.rs"]
+pub mod interface;